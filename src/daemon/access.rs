@@ -0,0 +1,178 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::net::UnixStream;
+
+/// Who besides the daemon's own user and root may send commands that change something.
+/// Everyone else reaching the control socket is limited to commands that only read
+#[derive(Clone, Debug, Default)]
+pub struct ControlAccess {
+    pub uids: Vec<u32>,
+    pub gids: Vec<u32>,
+}
+
+impl ControlAccess {
+    /// Mode of a control socket the daemon binds itself. It is owner only unless other
+    /// users are let in, from then on the peer credentials of each connection decide
+    pub fn socket_mode(&self) -> u32 {
+        if self.uids.is_empty() && self.gids.is_empty() { 0o600 } else { 0o666 }
+    }
+
+    pub fn allows(&self, peer: &Peer) -> bool {
+        let owner = unsafe { libc::geteuid() };
+        peer.uid == 0
+            || peer.uid == owner
+            || self.uids.contains(&peer.uid)
+            || peer.groups.iter().any(|gid| self.gids.contains(gid))
+    }
+}
+
+/// Process on the other end of a control connection, from SO_PEERCRED
+#[derive(Debug)]
+pub struct Peer {
+    pub uid: u32,
+    pub pid: Option<i32>,
+    /// primary and supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl Peer {
+    pub fn of(socket: &UnixStream) -> io::Result<Peer> {
+        let cred = socket.peer_cred()?;
+        let mut groups = vec![cred.gid()];
+        if let Some(pid) = cred.pid() {
+            groups.extend(supplementary_groups(pid).unwrap_or_default());
+        }
+        Ok(Peer { uid: cred.uid(), pid: cred.pid(), groups })
+    }
+
+    /// Resolves `path` the way the peer sees it, relative ones against its working directory
+    pub fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        let path = Path::new(path);
+        match self.pid {
+            Some(pid) if path.is_relative() => fs::canonicalize(Path::new(&format!("/proc/{pid}/cwd")).join(path)),
+            _ => fs::canonicalize(path),
+        }
+    }
+
+    /// Credentials shares added by the peer are read with, none for root and the daemon's own user
+    pub fn owner(&self) -> Option<Owner> {
+        let daemon = unsafe { libc::geteuid() };
+        (self.uid != 0 && self.uid != daemon).then(|| Owner { uid: self.uid, groups: self.groups.clone() })
+    }
+}
+
+/// The local user a share was added by. Its file is opened with their access every time,
+/// so swapping it for a symlink later gets no further than they could get themselves
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: u32,
+    /// primary group first
+    pub groups: Vec<u32>,
+}
+
+impl Owner {
+    /// Owner of a file with `meta` as far as shares go, none for root and the daemon's own user.
+    /// Only the primary group is known
+    pub fn of_file(meta: &fs::Metadata) -> Option<Owner> {
+        let daemon = unsafe { libc::geteuid() };
+        (meta.uid() != 0 && meta.uid() != daemon).then(|| Owner { uid: meta.uid(), groups: vec![meta.gid()] })
+    }
+
+    /// Opens `path` for reading as this user. A root daemon lets the kernel decide on a thread
+    /// with the user's filesystem credentials, others can only go by the mode bits
+    pub fn open(&self, path: &Path) -> io::Result<fs::File> {
+        if unsafe { libc::geteuid() } == 0 {
+            let (owner, path) = (self.clone(), path.to_path_buf());
+            // credentials are per thread, one that ends right after never hands them on
+            return std::thread::spawn(move || owner.open_as(&path))
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("opening thread panicked")));
+        }
+        let file = fs::File::open(path)?;
+        self.check_opened(&file)?;
+        Ok(file)
+    }
+
+    // setgroups through glibc would change every thread of the process, the syscall only this one
+    fn open_as(&self, path: &Path) -> io::Result<fs::File> {
+        let gid = self.groups.first().copied().unwrap_or(u32::MAX);
+        unsafe {
+            if libc::syscall(libc::SYS_setgroups, self.groups.len(), self.groups.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::setfsgid(gid);
+            libc::setfsuid(self.uid);
+            // both return the previous value, so asking again tells whether the switch took
+            if libc::setfsgid(gid) as u32 != gid || libc::setfsuid(self.uid) as u32 != self.uid {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "can't switch to the share owner"));
+            }
+        }
+        fs::File::open(path)
+    }
+
+    // the opened file and every directory on its real path, checked against the mode bits
+    fn check_opened(&self, file: &fs::File) -> io::Result<()> {
+        let denied = || io::Error::new(io::ErrorKind::PermissionDenied, "share owner has no access");
+        let real = fs::read_link(format!("/proc/self/fd/{}", file.as_raw_fd()))?;
+        if !self.has_access(&file.metadata()?, 0o4) {
+            return Err(denied());
+        }
+        for dir in real.ancestors().skip(1) {
+            if !self.has_access(&fs::metadata(dir)?, 0o1) {
+                return Err(denied());
+            }
+        }
+        Ok(())
+    }
+
+    // `bit` is the "other" permission bit, shifted for the owner and group classes
+    fn has_access(&self, meta: &fs::Metadata, bit: u32) -> bool {
+        let mode = meta.permissions().mode();
+        if meta.uid() == self.uid {
+            mode & (bit << 6) != 0
+        } else if self.groups.contains(&meta.gid()) {
+            mode & (bit << 3) != 0
+        } else {
+            mode & bit != 0
+        }
+    }
+}
+
+// the `Groups:` line of /proc/<pid>/status
+fn supplementary_groups(pid: i32) -> Option<Vec<u32>> {
+    let status = fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let line = status.lines().find_map(|line| line.strip_prefix("Groups:"))?;
+    Some(line.split_whitespace().filter_map(|gid| gid.parse().ok()).collect())
+}
+
+/// Parses a user name or numeric UID
+pub fn parse_uid(user: &str) -> Result<u32, String> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    let name = CString::new(user).map_err(|e| e.to_string())?;
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("no user named '{user}'"));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+/// Parses a group name or numeric GID
+pub fn parse_gid(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|e| e.to_string())?;
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(format!("no group named '{group}'"));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
@@ -0,0 +1,110 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::network::{AuditEvent, AuditOutcome, AuditRecord};
+use crate::utils::{format_utc, open_private};
+
+/// Which audit records `daemon audit` shows
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AuditQuery {
+    /// unix seconds, inclusive
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub user: Option<String>,
+    pub token: Option<String>,
+    pub share: Option<String>,
+    pub outcome: Option<AuditOutcome>,
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &AuditRecord) -> bool {
+        let share = match &record.event {
+            AuditEvent::Transfer { share, .. } | AuditEvent::Upload { share, .. } => Some(share),
+            _ => None,
+        };
+        self.since.is_none_or(|since| record.time >= since)
+            && self.until.is_none_or(|until| record.time <= until)
+            && self.user.as_ref().is_none_or(|user| record.user.as_ref() == Some(user))
+            && self.token.as_ref().is_none_or(|token| record.token.as_ref() == Some(token))
+            && self.share.as_ref().is_none_or(|wanted| share == Some(wanted))
+            && self.outcome.is_none_or(|outcome| record.outcome == outcome)
+    }
+}
+
+/// Records of the audit log at `path` that pass `query`, oldest first
+pub fn read_audit(path: &Path, query: &AuditQuery) -> io::Result<Vec<AuditRecord>> {
+    let file = open_private(path, OpenOptions::new().read(true))?;
+    let mut records = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let record: AuditRecord = match serde_json::from_str(&line?) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Skipping line {} of {}: {e}", i + 1, path.display());
+                continue;
+            }
+        };
+        if query.matches(&record) {
+            records.push(record);
+        }
+    }
+    Ok(records)
+}
+
+// `2026-10-19T04:00:00Z 10.0.0.2:51234 user transfer share=notes ... ok`
+pub fn describe_audit(record: &AuditRecord) -> String {
+    let event = match &record.event {
+        AuditEvent::Auth => "auth".to_string(),
+        AuditEvent::Transfer { share, hash, offset, bytes, duration_ms } => format!(
+            "transfer share={share} hash={hash} offset={offset} bytes={bytes} duration={}.{:03}s",
+            duration_ms / 1000, duration_ms % 1000,
+        ),
+        AuditEvent::Upload { share, hash, offset, bytes, duration_ms } => format!(
+            "upload share={share} hash={hash} offset={offset} bytes={bytes} duration={}.{:03}s",
+            duration_ms / 1000, duration_ms % 1000,
+        ),
+        AuditEvent::Admin { command } => format!("admin {command}"),
+    };
+    let outcome = match record.outcome {
+        AuditOutcome::Ok => "ok",
+        AuditOutcome::Denied => "denied",
+        AuditOutcome::Failed => "failed",
+    };
+    // link users as link:<token id>
+    let mut user = record.user.clone().unwrap_or_else(|| "-".into());
+    if let Some(token) = &record.token {
+        user = format!("{user}:{token}");
+    }
+    let mut line = format!("{} {} {user} {event} {outcome}", format_utc(record.time), record.peer);
+    if let Some(error) = &record.error {
+        line.push_str(&format!(": {error}"));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_matching_records_and_skips_broken_lines() {
+        let path = std::env::temp_dir().join(format!("file_share_audit_{}.jsonl", std::process::id()));
+        let lines = [
+            r#"{"time":10,"peer":"a:1","user":"user","event":"auth","outcome":"ok"}"#,
+            "not json",
+            r#"{"time":20,"peer":"b:1","user":null,"event":"auth","outcome":"denied","error":"wrong password"}"#,
+            r#"{"time":30,"peer":"a:1","user":"user","event":"transfer","share":"notes","hash":"h","offset":0,"bytes":5,"duration_ms":1,"outcome":"ok"}"#,
+        ];
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let times = |query: AuditQuery| read_audit(&path, &query).unwrap().iter().map(|r| r.time).collect::<Vec<_>>();
+        assert_eq!(times(AuditQuery::default()), [10, 20, 30]);
+        assert_eq!(times(AuditQuery { outcome: Some(AuditOutcome::Denied), ..Default::default() }), [20]);
+        assert_eq!(times(AuditQuery { share: Some("notes".into()), since: Some(15), ..Default::default() }), [30]);
+        assert_eq!(times(AuditQuery { until: Some(15), user: Some("user".into()), ..Default::default() }), [10]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
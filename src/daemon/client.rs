@@ -1,15 +1,430 @@
-use crate::settings::ClientCliCommand;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-pub async fn handle_client_command(command: ClientCliCommand) {
+use anyhow::Context;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tracing::{error, info, info_span, warn, Instrument};
+use tokio::task::JoinHandle;
+
+use crate::daemon::{
+    emit, notify_ready, CliError, CliResult, ControlAccess, DaemonCommand, DaemonMessage, DaemonResponse, DaemonStatus, ErrorKind, Responder
+};
+use super::{block_on, start_daemon, start_listener, send_command, stop_daemon, handle_response};
+use crate::network::{
+    browse, discover, load_jobs, resolve, run_sync, save_jobs, swarm_download, Client, ConnectionInfo, DiscoveryConfig,
+    KnownHosts, NameMatch, ProgressFn, Request, SearchQuery, ServiceRecord, ShareUri, SwarmPeer, SyncConfig, SyncStatus, TransferInfo
+};
+use crate::utils::state_path;
+use crate::settings::{
+    AdminCliCommand, ClientCliCommand, CLIENT_SYNC_FILE, CLIENT_DAEMON_ERR_PATH, CLIENT_DAEMON_LOG_PATH,
+    CLIENT_DAEMON_PID_PATH, CLIENT_DAEMON_SOCKET_PATH, DISCOVERY_GROUP, DISCOVER_WAIT_SECS, PING_INTERVAL_SECS
+};
+
+pub fn handle_client_command(command: ClientCliCommand) -> CliResult {
     match command {
-        ClientCliCommand::Connect { addr, password } => {
+        ClientCliCommand::Connect { addr, foreground, password, session, fp } => {
+            let jobs_path = state_path(CLIENT_SYNC_FILE)
+                .map_err(|e| CliError::new(ErrorKind::Io, format!("Can't set up the state directory: {e}")))?;
+            start_daemon(move |_tx, rx| async move {
+                let client = match (&session, &fp) {
+                    (Some(session), Some(fp)) => Client::connect_relayed(&addr, session, password, fp).await,
+                    // clap makes --session require --fp
+                    _ => Client::connect(&addr, password, fp.as_deref()).await,
+                };
+                let client = client.with_context(|| format!("Failed to connect to {addr}"))?;
+                info!("Connected to {addr}");
+                notify_ready();
+
+                handle_client_daemon_message(rx, client, addr, jobs_path).await;
+                Ok(())
+            }, |tx| start_listener(CLIENT_DAEMON_SOCKET_PATH, ControlAccess::default(), tx),
+            foreground, CLIENT_DAEMON_LOG_PATH, CLIENT_DAEMON_ERR_PATH, CLIENT_DAEMON_PID_PATH)
+        }
+        ClientCliCommand::Disconnect => stop_daemon(CLIENT_DAEMON_PID_PATH),
+        ClientCliCommand::Download { name, hash: false, delta, output } if ShareUri::is_uri(&name) => {
+            // links carry everything needed, so no daemon is involved
+            let res = block_on(async {
+                let uri = name.parse::<ShareUri>()?;
+                let mut client = Client::connect_uri(&uri).await?;
+                let output = output.unwrap_or_else(|| ".".into());
+                let res = download(&mut client, uri.share, false, delta, Path::new(&output)).await?;
+                let _ = client.request(&Request::Quit).await;
+                anyhow::Ok(res)
+            }).context("Download failed")?;
+            emit(json!({ "message": res }), || println!("{res}"));
+            Ok(())
+        }
+        ClientCliCommand::Download { name, hash, delta, output } => {
+            // the daemon runs in /, so relative paths are resolved here
+            let output = output.unwrap_or_else(|| ".".into());
+            let output = std::path::absolute(&output)
+                .map_or(output, |p| p.to_string_lossy().into_owned());
+            let cmd = DaemonCommand::Download { name, by_hash: hash, delta, output };
+            handle_response(block_on(send_command(cmd, CLIENT_DAEMON_SOCKET_PATH)))
         }
-        ClientCliCommand::Disconnect => {
+        ClientCliCommand::Discover { wait, group, interface } => {
+            let config = DiscoveryConfig { group, interface };
+            let peers = block_on(discover(config, Duration::from_secs(wait))).context("Discovery failed")?;
+            let values = peers.iter().map(|(addr, peer)| json!({
+                "name": peer.name,
+                "addr": addr.to_string(),
+                "auth_required": peer.auth_required,
+                "fingerprint": peer.fingerprint,
+            })).collect::<Vec<_>>();
+            emit(json!({ "peers": values }), || {
+                if peers.is_empty() {
+                    println!("No peers found");
+                }
+                for (addr, peer) in &peers {
+                    let auth = if peer.auth_required { "password" } else { "open" };
+                    println!("{} {addr} ({auth}) {}", peer.name, peer.fingerprint);
+                }
+            });
+            Ok(())
         }
-        ClientCliCommand::Download { name, output } => {
+        ClientCliCommand::Browse { wait } => {
+            let services = block_on(browse(Duration::from_secs(wait))).context("Browsing failed")?;
+            emit(json!({ "services": services }), || {
+                if services.is_empty() {
+                    println!("No services found");
+                }
+                services.iter().for_each(print_service);
+            });
+            Ok(())
+        }
+        ClientCliCommand::Resolve { instance, wait } => {
+            let service = block_on(resolve(&instance, Duration::from_secs(wait)))
+                .context("Resolving failed")?
+                .ok_or_else(|| CliError::new(ErrorKind::NotFound, format!("Service '{instance}' not found")))?;
+            emit(json!({ "service": service }), || print_service(&service));
+            Ok(())
         }
         ClientCliCommand::List => {
+            handle_response(block_on(send_command(DaemonCommand::ListRemote, CLIENT_DAEMON_SOCKET_PATH)))
+        }
+        ClientCliCommand::Search {
+            pattern, glob, regex, min_size, max_size, modified_after, modified_before,
+            tags, file_type, offset, limit
+        } => {
+            let name = pattern.map(|p| match (glob, regex) {
+                (true, _) => NameMatch::Glob(p),
+                (_, true) => NameMatch::Regex(p),
+                _ => NameMatch::Substring(p),
+            });
+            let query = SearchQuery {
+                name, min_size, max_size, modified_after, modified_before,
+                tags, file_type, offset, limit,
+            };
+            handle_response(block_on(send_command(DaemonCommand::Search(query), CLIENT_DAEMON_SOCKET_PATH)))
+        }
+        ClientCliCommand::Swarm { hash, output, peers, password, discover: use_discovery, block_timeout } => {
+            let res = block_on(async {
+                let mut addrs = peers;
+                if use_discovery {
+                    let config = DiscoveryConfig { group: DISCOVERY_GROUP, interface: Ipv4Addr::UNSPECIFIED };
+                    let found = discover(config, Duration::from_secs(DISCOVER_WAIT_SECS)).await?;
+                    addrs.extend(found.keys().map(|addr| addr.to_string()));
+                }
+                addrs.sort();
+                addrs.dedup();
+
+                let peers = addrs.into_iter()
+                    .map(|addr| SwarmPeer { addr, password: password.clone() })
+                    .collect();
+                swarm_download(&hash, peers, Path::new(&output), Duration::from_secs(block_timeout)).await
+            });
+            let size = res.context("Swarm download failed")?;
+            emit(json!({ "size": size, "path": output }), || println!("Downloaded {size} bytes to {output}"));
+            Ok(())
+        }
+        ClientCliCommand::Sync { server, prefix, dir, password, delete, interval, fp } => {
+            // the daemon runs in /, so relative paths are resolved here
+            let dir = std::path::absolute(&dir).unwrap_or_else(|_| dir.into());
+            // jobs run unattended, so they only ever talk to a server pinned up front
+            let fingerprint = match fp {
+                Some(fp) => fp,
+                None => {
+                    let known = KnownHosts::of_user().context("Can't open the known hosts")?;
+                    known.get(&server).context("Can't read the known hosts")?.ok_or_else(|| CliError::new(
+                        ErrorKind::Usage,
+                        format!("{server} is not a known host, connect to it once or pass --fp"),
+                    ))?
+                }
+            };
+            let config = SyncConfig { server, fingerprint, password, prefix, dir, delete, interval: interval.max(1) };
+            handle_response(block_on(send_command(DaemonCommand::Sync(config), CLIENT_DAEMON_SOCKET_PATH)))
         }
-        // dowload list
+        ClientCliCommand::Upload { server, path, name, password, fp } => {
+            let path = Path::new(&path);
+            let name = match name {
+                Some(name) => name,
+                None => path.file_name().context("Upload path has no file name")?.to_string_lossy().into_owned(),
+            };
+            let res = block_on(async {
+                let mut client = Client::connect(&server, password, fp.as_deref()).await?;
+                let res = client.upload(path, &name).await?;
+                let _ = client.request(&Request::Quit).await;
+                anyhow::Ok(res)
+            });
+            let (name, size) = res.context("Upload failed")?;
+            emit(json!({ "name": name, "size": size }), || println!("Uploaded {size} bytes as {name}"));
+            Ok(())
+        }
+        ClientCliCommand::Events { server, password, fp } => {
+            let res = block_on(async {
+                let client = Client::connect(&server, password, fp.as_deref()).await?;
+                client.subscribe(|event| emit(json!({ "event": event }), || println!("{event}"))).await
+            });
+            Ok(res.context("Event stream ended")?)
+        }
+        ClientCliCommand::Status => {
+            handle_response(block_on(send_command(DaemonCommand::Status, CLIENT_DAEMON_SOCKET_PATH)))
+        }
+        ClientCliCommand::LogLevel { filter } => {
+            handle_response(block_on(send_command(DaemonCommand::LogLevel { filter }, CLIENT_DAEMON_SOCKET_PATH)))
+        }
+        ClientCliCommand::SyncStatus => {
+            handle_response(block_on(send_command(DaemonCommand::SyncStatus, CLIENT_DAEMON_SOCKET_PATH)))
+        }
+        ClientCliCommand::SyncStop { id } => {
+            handle_response(block_on(send_command(DaemonCommand::SyncStop { id }, CLIENT_DAEMON_SOCKET_PATH)))
+        }
+        ClientCliCommand::Admin { command } => {
+            let cmd = match command {
+                AdminCliCommand::Add { path, name } => DaemonCommand::Add { path, name },
+                AdminCliCommand::Delete { name } => DaemonCommand::Delete { name },
+                AdminCliCommand::Rename { name, new_name } => DaemonCommand::Rename { name, new_name },
+                AdminCliCommand::Tag { name, tags } => DaemonCommand::Tag { name, tags },
+                AdminCliCommand::List => DaemonCommand::List,
+            };
+            handle_response(block_on(send_command(DaemonCommand::Admin(Box::new(cmd)), CLIENT_DAEMON_SOCKET_PATH)))
+        }
+    }
+}
+
+fn print_service(service: &ServiceRecord) {
+    let addrs = service.addrs.iter().map(|ip| SocketAddr::new(*ip, service.port).to_string()).collect::<Vec<_>>();
+    let auth = if service.auth_required { "password" } else { "open" };
+    println!(
+        "{} {} {} ({auth}) proto={} fp={}",
+        service.instance,
+        service.host,
+        addrs.join(","),
+        service.version.as_deref().unwrap_or("?"),
+        service.fingerprint.as_deref().unwrap_or("?"),
+    );
+}
+
+// one download on an open connection, returns what to tell the user
+async fn download(client: &mut Client, name: String, by_hash: bool, delta: bool, output: &Path) -> anyhow::Result<String> {
+    if delta {
+        let (path, size, reused) = client.download_delta(&name, output).await?;
+        return Ok(format!("Downloaded {size} bytes to {} ({reused} reused from the old copy)", path.display()));
+    }
+
+    let req = if by_hash {
+        Request::DownloadByHash { hash: name, offset: 0 }
+    } else {
+        Request::Download { name, offset: 0 }
+    };
+    let (path, size) = client.download(&req, output).await?;
+    Ok(format!("Downloaded {size} bytes to {}", path.display()))
+}
+
+// forwards download progress to the user, once per percent so a big file doesn't flood the socket,
+// and keeps the status of the client daemon up to date
+fn progress_reporter(resp_tx: Responder, label: String, stats: &ClientStats) -> ProgressFn {
+    let transfer = Arc::clone(&stats.transfer);
+    let received = Arc::clone(&stats.received);
+    let peer = stats.server.clone();
+    let mut last = None;
+    let mut last_done = 0;
+    Box::new(move |done, total| {
+        received.fetch_add(done.saturating_sub(last_done), Ordering::Relaxed);
+        last_done = done;
+        *transfer.lock().unwrap() = Some(TransferInfo { peer: peer.clone(), name: label.clone(), done, size: total });
+
+        let percent = done.saturating_mul(100).checked_div(total).unwrap_or(100);
+        if last != Some(percent) {
+            last = Some(percent);
+            resp_tx.progress(&label, done, Some(total));
+        }
+    })
+}
+
+// what the client daemon reports about itself besides its sync jobs
+struct ClientStats {
+    started: Instant,
+    server: String,
+    connected: ConnectionInfo,
+    fingerprint: Option<String>,
+    // the running download, kept up to date by its progress callback
+    transfer: Arc<Mutex<Option<TransferInfo>>>,
+    received: Arc<AtomicU64>,
+}
+
+impl ClientStats {
+    fn status(&self, sync_jobs: usize) -> DaemonStatus {
+        DaemonStatus {
+            pid: std::process::id(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            addresses: vec![self.server.clone()],
+            fingerprint: self.fingerprint.clone(),
+            shares: None,
+            sync_jobs: Some(sync_jobs),
+            connections: vec![self.connected.clone()],
+            transfers: self.transfer.lock().unwrap().iter().cloned().collect(),
+            bytes_transferred: self.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// a running sync job of the client daemon
+struct SyncJob {
+    config: SyncConfig,
+    status: Arc<Mutex<SyncStatus>>,
+    task: JoinHandle<()>,
+}
+
+impl SyncJob {
+    fn start(id: u64, config: SyncConfig) -> Self {
+        let status = Arc::new(Mutex::new(SyncStatus {
+            id,
+            server: config.server.clone(),
+            prefix: config.prefix.clone(),
+            dir: config.dir.clone(),
+            state: "starting".into(),
+            changes: 0,
+            last_sync: None,
+            last_error: None,
+        }));
+        // each job has its own connection, so it can sync from any server
+        let task = tokio::spawn(run_sync(config.clone(), Arc::clone(&status)).instrument(info_span!("sync", job = id)));
+        SyncJob { config, status, task }
+    }
+}
+
+// the jobs are saved whenever one is started or stopped and started again with the daemon
+async fn save_sync_jobs(path: &Path, jobs: &BTreeMap<u64, SyncJob>) -> anyhow::Result<()> {
+    save_jobs(path, jobs.values().map(|job| job.config.clone()).collect()).await
+        .with_context(|| format!("Can't save the sync jobs to {}", path.display()))
+}
+
+pub async fn handle_client_daemon_message(
+    mut rx: mpsc::Receiver<DaemonMessage>, mut client: Client, server: String, jobs_path: PathBuf
+) {
+    let mut ping = tokio::time::interval(Duration::from_secs(PING_INTERVAL_SECS));
+    let mut jobs: BTreeMap<u64, SyncJob> = BTreeMap::new();
+    let mut next_job = 1;
+    match load_jobs(&jobs_path).await {
+        Ok(configs) => for config in configs {
+            jobs.insert(next_job, SyncJob::start(next_job, config));
+            next_job += 1;
+        },
+        Err(e) => warn!("Can't restore the sync jobs from {}: {e:#}", jobs_path.display()),
+    }
+    if !jobs.is_empty() {
+        info!("Restored {} sync jobs", jobs.len());
+    }
+    // commands that came in while a download was running
+    let mut deferred = VecDeque::new();
+    let stats = ClientStats {
+        started: Instant::now(),
+        connected: ConnectionInfo {
+            peer: server.clone(),
+            since: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        },
+        server,
+        fingerprint: client.server_fingerprint(),
+        transfer: Arc::new(Mutex::new(None)),
+        received: Arc::new(AtomicU64::new(0)),
+    };
+
+    loop {
+        let msg = if let Some(msg) = deferred.pop_front() { msg } else { tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = ping.tick() => {
+                if let Err(e) = client.ping().await {
+                    error!("Lost connection to server: {e}");
+                    break;
+                }
+                continue;
+            }
+        }};
+
+        let DaemonMessage { cmd, resp_tx, .. } = msg;
+        let resp = match cmd {
+            DaemonCommand::ListRemote => match client.list().await {
+                Ok(files) => resp_tx.pages(files, DaemonResponse::Files),
+                Err(e) => DaemonResponse::from_error(e),
+            },
+            DaemonCommand::Search(query) => match client.search(query).await {
+                Ok((total, hits)) => DaemonResponse::Search { total, hits },
+                Err(e) => DaemonResponse::from_error(e),
+            },
+            DaemonCommand::Download { name, by_hash, delta, output } => {
+                client.set_progress(Some(progress_reporter(resp_tx.clone(), name.clone(), &stats)));
+                let res = {
+                    let download = download(&mut client, name, by_hash, delta, Path::new(&output));
+                    tokio::pin!(download);
+                    // status stays answerable during a long download, anything else waits for it
+                    loop {
+                        tokio::select! {
+                            res = &mut download => break res,
+                            Some(msg) = rx.recv() => match msg.cmd {
+                                DaemonCommand::Status => {
+                                    msg.resp_tx.send(DaemonResponse::Status(stats.status(jobs.len())));
+                                }
+                                _ => deferred.push_back(msg),
+                            },
+                        }
+                    }
+                };
+                client.set_progress(None);
+                *stats.transfer.lock().unwrap() = None;
+                match res {
+                    Ok(msg) => DaemonResponse::Ok(msg),
+                    Err(e) => DaemonResponse::from_error(e.context("Download failed")),
+                }
+            }
+            DaemonCommand::Sync(config) => {
+                let id = next_job;
+                next_job += 1;
+                jobs.insert(id, SyncJob::start(id, config));
+                match save_sync_jobs(&jobs_path, &jobs).await {
+                    Ok(()) => DaemonResponse::Ok(format!("Started sync job {id}")),
+                    Err(e) => DaemonResponse::from_error(e.context(format!("Started sync job {id}, but it won't survive a restart"))),
+                }
+            }
+            DaemonCommand::Status => DaemonResponse::Status(stats.status(jobs.len())),
+            DaemonCommand::SyncStatus => {
+                DaemonResponse::SyncJobs(jobs.values().map(|job| job.status.lock().unwrap().clone()).collect())
+            }
+            DaemonCommand::SyncStop { id } => match jobs.remove(&id) {
+                Some(job) => {
+                    job.task.abort();
+                    match save_sync_jobs(&jobs_path, &jobs).await {
+                        Ok(()) => DaemonResponse::Ok(format!("Stopped sync job {id}")),
+                        Err(e) => DaemonResponse::from_error(e.context(format!("Stopped sync job {id}, but it will be back after a restart"))),
+                    }
+                }
+                None => DaemonResponse::Err(ErrorKind::NotFound, format!("No sync job {id}")),
+            },
+            DaemonCommand::Admin(cmd) => match client.admin(*cmd).await {
+                Ok(resp) => resp,
+                Err(e) => DaemonResponse::from_error(e),
+            },
+            _ => DaemonResponse::Err(ErrorKind::Usage, "Not supported by the client daemon".into()),
+        };
+
+        let _ = resp_tx.send(resp);
     }
-}
\ No newline at end of file
+}
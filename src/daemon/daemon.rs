@@ -1,124 +1,718 @@
-use std::fs::File;
+use std::fs::OpenOptions;
 use std::fs;
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use daemonize::Daemonize;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixStream;
+use anyhow::Context;
+use daemonize::{Daemonize, Outcome};
+use tracing::{error, info, warn};
+use serde_json::{json, Value};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::signal::unix::Signal;
+use tokio::io::AsyncReadExt;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::sync::mpsc;
 
-use crate::daemon::{DaemonCommand, DaemonMessage, DaemonResponse};
-use crate::network::Server;
+use crate::daemon::{
+    describe_audit, emit, json_output, CliError, CliResult, ControlHello, DaemonCommand, DaemonEvent, DaemonMessage, DaemonReply, DaemonResponse,
+    DaemonStatus, ErrorKind, HandlerReply, Responder, TimedEvent
+};
+use crate::daemon::{daemon_args, daemon_dir, running_daemon, wait_for_exit, ControlAccess, Owner, Peer, PidLock};
+use crate::daemon::{log_to, receive_sockets, set_log_filter, spawn_watchdog, take_control_listener, Heartbeat, LogTarget};
+use crate::network::{Codec, CodecError, Server};
+use crate::settings::{CONTROL_PROTOCOL_VERSION, VERSION};
+use crate::utils::{format_utc, hostname, unix_now};
 
+/// Runs `callback` with the control socket served by `listener`, either forked into the
+/// background logging to `log_path` or, with `foreground`, attached and logging to stderr
 pub fn start_daemon<F, Fut, L, Lfut>(
     callback: F,
     listener: L,
-    out_path: &str,
+    foreground: bool,
+    log_path: &str,
     err_path: &str,
     pid_path: &str,
-)
+) -> CliResult
 where
-    F: FnOnce(mpsc::Receiver<DaemonMessage>) -> Fut + Send + 'static,
-    Fut: std::future::Future<Output = ()> + Send + 'static,
+    F: FnOnce(mpsc::Sender<DaemonMessage>, mpsc::Receiver<DaemonMessage>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
     L: FnOnce(mpsc::Sender<DaemonMessage>) -> Lfut + Send + 'static,
     Lfut: std::future::Future<Output = ()> + Send + 'static,
 {
-    let stdout = File::create(out_path).unwrap();
-    let stderr = File::create(err_path).unwrap();
+    let mut lock = PidLock::acquire(pid_path)?;
+    let write_pid = |lock: &mut PidLock| lock.write_pid()
+        .map_err(|e| CliError::new(ErrorKind::Io, format!("Can't write {pid_path}: {e}")));
 
+    // before forking, systemd passes them to this very process
+    receive_sockets();
+
+    if foreground {
+        // everything the daemon prints is a log line
+        unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) };
+        write_pid(&mut lock)?;
+        return run_daemon(callback, listener, LogTarget::Stderr);
+    }
+
+    // only panics and the like end up here, the log itself is written by `log_to`
+    let open = || OpenOptions::new().create(true).append(true).open(err_path)
+        .map_err(|e| CliError::new(ErrorKind::Io, format!("Can't open {err_path}: {e}")));
+    let stdout = open()?;
+    let stderr = open()?;
+
+    // the lock is inherited, so it stays held by the daemon once this process exits
     let daemonize = Daemonize::new()
-        .pid_file(pid_path)
-        .chown_pid_file(true)
         .working_directory("/")
         .stdout(stdout)
         .stderr(stderr);
 
-    match daemonize.start() {
-        Ok(_) => {
-            tokio::runtime::Builder::new_multi_thread()
-                .enable_all()
-                .build()
-                .unwrap()
-                .block_on(async {
-                    let (tx, rx) = mpsc::channel::<DaemonMessage>(32);
-                    tokio::spawn(listener(tx.clone()));
-                    callback(rx).await;
-                });
+    match daemonize.execute() {
+        Outcome::Parent(Ok(_)) => {
+            emit(json!({ "message": "Daemon started" }), || println!("Daemon started"));
+            Ok(())
+        }
+        Outcome::Parent(Err(e)) | Outcome::Child(Err(e)) => {
+            Err(CliError::new(ErrorKind::Failed, format!("Failed to start daemon: {e}")))
+        }
+        Outcome::Child(Ok(_)) => {
+            write_pid(&mut lock)?;
+            run_daemon(callback, listener, LogTarget::File(log_path))
         }
-        Err(e) => eprintln!("Error: {}", e),
     }
 }
 
-pub fn stop_daemon(pid_path: &str) {
-    if let Ok(pid_str) = fs::read_to_string(pid_path) {
-        if let Ok(pid) = pid_str.trim().parse::<i32>() {
-            let res = unsafe { libc::kill(pid, libc::SIGTERM) }; // kills the process
-            if res == 0 {
-                println!("Stopped daemon with PID {pid}");
-            } else {
-                eprintln!("Failed to kill PID {pid}");
+fn run_daemon<F, Fut, L, Lfut>(callback: F, listener: L, log: LogTarget) -> CliResult
+where
+    F: FnOnce(mpsc::Sender<DaemonMessage>, mpsc::Receiver<DaemonMessage>) -> Fut + Send + 'static,
+    Fut: std::future::Future<Output = anyhow::Result<()>> + Send + 'static,
+    L: FnOnce(mpsc::Sender<DaemonMessage>) -> Lfut + Send + 'static,
+    Lfut: std::future::Future<Output = ()> + Send + 'static,
+{
+    log_to(log).map_err(|e| CliError::new(ErrorKind::Io, format!("Can't open the log: {e}")))?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(async {
+            let (tx, rx) = mpsc::channel::<DaemonMessage>(32);
+            tokio::spawn(listener(tx.clone()));
+            spawn_watchdog();
+            callback(tx, rx).await
+        })?;
+    Ok(())
+}
+
+// commands run on a short-lived runtime, the process may fork into a daemon
+// first and that has to happen before any runtime threads exist
+pub fn block_on<F: std::future::Future>(fut: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(fut)
+}
+
+pub fn stop_daemon(pid_path: &str) -> CliResult {
+    let pid = terminate(pid_path)?;
+    emit(json!({ "pid": pid }), || println!("Stopped daemon with PID {pid}"));
+    Ok(())
+}
+
+// SIGTERM to the daemon holding the lock on `pid_path`, returns once it is gone
+fn terminate(pid_path: &str) -> Result<i32, CliError> {
+    let Some(pid) = running_daemon(pid_path)? else {
+        return Err(CliError::new(ErrorKind::NotRunning, "No running daemon found"));
+    };
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(CliError::new(ErrorKind::Failed, format!("Failed to kill PID {pid}")));
+    }
+    wait_for_exit(pid_path)?;
+    let _ = fs::remove_file(pid_path);
+    Ok(pid)
+}
+
+/// Stops the daemon and starts it again with the arguments it was started with,
+/// from the directory it was started in so relative paths mean the same
+pub fn restart_daemon(pid_path: &str) -> CliResult {
+    let Some(pid) = running_daemon(pid_path)? else {
+        return Err(CliError::new(ErrorKind::NotRunning, "No running daemon found"));
+    };
+    let Some(mut args) = daemon_args(pid) else {
+        return Err(CliError::new(ErrorKind::Failed, format!("Can't read the arguments of PID {pid}")));
+    };
+    if args.iter().any(|arg| arg == "--foreground") {
+        let message = "Daemon runs in the foreground, restart it through its service manager";
+        return Err(CliError::new(ErrorKind::Usage, message));
+    }
+    // the new start reports in the format asked for now
+    args.retain(|arg| arg != "--json");
+    if json_output() {
+        args.push("--json".into());
+    }
+    let dir = daemon_dir(pid_path);
+
+    terminate(pid_path)?;
+    let exe = std::env::current_exe()
+        .map_err(|e| CliError::new(ErrorKind::Io, format!("Can't find this program: {e}")))?;
+    let mut command = Command::new(exe);
+    command.args(&args);
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+    let status = command.status()
+        .map_err(|e| CliError::new(ErrorKind::Io, format!("Can't start the daemon: {e}")))?;
+    match status.code() {
+        Some(0) => Ok(()),
+        // the start already reported why
+        Some(code) => std::process::exit(code),
+        None => Err(CliError::new(ErrorKind::Failed, "Daemon was stopped but did not start again")),
+    }
+}
+
+/// Asks the daemon to re-read its shares and TLS certificate, open connections are kept.
+/// Start options are not re-read, changing them takes a restart
+pub fn reload_daemon(pid_path: &str) -> CliResult {
+    let Some(pid) = running_daemon(pid_path)? else {
+        return Err(CliError::new(ErrorKind::NotRunning, "No running daemon found"));
+    };
+    if unsafe { libc::kill(pid, libc::SIGHUP) } != 0 {
+        return Err(CliError::new(ErrorKind::Failed, format!("Failed to signal PID {pid}")));
+    }
+    emit(json!({ "pid": pid }), || println!("Reloading daemon with PID {pid}"));
+    Ok(())
+}
+
+/// Reloads `server` on every SIGHUP until the stream ends
+pub async fn reload_on_hangup(server: &Server, mut hangup: Signal) {
+    while hangup.recv().await.is_some() {
+        info!("Reloading shares and TLS certificate");
+        match server.reload().await {
+            Ok(()) => info!("Reloaded"),
+            Err(e) => error!("Reload failed: {e:#}"),
+        }
+    }
+}
+
+/// Sends `cmd` to the daemon listening on `socket_path` and collects its responses.
+/// Progress updates are drawn on stderr while they come in
+pub async fn send_command(cmd: DaemonCommand, socket_path: &str) -> anyhow::Result<Vec<DaemonResponse>> {
+    let mut responses = Vec::new();
+    let mut progress_shown = false;
+    stream_command(cmd, socket_path, |reply| match reply {
+        DaemonReply::Progress(p) if json_output() => eprintln!("{}", json!({ "progress": p })),
+        DaemonReply::Progress(p) => {
+            match p.total {
+                Some(total) if total > 0 => eprint!("\r{}: {}/{total} bytes ({}%)", p.label, p.done, p.done * 100 / total),
+                _ => eprint!("\r{}: {} bytes", p.label, p.done),
             }
-            let _ = fs::remove_file(pid_path);
+            progress_shown = true;
         }
-    } else {
-        eprintln!("No running daemon found");
+        DaemonReply::Response(resp) => {
+            if std::mem::take(&mut progress_shown) {
+                eprintln!();
+            }
+            responses.push(resp);
+        }
+        // only a subscription gets events, see `watch_daemon`
+        DaemonReply::Event(_) | DaemonReply::End => {}
+    }).await?;
+    if progress_shown {
+        eprintln!();
     }
+    Ok(responses)
 }
 
-pub async fn send_command(cmd: DaemonCommand, socket_path: &str) -> anyhow::Result<DaemonResponse> {
-    let mut stream = UnixStream::connect(socket_path).await?;
+/// Prints the events of the daemon at `socket_path` as they happen, until interrupted.
+/// With --json every event is one line, for tools to react on
+pub fn watch_daemon(socket_path: &str) -> CliResult {
+    let mut error = None;
+    let res = block_on(stream_command(DaemonCommand::Subscribe, socket_path, |reply| match reply {
+        DaemonReply::Event(TimedEvent { time, event }) => {
+            emit(json!({ "time": time, "event": event }), || println!("{} {event}", format_utc(time)));
+        }
+        // a daemon without events answers with an error instead
+        DaemonReply::Response(DaemonResponse::Err(kind, message)) => {
+            error.get_or_insert(CliError::new(kind, message));
+        }
+        _ => {}
+    }));
+    if let Some(e) = error {
+        return Err(e);
+    }
+    Ok(res.context("Event stream ended")?)
+}
 
-    // sends command
-    let encoded: Vec<u8> = bincode::serialize(&cmd)?;
-    stream.write(&encoded).await?;
+/// Sends `cmd` and hands every reply to `on_reply` until the daemon ends the stream
+pub async fn stream_command<F: FnMut(DaemonReply)>(cmd: DaemonCommand, socket_path: &str, mut on_reply: F) -> anyhow::Result<()> {
+    let mut stream = UnixStream::connect(socket_path).await.map_err(|e| match e.kind() {
+        std::io::ErrorKind::PermissionDenied => {
+            CliError::new(ErrorKind::PermissionDenied, format!("No access to the daemon at {socket_path}"))
+        }
+        _ => CliError::new(ErrorKind::NotRunning, format!("No daemon listening on {socket_path}: {e}")),
+    })?;
+    let codec = Codec::default();
+
+    codec.send(&mut stream, &ControlHello::current()).await?;
+    let hello: ControlHello = codec.recv(&mut stream).await?;
+    if hello.version != CONTROL_PROTOCOL_VERSION {
+        let message = format!(
+            "Daemon speaks control protocol v{} (build {}), this binary v{CONTROL_PROTOCOL_VERSION} (build {VERSION}), restart the daemon",
+            hello.version, hello.build,
+        );
+        return Err(CliError::new(ErrorKind::Protocol, message).into());
+    }
+    codec.send(&mut stream, &cmd).await?;
 
-    // response
-    let mut buf = vec![0u8; 1024];
-    let n = stream.read(&mut buf).await?;
-    let resp: DaemonResponse = bincode::deserialize(&buf[..n])?;
+    // commands like downloads may take arbitrarily long
+    let codec = codec.with_read_timeout(None);
+    loop {
+        match codec.recv(&mut stream).await {
+            Ok(DaemonReply::End) => return Ok(()),
+            Ok(reply) => on_reply(reply),
+            Err(CodecError::Disconnected) => {
+                return Err(CliError::new(ErrorKind::Protocol, "Daemon closed the connection before finishing").into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
 
-    Ok(resp)
+pub async fn handle_daemon_message(mut rx: mpsc::Receiver<DaemonMessage>, server: &Server) {
+    let mut heartbeat = Heartbeat::register();
+    loop {
+        let msg = tokio::select! {
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = heartbeat.tick() => continue,
+        };
+        // commands run on tasks of their own, so a slow one doesn't keep the loop from its heartbeat
+        let server = server.clone();
+        tokio::spawn(async move { handle_command(&server, msg).await });
+    }
 }
 
-pub async fn handle_daemon_message(mut rx: mpsc::Receiver<DaemonMessage>, server: Server) {
-    while let Some(msg) = rx.recv().await {
-        let DaemonMessage { cmd, resp_tx } = msg;
-        let resp = match cmd {
-            DaemonCommand::Add { path, name } => {
-                // if name is None than take it from path: .../.../test.txt -> test.txt
-                let name = name.unwrap_or_else(|| {
-                    Path::new(&path)
-                        .file_name()
-                        .unwrap()
-                        .to_string_lossy()
-                        .into()
-                });
-                server.add_file(name, PathBuf::from(path)).await;
-                DaemonResponse::Ok("File added".into())
+async fn handle_command(server: &Server, msg: DaemonMessage) {
+    let DaemonMessage { cmd, resp_tx, owner } = msg;
+    let resp = match cmd {
+        DaemonCommand::Add { path, name } => {
+            // if name is None than take it from path: .../.../test.txt -> test.txt
+            match name.or_else(|| Path::new(&path).file_name().map(|n| n.to_string_lossy().into_owned())) {
+                Some(name) => {
+                    server.add_file(name, PathBuf::from(path), owner).await;
+                    DaemonResponse::Ok("File added".into())
+                }
+                None => DaemonResponse::Err(ErrorKind::Usage, format!("Can't derive a share name from {path}")),
+            }
+        }
+        DaemonCommand::Delete { name } => {
+            server.remove_file(&name).await;
+            DaemonResponse::Ok(format!("File '{name}' deleted"))
+        }
+        DaemonCommand::List => {
+            let list = server.list_files().await.into_iter().collect();
+            resp_tx.pages(list, |page| DaemonResponse::List(page.into_iter().collect()))
+        }
+        DaemonCommand::Rename { name, new_name } => {
+            if server.rename_file(&name, new_name.clone()).await {
+                DaemonResponse::Ok(format!("File '{name}' renamed to '{new_name}'"))
+            } else {
+                DaemonResponse::Err(ErrorKind::Failed, format!("Can't rename '{name}' to '{new_name}'"))
+            }
+        }
+        DaemonCommand::Tag { name, tags } => {
+            if server.set_tags(&name, tags).await {
+                DaemonResponse::Ok(format!("Tags of '{name}' updated"))
+            } else {
+                DaemonResponse::Err(ErrorKind::NotFound, format!("File '{name}' not found"))
+            }
+        }
+        DaemonCommand::Status => DaemonResponse::Status(server.status().await),
+        DaemonCommand::Subscribe => {
+            // the control connection streams them until the watcher goes away
+            resp_tx.subscribe(server.activity());
+            return;
+        }
+        DaemonCommand::Link { name, host, expires } => {
+            let host = host.unwrap_or_else(hostname);
+            match server.share_link(&name, &host, expires).await {
+                Ok(uri) => DaemonResponse::Ok(uri.to_string()),
+                Err(e) => DaemonResponse::from_error(e),
+            }
+        }
+        DaemonCommand::Links => DaemonResponse::Links(server.links().await),
+        DaemonCommand::Audit(query) => match server.audit_records(query).await {
+            Ok(records) => {
+                let lines = records.iter().filter_map(|record| serde_json::to_string(record).ok()).collect();
+                resp_tx.pages(lines, DaemonResponse::Audit)
             }
-            DaemonCommand::Delete { name } => {
-                server.remove_file(&name).await;
-                DaemonResponse::Ok(format!("File '{name}' deleted"))
+            Err(e) => DaemonResponse::from_error(e),
+        },
+        DaemonCommand::RevokeLink { id } => {
+            if server.revoke_link(&id).await {
+                DaemonResponse::Ok(format!("Link {id} revoked"))
+            } else {
+                DaemonResponse::Err(ErrorKind::NotFound, format!("No link {id}"))
             }
-            DaemonCommand::List => {
-                let list = server.list_files().await;
-                DaemonResponse::List(list)
+        }
+        DaemonCommand::ListRemote | DaemonCommand::Search(_) | DaemonCommand::Download { .. }
+        | DaemonCommand::Sync(_) | DaemonCommand::SyncStatus | DaemonCommand::SyncStop { .. }
+        | DaemonCommand::Admin(_) | DaemonCommand::LogLevel { .. } => {
+            DaemonResponse::Err(ErrorKind::Usage, "Not supported by the server daemon".into())
+        }
+    };
+
+    let _ = resp_tx.send(resp);
+}
+
+/// Prints what the daemon answered and fails with the first error it reported
+pub fn handle_response(result: anyhow::Result<Vec<DaemonResponse>>) -> CliResult {
+    let mut values = Vec::new();
+    let mut error = None;
+    for resp in DaemonResponse::merge_pages(result?) {
+        match resp {
+            DaemonResponse::Err(kind, message) => {
+                error.get_or_insert(CliError::new(kind, message));
+            }
+            resp if json_output() => values.push(response_json(&resp)),
+            resp => print_response(resp),
+        }
+    }
+    if let Some(e) = error {
+        return Err(e);
+    }
+    if json_output() {
+        let data = if values.len() == 1 { values.remove(0) } else { Value::Array(values) };
+        emit(data, || {});
+    }
+    Ok(())
+}
+
+fn response_json(resp: &DaemonResponse) -> Value {
+    match resp {
+        DaemonResponse::Ok(message) => json!({ "message": message }),
+        DaemonResponse::Err(kind, message) => json!({ "error": { "kind": kind, "message": message } }),
+        DaemonResponse::List(shares) => json!({ "shares": shares }),
+        DaemonResponse::Files(files) => json!({ "files": files }),
+        DaemonResponse::Search { total, hits } => json!({ "total": total, "hits": hits }),
+        DaemonResponse::SyncJobs(jobs) => json!({ "jobs": jobs }),
+        DaemonResponse::Status(status) => json!(status),
+        DaemonResponse::Links(links) => json!({ "links": links }),
+        DaemonResponse::Audit(lines) => {
+            let records = lines.iter().filter_map(|line| serde_json::from_str::<Value>(line).ok()).collect::<Vec<_>>();
+            json!({ "records": records })
+        }
+    }
+}
+
+fn print_response(resp: DaemonResponse) {
+    match resp {
+        DaemonResponse::Ok(msg) => println!("{msg}"),
+        DaemonResponse::Err(_, msg) => eprintln!("{msg}"),
+        DaemonResponse::List(shares) => {
+            for share in shares {
+                let state = if share.available { "" } else { ", unavailable" };
+                println!("{} ({}{state})", share.name, share.path);
+            }
+        }
+        DaemonResponse::Files(files) => {
+            for entry in files {
+                let state = if entry.available { "" } else { " (unavailable)" };
+                println!("{} {}{state}", entry.hash.as_deref().unwrap_or("-"), entry.name);
+            }
+        }
+        DaemonResponse::Search { total, hits } => {
+            for hit in &hits {
+                println!("{} ({} bytes) [{}]", hit.name, hit.size, hit.tags.join(", "));
+            }
+            println!("{} of {total} results", hits.len());
+        }
+        DaemonResponse::Status(status) => print_status(&status),
+        DaemonResponse::Links(links) if links.is_empty() => println!("No links"),
+        DaemonResponse::Links(links) => {
+            for link in links {
+                let expires = link.expires.map_or("never".into(), format_utc);
+                println!("{} {} expires={expires} hash={}", link.id, link.name, link.hash);
+            }
+        }
+        DaemonResponse::Audit(lines) => {
+            for line in lines {
+                match serde_json::from_str(&line) {
+                    Ok(record) => println!("{}", describe_audit(&record)),
+                    Err(_) => println!("{line}"),
+                }
+            }
+        }
+        DaemonResponse::SyncJobs(jobs) if jobs.is_empty() => println!("No sync jobs"),
+        DaemonResponse::SyncJobs(jobs) => {
+            for job in jobs {
+                let last_sync = job.last_sync.map_or("never".into(), |t| t.to_string());
+                println!(
+                    "[{}] {} '{}' -> {} {} changes={} last_sync={last_sync}",
+                    job.id, job.server, job.prefix, job.dir.display(), job.state, job.changes,
+                );
+                if let Some(e) = job.last_error {
+                    println!("    error: {e}");
+                }
+            }
+        }
+    }
+}
+
+fn print_status(status: &DaemonStatus) {
+    println!("PID:         {}", status.pid);
+    println!("Uptime:      {}", format_duration(status.uptime_secs));
+    println!("Addresses:   {}", status.addresses.join(", "));
+    println!("Fingerprint: {}", status.fingerprint.as_deref().unwrap_or("-"));
+    if let Some(shares) = status.shares {
+        println!("Shares:      {shares}");
+    }
+    if let Some(jobs) = status.sync_jobs {
+        println!("Sync jobs:   {jobs}");
+    }
+    println!("Transferred: {} bytes", status.bytes_transferred);
+    println!("Connections: {}", status.connections.len());
+    for conn in &status.connections {
+        println!("    {} since {}", conn.peer, conn.since);
+    }
+    println!("Transfers:   {}", status.transfers.len());
+    for transfer in &status.transfers {
+        let percent = (transfer.done.saturating_mul(100)).checked_div(transfer.size).unwrap_or(100);
+        println!("    '{}' ({}) {}/{} bytes ({percent}%)", transfer.name, transfer.peer, transfer.done, transfer.size);
+    }
+}
+
+fn format_duration(secs: u64) -> String {
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{days}d {hours}h {mins}m")
+    } else if hours > 0 {
+        format!("{hours}h {mins}m {secs}s")
+    } else {
+        format!("{mins}m {secs}s")
+    }
+}
+
+/// Binds the control socket right away, so it is there before the daemon reports
+/// being ready, and returns the task accepting on it
+pub fn start_listener(
+    socket_path: &str,
+    access: ControlAccess,
+    tx: mpsc::Sender<DaemonMessage>,
+) -> impl Future<Output = ()> {
+    let listener = bind_control(socket_path, &access);
+    async move {
+        match listener {
+            Ok(listener) => accept_control(listener, access, tx).await,
+            Err(e) => error!("Failed to bind Unix socket: {e}"),
+        }
+    }
+}
+
+// a socket passed in by systemd has the mode its unit gives it
+fn bind_control(socket_path: &str, access: &ControlAccess) -> std::io::Result<UnixListener> {
+    if let Some(listener) = take_control_listener() {
+        listener.set_nonblocking(true)?;
+        return UnixListener::from_std(listener);
+    }
+
+    if Path::new(socket_path).exists() {
+        let _ = fs::remove_file(socket_path);
+    }
+    // the socket gets its mode when it is created, it is never open to anyone else in between.
+    // The umask is process wide, a file another thread creates meanwhile gets at most this mode
+    let umask = unsafe { libc::umask(!access.socket_mode() & 0o777) };
+    let listener = UnixListener::bind(socket_path);
+    unsafe { libc::umask(umask) };
+    listener
+}
+
+async fn accept_control(listener: UnixListener, access: ControlAccess, tx: mpsc::Sender<DaemonMessage>) {
+    let mut heartbeat = Heartbeat::register();
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = heartbeat.tick() => continue,
+        };
+        let (socket, _) = match accepted {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let peer = match Peer::of(&socket) {
+            Ok(peer) => peer,
+            Err(e) => {
+                warn!("Refused control connection without peer credentials: {e}");
+                continue;
             }
         };
+        let access = access.clone();
+        let tx = tx.clone();
 
-        let _ = resp_tx.send(resp);
+        tokio::spawn(async move {
+            match serve_control(socket, peer, access, tx).await {
+                Ok(()) | Err(CodecError::Disconnected) => {}
+                Err(e) => warn!("Control connection failed: {e}"),
+            }
+        });
     }
 }
 
-pub fn handle_response(result: anyhow::Result<DaemonResponse>) {
-    match result {
-        Ok(DaemonResponse::Ok(msg)) => println!("{msg}"),
-        Ok(DaemonResponse::Err(msg)) => eprintln!("{msg}"),
-        Ok(DaemonResponse::List(files)) => {
-            for (k, v) in files {
-                println!("{k} ({v})");
+// one command per connection: hello, command, then replies until the handler is done
+async fn serve_control(
+    mut socket: UnixStream,
+    peer: Peer,
+    access: ControlAccess,
+    tx: mpsc::Sender<DaemonMessage>,
+) -> Result<(), CodecError> {
+    let codec = Codec::default();
+
+    let hello: ControlHello = codec.recv(&mut socket).await?;
+    codec.send(&mut socket, &ControlHello::current()).await?;
+    if hello.version != CONTROL_PROTOCOL_VERSION {
+        warn!(
+            "Rejected control client speaking protocol v{} (build {}), expected v{CONTROL_PROTOCOL_VERSION}",
+            hello.version, hello.build,
+        );
+        return Ok(());
+    }
+
+    let cmd = match codec.recv::<DaemonCommand, _>(&mut socket).await {
+        Ok(cmd) => cmd,
+        Err(CodecError::Protocol(e)) => {
+            let resp = DaemonReply::Response(DaemonResponse::Err(ErrorKind::Protocol, format!("Invalid command: {e}")));
+            codec.send(&mut socket, &resp).await?;
+            return codec.send(&mut socket, &DaemonReply::End).await;
+        }
+        Err(e) => return Err(e),
+    };
+    let (cmd, owner) = match authorize(cmd, &peer, &access).await {
+        Ok(authorized) => authorized,
+        Err((kind, message)) => {
+            codec.send(&mut socket, &DaemonReply::Response(DaemonResponse::Err(kind, message))).await?;
+            return codec.send(&mut socket, &DaemonReply::End).await;
+        }
+    };
+
+    // the log belongs to the process, not to whatever the daemon runs
+    if let DaemonCommand::LogLevel { filter } = &cmd {
+        let resp = match set_log_filter(filter) {
+            Ok(()) => {
+                info!(filter, "Log filter changed");
+                DaemonResponse::Ok(format!("Logging at '{filter}'"))
             }
+            Err(e) => DaemonResponse::Err(ErrorKind::Usage, format!("Invalid log filter '{filter}': {e}")),
+        };
+        codec.send(&mut socket, &DaemonReply::Response(resp)).await?;
+        return codec.send(&mut socket, &DaemonReply::End).await;
+    }
+
+    let (resp_tx, mut resp_rx) = Responder::channel();
+    if tx.send(DaemonMessage { cmd, resp_tx, owner }).await.is_err() {
+        let resp = DaemonReply::Response(DaemonResponse::Err(ErrorKind::NotRunning, "Daemon not running".into()));
+        codec.send(&mut socket, &resp).await?;
+    }
+
+    // the user sends nothing after the command, so a read only returns once it hangs up.
+    // Dropping the receiver then tells the handler, e.g. a subscription, to stop
+    let (mut reader, mut writer) = socket.split();
+    let mut byte = [0u8; 1];
+    loop {
+        tokio::select! {
+            reply = resp_rx.recv() => match reply {
+                Some(HandlerReply::Reply(reply)) => codec.send(&mut writer, &reply).await?,
+                Some(HandlerReply::Events(mut events)) => loop {
+                    tokio::select! {
+                        event = events.recv() => {
+                            let event = match event {
+                                Ok(event) => event,
+                                // the backlog of a slow watcher is capped, it hears what it lost instead
+                                Err(RecvError::Lagged(count)) => TimedEvent { time: unix_now(), event: DaemonEvent::Missed { count } },
+                                Err(RecvError::Closed) => break,
+                            };
+                            codec.send(&mut writer, &DaemonReply::Event(event)).await?;
+                        }
+                        _ = reader.read(&mut byte) => return Ok(()),
+                    }
+                },
+                None => break,
+            },
+            _ = reader.read(&mut byte) => return Ok(()),
+        }
+    }
+    codec.send(&mut writer, &DaemonReply::End).await
+}
+
+// refuses what the peer may not do. Add gets the path resolved as the peer sees it, and only
+// if the peer could open the file itself. Its share keeps being opened with the peer's access
+async fn authorize(
+    cmd: DaemonCommand,
+    peer: &Peer,
+    access: &ControlAccess,
+) -> Result<(DaemonCommand, Option<Owner>), (ErrorKind, String)> {
+    if !cmd.is_read_only() && !access.allows(peer) {
+        warn!(uid = peer.uid, "Refused control command from a user without access");
+        let message = match cmd {
+            DaemonCommand::Subscribe => "Not allowed to watch this daemon",
+            DaemonCommand::Audit(_) => "Not allowed to read the audit log of this daemon",
+            _ => "Not allowed to change this daemon",
+        };
+        return Err((ErrorKind::PermissionDenied, message.into()));
+    }
+
+    let owner = peer.owner();
+    match cmd {
+        DaemonCommand::Add { path, name } => {
+            let resolved = peer.resolve(&path).ok();
+            let readable = match (&resolved, &owner) {
+                (Some(resolved), Some(owner)) => {
+                    let (owner, resolved) = (owner.clone(), resolved.clone());
+                    tokio::task::spawn_blocking(move || owner.open(&resolved).is_ok()).await.unwrap_or(false)
+                }
+                (resolved, _) => resolved.is_some(),
+            };
+            // the same answer for missing and unreadable, so nothing is learned about hidden files
+            let (Some(resolved), true) = (resolved, readable) else {
+                let message = format!("Can't read {path}: it does not exist or you have no access");
+                return Err((ErrorKind::PermissionDenied, message));
+            };
+            let name = name.or_else(|| Path::new(&path).file_name().map(|n| n.to_string_lossy().into_owned()));
+            Ok((DaemonCommand::Add { path: resolved.to_string_lossy().into_owned(), name }, owner))
+        }
+        cmd => Ok((cmd, owner)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // runs `cmd` through the command handler the way the control socket does
+    async fn handle(server: &Server, cmd: DaemonCommand) -> DaemonResponse {
+        let (tx, rx) = mpsc::channel(1);
+        let (resp_tx, mut resp_rx) = Responder::channel();
+        tx.send(DaemonMessage { cmd, resp_tx, owner: None }).await.unwrap();
+        drop(tx);
+        handle_daemon_message(rx, server).await;
+        match resp_rx.recv().await {
+            Some(HandlerReply::Reply(DaemonReply::Response(resp))) => resp,
+            _ => panic!("the handler didn't respond"),
+        }
+    }
+
+    #[tokio::test]
+    async fn add_refuses_paths_without_a_file_name() {
+        let server = Server::new(None);
+        for path in ["/", "..", "foo/.."] {
+            let resp = handle(&server, DaemonCommand::Add { path: path.into(), name: None }).await;
+            assert!(matches!(resp, DaemonResponse::Err(ErrorKind::Usage, _)), "{path}: {resp:?}");
         }
-        Err(e) => eprint!("Error sending command: {e}"),
+        // the handler is still there for the next one
+        let resp = handle(&server, DaemonCommand::Add { path: "/".into(), name: Some("root".into()) }).await;
+        assert!(matches!(resp, DaemonResponse::Ok(_)), "{resp:?}");
     }
 }
@@ -0,0 +1,136 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::ffi::OsStr;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::daemon::{CliError, ErrorKind};
+use crate::settings::DAEMON_STOP_TIMEOUT_SECS;
+
+/// Exclusive lock on a daemon's PID file. It is taken before forking and stays with the
+/// daemon, so it is released only when the daemon process is gone
+pub struct PidLock {
+    file: File,
+    // where the daemon was started from, it changes to / once forked
+    dir: Option<PathBuf>,
+}
+
+impl PidLock {
+    /// Fails if another daemon holds the lock
+    pub fn acquire(path: &str) -> Result<Self, CliError> {
+        let mut file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)
+            .map_err(|e| CliError::new(ErrorKind::Io, format!("Can't open {path}: {e}")))?;
+        if !try_lock(&file, libc::LOCK_EX)? {
+            let message = match read_pid(&mut file) {
+                Some(pid) => format!("Daemon already running with PID {pid}"),
+                None => "Daemon already running".to_string(),
+            };
+            return Err(CliError::new(ErrorKind::AlreadyRunning, message));
+        }
+        Ok(PidLock { file, dir: std::env::current_dir().ok() })
+    }
+
+    /// Records the PID of the calling process, the one that goes on as the daemon,
+    /// followed by the directory it was started from
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.rewind()?;
+        writeln!(self.file, "{}", std::process::id())?;
+        if let Some(dir) = &self.dir {
+            self.file.write_all(dir.as_os_str().as_bytes())?;
+            writeln!(self.file)?;
+        }
+        Ok(())
+    }
+}
+
+// false if someone else holds a conflicting lock
+fn try_lock(file: &File, op: libc::c_int) -> Result<bool, CliError> {
+    if unsafe { libc::flock(file.as_raw_fd(), op | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    match io::Error::last_os_error() {
+        e if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        e => Err(CliError::new(ErrorKind::Io, format!("Can't lock the PID file: {e}"))),
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<i32> {
+    let mut content = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.lines().next()?.trim().parse().ok()
+}
+
+/// Directory the daemon holding the lock on `path` was started from, relative
+/// paths among its arguments were meant from there
+pub fn daemon_dir(path: &str) -> Option<PathBuf> {
+    let content = fs::read(path).ok()?;
+    let dir = content.split(|b| *b == b'\n').nth(1).filter(|dir| !dir.is_empty())?;
+    Some(PathBuf::from(OsStr::from_bytes(dir)))
+}
+
+/// PID of the daemon holding the lock on `path`. A PID file nobody holds is stale and removed,
+/// one naming a process that isn't this program is an error, so it never gets signalled
+pub fn running_daemon(path: &str) -> Result<Option<i32>, CliError> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(CliError::new(ErrorKind::Io, format!("Can't open {path}: {e}"))),
+    };
+    if try_lock(&file, libc::LOCK_SH)? {
+        let _ = fs::remove_file(path);
+        return Ok(None);
+    }
+
+    let Some(pid) = read_pid(&mut file) else {
+        return Err(CliError::new(ErrorKind::Failed, "Daemon is still starting, try again"));
+    };
+    if daemon_args(pid).is_none() {
+        let message = format!("{path} names PID {pid}, which is not a daemon of this program");
+        return Err(CliError::new(ErrorKind::Failed, message));
+    }
+    Ok(Some(pid))
+}
+
+/// Arguments the daemon with `pid` was started with, None if it runs some other executable
+pub fn daemon_args(pid: i32) -> Option<Vec<String>> {
+    if !runs_this_program(pid) {
+        return None;
+    }
+    let cmdline = fs::read(format!("/proc/{pid}/cmdline")).ok()?;
+    let mut args = cmdline.split(|b| *b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned());
+    args.next()?;
+    Some(args.collect())
+}
+
+// same executable as the calling process. One replaced since the daemon started,
+// e.g. by an upgrade, shows up with a ` (deleted)` suffix
+fn runs_this_program(pid: i32) -> bool {
+    let Ok(exe) = fs::read_link(format!("/proc/{pid}/exe")) else {
+        return false;
+    };
+    let Ok(ours) = std::env::current_exe() else {
+        return false;
+    };
+    let exe = exe.as_os_str().as_bytes();
+    let exe = exe.strip_suffix(b" (deleted)").unwrap_or(exe);
+    Path::new(OsStr::from_bytes(exe)) == ours
+}
+
+/// Waits until the daemon holding the lock on `path` is gone
+pub fn wait_for_exit(path: &str) -> Result<(), CliError> {
+    let deadline = Instant::now() + Duration::from_secs(DAEMON_STOP_TIMEOUT_SECS);
+    while Instant::now() < deadline {
+        match File::open(path) {
+            Ok(file) if !try_lock(&file, libc::LOCK_SH)? => std::thread::sleep(Duration::from_millis(100)),
+            _ => return Ok(()),
+        }
+    }
+    let message = format!("Daemon did not stop within {DAEMON_STOP_TIMEOUT_SECS}s");
+    Err(CliError::new(ErrorKind::Failed, message))
+}
@@ -0,0 +1,229 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use serde_json::json;
+use tracing::Level;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use crate::daemon::{emit, json_output, CliError, CliResult, ErrorKind};
+use crate::settings::{DEFAULT_LOG_FILTER, LOGS_POLL_MS, LOG_ENV, LOG_KEEP_FILES, LOG_MAX_SIZE};
+
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+type Output = Box<dyn Layer<Filtered> + Send + Sync>;
+
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+static OUTPUT: OnceLock<reload::Handle<Output, Filtered>> = OnceLock::new();
+
+/// Sets up logging for the command line: bare messages on stderr, so stdout only has results.
+/// A daemon switches to full log lines with `log_to`
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_env(LOG_ENV).unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER));
+    let (filter, filter_handle) = reload::Layer::new(filter);
+    let output: Output = Box::new(
+        fmt::layer().with_writer(io::stderr).without_time().with_level(false).with_target(false)
+    );
+    let (output, output_handle) = reload::Layer::new(output);
+
+    tracing_subscriber::registry().with(filter).with(output).init();
+    let _ = FILTER.set(filter_handle);
+    let _ = OUTPUT.set(output_handle);
+}
+
+/// Where a daemon writes its log
+pub enum LogTarget<'a> {
+    Stderr,
+    File(&'a str),
+}
+
+/// Switches to timestamped, leveled lines with span fields, as a daemon logs
+pub fn log_to(target: LogTarget) -> io::Result<()> {
+    let layer = fmt::layer().with_ansi(false).with_target(false);
+    let output: Output = match target {
+        LogTarget::Stderr => Box::new(layer.with_writer(io::stderr)),
+        LogTarget::File(path) => {
+            let file = RotatingFile::open(Path::new(path))?;
+            Box::new(layer.with_writer(move || file.clone()))
+        }
+    };
+    if let Some(handle) = OUTPUT.get() {
+        handle.reload(output).map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+/// Replaces the log filter of the running process, takes the same syntax as FILE_SHARE_LOG
+pub fn set_log_filter(filter: &str) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(filter)?;
+    if let Some(handle) = FILTER.get() {
+        handle.reload(filter)?;
+    }
+    Ok(())
+}
+
+/// Log file that moves to `path.1`, shifting older ones up to `path.LOG_KEEP_FILES`,
+/// once it grows past LOG_MAX_SIZE
+#[derive(Clone)]
+struct RotatingFile(Arc<Mutex<LogFile>>);
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile(Arc::new(Mutex::new(LogFile { path: path.to_path_buf(), file, size }))))
+    }
+}
+
+impl LogFile {
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..LOG_KEEP_FILES).rev() {
+            let _ = fs::rename(numbered(&self.path, i), numbered(&self.path, i + 1));
+        }
+        fs::rename(&self.path, numbered(&self.path, 1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{i}"));
+    PathBuf::from(name)
+}
+
+impl Write for RotatingFile {
+    // every event arrives in one write, so lines are never split across files
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut log = self.0.lock().unwrap();
+        if log.size > 0 && log.size + buf.len() as u64 > LOG_MAX_SIZE {
+            log.rotate()?;
+        }
+        let n = log.file.write(buf)?;
+        log.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().file.flush()
+    }
+}
+
+/// Which log lines `daemon logs` shows
+pub struct LogFilter {
+    /// least severe level shown
+    pub level: Option<Level>,
+    /// text the line has to contain, e.g. `peer=10.0.0.2` or `share=notes`
+    pub grep: Option<String>,
+}
+
+impl LogFilter {
+    // continuation lines of a multi-line message have no level of their own
+    fn matches(&self, line: &str, level: Option<Level>) -> bool {
+        let level_ok = match (self.level, level) {
+            (Some(min), Some(level)) => level <= min,
+            _ => true,
+        };
+        level_ok && self.grep.as_ref().is_none_or(|text| line.contains(text.as_str()))
+    }
+}
+
+// lines look like `2026-10-19T04:00:00.123456Z  INFO conn{peer=..}: message`
+fn line_level(line: &str) -> Option<Level> {
+    line.split_whitespace().nth(1).and_then(|token| Level::from_str(token).ok())
+}
+
+fn print_line(line: &str, level: Option<Level>) {
+    if json_output() {
+        let mut parts = line.splitn(2, ' ');
+        let time = parts.next().unwrap_or_default();
+        let message = parts.next().unwrap_or_default().trim_start();
+        let message = match level {
+            Some(_) => message.split_once(' ').map_or("", |(_, rest)| rest),
+            None => line,
+        };
+        let level = level.map(|l| l.to_string().to_lowercase());
+        emit(json!({ "time": time, "level": level, "message": message }), || {});
+    } else {
+        println!("{line}");
+    }
+}
+
+/// Prints the last `lines` lines of the log at `path` that pass `filter`,
+/// with `follow` keeps printing new ones as they are written, also across rotations
+pub fn show_logs(path: &str, filter: &LogFilter, lines: usize, follow: bool) -> CliResult {
+    let open = || File::open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => CliError::new(ErrorKind::NotFound, format!("No log at {path}")),
+        _ => CliError::new(ErrorKind::Io, format!("Can't read {path}: {e}")),
+    });
+
+    let mut reader = BufReader::new(open()?);
+    let mut last_level = None;
+    let mut shown = Vec::new();
+    let mut line = String::new();
+    while read_line(&mut reader, &mut line)? {
+        let text = line.trim_end();
+        last_level = line_level(text).or(last_level);
+        if filter.matches(text, last_level) {
+            shown.push((text.to_string(), last_level));
+        }
+        line.clear();
+    }
+    for (text, level) in &shown[shown.len().saturating_sub(lines)..] {
+        print_line(text, *level);
+    }
+    if !follow {
+        return Ok(());
+    }
+
+    let mut inode = reader.get_ref().metadata().map(|m| m.ino()).unwrap_or_default();
+    let mut rotated = false;
+    loop {
+        // `line` keeps a partly written last line until the rest of it arrives
+        if read_line(&mut reader, &mut line)? && line.ends_with('\n') {
+            let text = line.trim_end();
+            last_level = line_level(text).or(last_level);
+            if filter.matches(text, last_level) {
+                print_line(text, last_level);
+            }
+            line.clear();
+            continue;
+        }
+        if rotated {
+            // the old file is read to its end, continue with the new one
+            if let Ok(file) = File::open(path) {
+                inode = file.metadata().map(|m| m.ino()).unwrap_or_default();
+                reader = BufReader::new(file);
+            }
+            line.clear();
+            rotated = false;
+            continue;
+        }
+        std::thread::sleep(Duration::from_millis(LOGS_POLL_MS));
+
+        // the daemon rotated or truncated the log, lines written before that are read first
+        if let Ok(meta) = fs::metadata(path) {
+            let pos = reader.stream_position().unwrap_or_default();
+            rotated = meta.ino() != inode || meta.len() < pos;
+        }
+    }
+}
+
+// false at the end of the file
+fn read_line<R: Read>(reader: &mut BufReader<R>, line: &mut String) -> Result<bool, CliError> {
+    reader.read_line(line)
+        .map(|n| n > 0)
+        .map_err(|e| CliError::new(ErrorKind::Io, format!("Can't read the log: {e}")))
+}
@@ -1,9 +1,24 @@
 pub mod client;
 pub mod server;
+pub mod transfer;
 pub mod protocol;
+pub mod output;
+pub mod logging;
+pub mod systemd;
+pub mod lock;
+pub mod access;
+pub mod audit;
+#[allow(clippy::module_inception)]
 pub mod daemon;
 
 pub use client::*;
 pub use server::*;
+pub use transfer::*;
 pub use protocol::*;
+pub use output::*;
+pub use logging::*;
+pub use systemd::*;
+pub use lock::*;
+pub use access::*;
+pub use audit::*;
 pub use daemon::*;
\ No newline at end of file
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::{json, Value};
+
+use crate::daemon::ErrorKind;
+use crate::network::CodecError;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Switches all command output to JSON, set once from `--json`
+pub fn set_json_output(on: bool) {
+    JSON.store(on, Ordering::Relaxed);
+}
+
+pub fn json_output() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+/// A failed command as reported to the user
+#[derive(Debug)]
+pub struct CliError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+pub type CliResult = Result<(), CliError>;
+
+impl CliError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        CliError { kind, message: message.into() }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for CliError {}
+
+impl From<anyhow::Error> for CliError {
+    fn from(e: anyhow::Error) -> Self {
+        CliError { kind: classify(&e), message: format!("{e:#}") }
+    }
+}
+
+// the first cause that says what kind of failure this is
+fn classify(e: &anyhow::Error) -> ErrorKind {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<CliError>() {
+            return e.kind;
+        }
+        if let Some(e) = cause.downcast_ref::<CodecError>() {
+            return match e {
+                CodecError::Oversize { .. } | CodecError::Protocol(_) => ErrorKind::Protocol,
+                _ => ErrorKind::Network,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return match e.kind() {
+                io::ErrorKind::NotFound => ErrorKind::NotFound,
+                io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable
+                | io::ErrorKind::TimedOut => ErrorKind::Network,
+                _ => ErrorKind::Io,
+            };
+        }
+    }
+
+    // errors reported by a server only arrive as text
+    match e.root_cause().to_string().as_str() {
+        "File not found" | "File unavailable" => ErrorKind::NotFound,
+        "Permission denied" | "Authentication failed" => ErrorKind::PermissionDenied,
+        _ => ErrorKind::Failed,
+    }
+}
+
+/// Prints the result of a successful command: `value` with `--json`, otherwise what `text` prints
+pub fn emit(value: Value, text: impl FnOnce()) {
+    if json_output() {
+        println!("{}", json!({ "ok": true, "data": value }));
+    } else {
+        text();
+    }
+}
+
+/// Prints a failed command and returns the exit code for it
+pub fn report_error(e: &CliError) -> i32 {
+    if json_output() {
+        println!("{}", json!({ "ok": false, "error": { "kind": e.kind, "message": e.message } }));
+    } else {
+        eprintln!("{}", e.message);
+    }
+    e.kind.exit_code()
+}
@@ -1,7 +1,27 @@
-use std::collections::HashMap;
+use std::fmt;
 
 use serde::{Deserialize, Serialize};
-use tokio::sync::oneshot;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::daemon::{AuditQuery, CliError, Owner};
+use crate::network::{
+    ConnectionInfo, SearchHit, SearchQuery, ShareEntry, ShareEvent, SyncConfig, SyncStatus, TransferInfo
+};
+use crate::settings::{CONTROL_PAGE_SIZE, CONTROL_PROTOCOL_VERSION, VERSION};
+
+/// First message on the control socket, sent by both sides before anything else
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ControlHello {
+    pub version: u32,
+    /// package version of the binary, only shown to the user
+    pub build: String,
+}
+
+impl ControlHello {
+    pub fn current() -> Self {
+        ControlHello { version: CONTROL_PROTOCOL_VERSION, build: VERSION.into() }
+    }
+}
 
 // user sends it to daemon
 #[derive(Serialize, Deserialize, Debug)]
@@ -9,18 +29,431 @@ pub enum DaemonCommand {
     Add { path: String, name: Option<String> },
     Delete { name: String },
     List,
+    Rename { name: String, new_name: String },
+    Tag { name: String, tags: Vec<String> },
+    /// `expires` in seconds from now, none for a link that works until it is revoked
+    Link { name: String, host: Option<String>, expires: Option<u64> },
+    Links,
+    /// by the id `Links` shows
+    RevokeLink { id: String },
+    /// records of the daemon's own audit log
+    Audit(AuditQuery),
+    Status,
+    /// keeps the connection open and streams every `DaemonEvent` until the user goes away
+    Subscribe,
+    /// handled by every daemon with a control socket
+    LogLevel { filter: String },
+
+    // client daemon
+    ListRemote,
+    Search(SearchQuery),
+    Download { name: String, by_hash: bool, delta: bool, output: String },
+    Sync(SyncConfig),
+    SyncStatus,
+    SyncStop { id: u64 },
+    Admin(Box<DaemonCommand>),
+}
+
+impl DaemonCommand {
+    // commands an admin client may send over the network
+    pub fn is_remote(&self) -> bool {
+        matches!(
+            self,
+            DaemonCommand::Add { .. }
+                | DaemonCommand::Delete { .. }
+                | DaemonCommand::List
+                | DaemonCommand::Rename { .. }
+                | DaemonCommand::Tag { .. }
+        )
+    }
+
+    // commands any local user reaching the control socket may send, the rest need `ControlAccess`.
+    // Watching isn't one of them, events name peers and shares of other users
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            DaemonCommand::List
+            | DaemonCommand::Status
+            | DaemonCommand::ListRemote
+            | DaemonCommand::Search(_)
+            | DaemonCommand::SyncStatus => true,
+            DaemonCommand::Admin(cmd) => cmd.is_read_only(),
+            _ => false,
+        }
+    }
+}
+
+/// What went wrong, each kind has its own exit code so scripts can tell them apart
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Failed,
+    /// bad arguments or a command this daemon doesn't support
+    Usage,
+    /// no daemon is listening on the control socket
+    NotRunning,
+    /// the other side speaks a different protocol version or sent garbage
+    Protocol,
+    NotFound,
+    PermissionDenied,
+    /// the server could not be reached or the connection broke
+    Network,
+    /// local file system errors
+    Io,
+    /// a daemon of the same kind holds the lock
+    AlreadyRunning,
+}
+
+impl ErrorKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Failed => 1,
+            ErrorKind::Usage => 2,
+            ErrorKind::NotRunning => 3,
+            ErrorKind::Protocol => 4,
+            ErrorKind::NotFound => 5,
+            ErrorKind::PermissionDenied => 6,
+            ErrorKind::Network => 7,
+            ErrorKind::Io => 8,
+            ErrorKind::AlreadyRunning => 9,
+        }
+    }
+}
+
+/// A local share as listed by the server daemon
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShareInfo {
+    pub name: String,
+    pub path: String,
+    /// false while the file is missing from disk
+    pub available: bool,
 }
 
 // response from daemon
 #[derive(Serialize, Deserialize, Debug)]
 pub enum DaemonResponse {
     Ok(String),
-    Err(String),
-    List(HashMap<String, String>),
+    Err(ErrorKind, String),
+    List(Vec<ShareInfo>),
+    Files(Vec<ShareEntry>),
+    Search { total: u64, hits: Vec<SearchHit> },
+    SyncJobs(Vec<SyncStatus>),
+    Status(DaemonStatus),
+    Links(Vec<LinkInfo>),
+    /// audit records as lines of the log, bincode can't carry their flattened event
+    Audit(Vec<String>),
+}
+
+/// A share link that still works, as listed by the server daemon
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkInfo {
+    /// names the link in the audit log and for revoking, the token itself isn't kept
+    pub id: String,
+    /// share the link was made for, it keeps working if the share is renamed
+    pub name: String,
+    /// blake3 of the content the link gives access to
+    pub hash: String,
+    /// unix seconds
+    pub expires: Option<u64>,
+}
+
+/// What a running daemon is doing
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DaemonStatus {
+    pub pid: u32,
+    pub uptime_secs: u64,
+    /// where the server daemon listens, the server the client daemon is connected to
+    pub addresses: Vec<String>,
+    /// of the server's TLS certificate
+    pub fingerprint: Option<String>,
+    /// server daemon only
+    pub shares: Option<usize>,
+    /// client daemon only
+    pub sync_jobs: Option<usize>,
+    pub connections: Vec<ConnectionInfo>,
+    pub transfers: Vec<TransferInfo>,
+    /// file data sent by the server daemon or received by the client daemon
+    pub bytes_transferred: u64,
+}
+
+impl DaemonResponse {
+    /// Error response with the kind the CLI would give `e`
+    pub fn from_error(e: anyhow::Error) -> Self {
+        let e = CliError::from(e);
+        DaemonResponse::Err(e.kind, e.message)
+    }
+
+    /// Puts lists that were streamed in pages back together
+    pub fn merge_pages(responses: Vec<DaemonResponse>) -> Vec<DaemonResponse> {
+        let mut merged: Vec<DaemonResponse> = Vec::with_capacity(responses.len());
+        for resp in responses {
+            match (merged.last_mut(), resp) {
+                (Some(DaemonResponse::List(all)), DaemonResponse::List(page)) => all.extend(page),
+                (Some(DaemonResponse::Files(all)), DaemonResponse::Files(page)) => all.extend(page),
+                (Some(DaemonResponse::Audit(all)), DaemonResponse::Audit(page)) => all.extend(page),
+                (_, resp) => merged.push(resp),
+            }
+        }
+        merged
+    }
+}
+
+/// Progress of a long running command
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Progress {
+    pub label: String,
+    pub done: u64,
+    pub total: Option<u64>,
+}
+
+// daemon -> user, a command gets any number of these followed by End
+#[derive(Serialize, Deserialize, Debug)]
+pub enum DaemonReply {
+    Response(DaemonResponse),
+    Progress(Progress),
+    Event(TimedEvent),
+    End,
+}
+
+/// A DaemonEvent stamped with the time the daemon saw it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimedEvent {
+    /// unix seconds
+    pub time: u64,
+    pub event: DaemonEvent,
+}
+
+/// Something that happened in the server daemon, as streamed to `daemon watch`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DaemonEvent {
+    Connected { peer: String },
+    Disconnected { peer: String },
+    /// `user` is the role the peer got, none if it was refused
+    Auth { peer: String, user: Option<String> },
+    /// `upload` is set for data coming into the inbox
+    TransferStarted { id: u64, peer: String, share: String, done: u64, size: u64, upload: bool },
+    TransferProgress { id: u64, share: String, done: u64, size: u64 },
+    /// `complete` is false if the transfer broke off
+    TransferFinished { id: u64, peer: String, share: String, bytes: u64, duration_ms: u64, complete: bool, upload: bool },
+    Share(ShareEvent),
+    /// the watcher fell behind and this many events were dropped for it
+    Missed { count: u64 },
+}
+
+impl fmt::Display for DaemonEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DaemonEvent::Connected { peer } => write!(f, "{peer} connected"),
+            DaemonEvent::Disconnected { peer } => write!(f, "{peer} disconnected"),
+            DaemonEvent::Auth { peer, user: Some(user) } => write!(f, "{peer} authenticated as {user}"),
+            DaemonEvent::Auth { peer, user: None } => write!(f, "{peer} failed to authenticate"),
+            DaemonEvent::TransferStarted { id, peer, share, done, size, upload } => {
+                let to = if *upload { "from" } else { "to" };
+                write!(f, "#{id} '{share}' {to} {peer} started at {done}/{size} bytes")
+            }
+            DaemonEvent::TransferProgress { id, share, done, size } => {
+                let percent = if *size > 0 { done * 100 / size } else { 100 };
+                write!(f, "#{id} '{share}' {done}/{size} bytes ({percent}%)")
+            }
+            DaemonEvent::TransferFinished { id, peer, share, bytes, duration_ms, complete, upload } => {
+                let how = if *complete { "finished" } else { "broke off" };
+                let secs = *duration_ms as f64 / 1000.0;
+                let (to, sent) = if *upload { ("from", "received") } else { ("to", "sent") };
+                write!(f, "#{id} '{share}' {to} {peer} {how}, {bytes} bytes {sent} in {secs:.1}s")
+            }
+            DaemonEvent::Share(event) => write!(f, "Share {event}"),
+            DaemonEvent::Missed { count } => write!(f, "{count} events missed, the watcher fell behind"),
+        }
+    }
+}
+
+// what a command handler passes on to the control connection serving the command
+pub enum HandlerReply {
+    Reply(DaemonReply),
+    /// stream these until the user hangs up. They are read off the broadcast channel by the
+    /// connection itself, so a slow watcher holds a bounded backlog and is told what it missed
+    Events(broadcast::Receiver<TimedEvent>),
+}
+
+/// Where the handler of a command sends its replies.
+/// The reply stream ends once every clone of it is dropped
+#[derive(Clone)]
+pub struct Responder(mpsc::UnboundedSender<HandlerReply>);
+
+impl Responder {
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<HandlerReply>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Responder(tx), rx)
+    }
+
+    /// False if the user went away
+    pub fn send(&self, resp: DaemonResponse) -> bool {
+        self.0.send(HandlerReply::Reply(DaemonReply::Response(resp))).is_ok()
+    }
+
+    pub fn progress(&self, label: &str, done: u64, total: Option<u64>) -> bool {
+        let progress = Progress { label: label.to_string(), done, total };
+        self.0.send(HandlerReply::Reply(DaemonReply::Progress(progress))).is_ok()
+    }
+
+    /// Turns the reply stream into `events`, the handler is done with the command after this
+    pub fn subscribe(&self, events: broadcast::Receiver<TimedEvent>) -> bool {
+        self.0.send(HandlerReply::Events(events)).is_ok()
+    }
+
+    /// Sends `items` in pages of CONTROL_PAGE_SIZE so no frame hits the size limit.
+    /// The last page is returned instead of sent, to go out like any other response
+    pub fn pages<T>(&self, mut items: Vec<T>, wrap: impl Fn(Vec<T>) -> DaemonResponse) -> DaemonResponse {
+        while items.len() > CONTROL_PAGE_SIZE {
+            let rest = items.split_off(CONTROL_PAGE_SIZE);
+            self.send(wrap(items));
+            items = rest;
+        }
+        wrap(items)
+    }
 }
 
-// oneshot message from daemon to server
+// command from the control socket to the daemon's handler
 pub struct DaemonMessage {
     pub cmd: DaemonCommand,
-    pub resp_tx: oneshot::Sender<DaemonResponse>,
+    pub resp_tx: Responder,
+    /// local user whose access shares added by the command are limited to
+    pub owner: Option<Owner>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::network::{AuditOutcome, Codec, NameMatch};
+
+    // a variant added to any of these fails to compile here, give it a sample in `samples` too
+    const _: fn(&DaemonCommand) = |cmd| match cmd {
+        DaemonCommand::Add { .. } | DaemonCommand::Delete { .. } | DaemonCommand::List | DaemonCommand::Rename { .. }
+        | DaemonCommand::Tag { .. } | DaemonCommand::Link { .. } | DaemonCommand::Links | DaemonCommand::RevokeLink { .. }
+        | DaemonCommand::Audit(_) | DaemonCommand::Status | DaemonCommand::Subscribe
+        | DaemonCommand::LogLevel { .. } | DaemonCommand::ListRemote | DaemonCommand::Search(_)
+        | DaemonCommand::Download { .. } | DaemonCommand::Sync(_) | DaemonCommand::SyncStatus
+        | DaemonCommand::SyncStop { .. } | DaemonCommand::Admin(_) => {}
+    };
+    const _: fn(&DaemonResponse) = |resp| match resp {
+        DaemonResponse::Ok(_) | DaemonResponse::Err(..) | DaemonResponse::List(_) | DaemonResponse::Files(_)
+        | DaemonResponse::Search { .. } | DaemonResponse::SyncJobs(_) | DaemonResponse::Status(_)
+        | DaemonResponse::Links(_) | DaemonResponse::Audit(_) => {}
+    };
+    const _: fn(&DaemonReply) = |reply| match reply {
+        DaemonReply::Response(_) | DaemonReply::Progress(_) | DaemonReply::Event(_) | DaemonReply::End => {}
+    };
+    const _: fn(&DaemonEvent) = |event| match event {
+        DaemonEvent::Connected { .. } | DaemonEvent::Disconnected { .. } | DaemonEvent::Auth { .. }
+        | DaemonEvent::TransferStarted { .. } | DaemonEvent::TransferProgress { .. }
+        | DaemonEvent::TransferFinished { .. } | DaemonEvent::Share(_) | DaemonEvent::Missed { .. } => {}
+    };
+    const _: fn(&ShareEvent) = |event| match event {
+        ShareEvent::Added { .. } | ShareEvent::Removed { .. } | ShareEvent::Renamed { .. } | ShareEvent::Modified { .. }
+        | ShareEvent::Moved { .. } | ShareEvent::Unavailable { .. } | ShareEvent::Available { .. } => {}
+    };
+
+    // one value of every variant of the control messages, encoded as they go over the socket
+    async fn samples() -> Vec<u8> {
+        let s = || "s".to_string();
+        let event = |event| DaemonReply::Event(TimedEvent { time: 1, event });
+        let query = SearchQuery { name: Some(NameMatch::Glob(s())), tags: vec![s()], file_type: Some(s()), ..Default::default() };
+        let sync = SyncConfig { server: s(), fingerprint: s(), password: Some(s()), prefix: s(), dir: PathBuf::from("d"), delete: true, interval: 1 };
+
+        let commands = vec![
+            DaemonCommand::Add { path: s(), name: Some(s()) },
+            DaemonCommand::Delete { name: s() },
+            DaemonCommand::List,
+            DaemonCommand::Rename { name: s(), new_name: s() },
+            DaemonCommand::Tag { name: s(), tags: vec![s()] },
+            DaemonCommand::Link { name: s(), host: Some(s()), expires: Some(1) },
+            DaemonCommand::Links,
+            DaemonCommand::RevokeLink { id: s() },
+            DaemonCommand::Audit(AuditQuery {
+                since: Some(1), until: Some(1), user: Some(s()), token: Some(s()), share: Some(s()), outcome: Some(AuditOutcome::Denied),
+            }),
+            DaemonCommand::Status,
+            DaemonCommand::Subscribe,
+            DaemonCommand::LogLevel { filter: s() },
+            DaemonCommand::ListRemote,
+            DaemonCommand::Search(query),
+            DaemonCommand::Download { name: s(), by_hash: true, delta: true, output: s() },
+            DaemonCommand::Sync(sync),
+            DaemonCommand::SyncStatus,
+            DaemonCommand::SyncStop { id: 1 },
+            DaemonCommand::Admin(Box::new(DaemonCommand::List)),
+        ];
+        let status = DaemonStatus {
+            pid: 1,
+            uptime_secs: 1,
+            addresses: vec![s()],
+            fingerprint: Some(s()),
+            shares: Some(1),
+            sync_jobs: Some(1),
+            connections: vec![ConnectionInfo { peer: s(), since: 1 }],
+            transfers: vec![TransferInfo { peer: s(), name: s(), done: 1, size: 1 }],
+            bytes_transferred: 1,
+        };
+        let sync_status = SyncStatus {
+            id: 1, server: s(), prefix: s(), dir: PathBuf::from("d"), state: s(), changes: 1, last_sync: Some(1), last_error: Some(s()),
+        };
+        let replies = vec![
+            DaemonReply::Response(DaemonResponse::Ok(s())),
+            DaemonReply::Response(DaemonResponse::Err(ErrorKind::AlreadyRunning, s())),
+            DaemonReply::Response(DaemonResponse::List(vec![ShareInfo { name: s(), path: s(), available: true }])),
+            DaemonReply::Response(DaemonResponse::Files(vec![ShareEntry { name: s(), hash: Some(s()), available: true }])),
+            DaemonReply::Response(DaemonResponse::Search {
+                total: 1,
+                hits: vec![SearchHit { name: s(), size: 1, modified: 1, tags: vec![s()] }],
+            }),
+            DaemonReply::Response(DaemonResponse::SyncJobs(vec![sync_status])),
+            DaemonReply::Response(DaemonResponse::Status(status)),
+            DaemonReply::Response(DaemonResponse::Links(vec![LinkInfo { id: s(), name: s(), hash: s(), expires: Some(1) }])),
+            DaemonReply::Response(DaemonResponse::Audit(vec![s()])),
+            DaemonReply::Progress(Progress { label: s(), done: 1, total: Some(1) }),
+            event(DaemonEvent::Connected { peer: s() }),
+            event(DaemonEvent::Disconnected { peer: s() }),
+            event(DaemonEvent::Auth { peer: s(), user: Some(s()) }),
+            event(DaemonEvent::TransferStarted { id: 1, peer: s(), share: s(), done: 1, size: 1, upload: true }),
+            event(DaemonEvent::TransferProgress { id: 1, share: s(), done: 1, size: 1 }),
+            event(DaemonEvent::TransferFinished {
+                id: 1, peer: s(), share: s(), bytes: 1, duration_ms: 1, complete: true, upload: true,
+            }),
+            event(DaemonEvent::Share(ShareEvent::Added { name: s() })),
+            event(DaemonEvent::Share(ShareEvent::Removed { name: s() })),
+            event(DaemonEvent::Share(ShareEvent::Renamed { name: s(), new_name: s() })),
+            event(DaemonEvent::Share(ShareEvent::Modified { name: s() })),
+            event(DaemonEvent::Share(ShareEvent::Moved { name: s(), path: PathBuf::from("p") })),
+            event(DaemonEvent::Share(ShareEvent::Unavailable { name: s() })),
+            event(DaemonEvent::Share(ShareEvent::Available { name: s() })),
+            event(DaemonEvent::Missed { count: 1 }),
+            DaemonReply::End,
+        ];
+
+        let codec = Codec::default();
+        let mut wire = Vec::new();
+        codec.send(&mut wire, &ControlHello { version: 0, build: s() }).await.unwrap();
+        for cmd in &commands {
+            codec.send(&mut wire, cmd).await.unwrap();
+        }
+        for reply in &replies {
+            codec.send(&mut wire, reply).await.unwrap();
+        }
+        wire
+    }
+
+    // daemons of another build refuse anything but their own version, so the encoding of a
+    // version has to stay fixed. Changing it means bumping CONTROL_PROTOCOL_VERSION and putting
+    // the new version here with the hash this test prints
+    #[tokio::test]
+    async fn encoding_changes_bump_the_version() {
+        const ENCODING: (u32, &str) = (7, "e0b30a1727c9c255caa356d4255bb47c4ef23eecc2bf79ea0d525c38a4d6dc45");
+
+        let hash = blake3::hash(&samples().await).to_hex().to_string();
+        assert_eq!(
+            (CONTROL_PROTOCOL_VERSION, hash.as_str()), ENCODING,
+            "the control protocol encoding changed, bump CONTROL_PROTOCOL_VERSION and update ENCODING",
+        );
+    }
 }
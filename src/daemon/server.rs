@@ -1,109 +1,180 @@
-use std::fs;
-use std::path::Path;
+use std::path::PathBuf;
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::{mpsc, oneshot};
-use tokio::net::UnixListener;
+use anyhow::Context;
+use tokio::signal::unix::{signal, SignalKind};
 
-use crate::daemon::{DaemonCommand, DaemonMessage, DaemonResponse};
-use super::{handle_daemon_message, start_daemon, send_command, stop_daemon, handle_response};
-use crate::network::Server;
+use crate::daemon::{
+    notify_ready, show_logs, take_tcp_listener, AuditQuery, CliError, CliResult, ControlAccess, DaemonCommand,
+    ErrorKind, LogFilter, Owner
+};
+use super::{
+    block_on, handle_daemon_message, reload_daemon, reload_on_hangup, restart_daemon, start_daemon, start_listener,
+    send_command, stop_daemon, handle_response, watch_daemon
+};
+use crate::network::{run_relay, Codec, DiscoveryConfig, Inbox, Server, Timeouts};
+use crate::utils::{hostname, state_path};
 use crate::settings::{
     SERVER_DAEMON_SOCKET_PATH, SERVER_DAEMON_ERR_PATH,
-    SERVER_DAEMON_OUT_PATH, SERVER_DAEMON_PID_PATH, ServerCliCommand
+    SERVER_DAEMON_LOG_PATH, SERVER_DAEMON_PID_PATH, SERVER_SHARES_FILE, SERVER_AUDIT_FILE, RELAY_DAEMON_LOG_PATH,
+    RELAY_DAEMON_ERR_PATH, RELAY_DAEMON_PID_PATH, RelayCliCommand, ServerCliCommand
 };
 
-pub async fn handle_server_command(command: ServerCliCommand) {
+pub fn handle_server_command(command: ServerCliCommand) -> CliResult {
     match command {
-        ServerCliCommand::Start { port, password } => {
-            start_daemon(move |mut rx| async move {
-                let server = Server::new(password);
-                if let Err(err) = server.run(port).await {
-                    eprintln!("Error while starting server: {err}");
+        ServerCliCommand::Start {
+            port, foreground, password, max_message_size,
+            handshake_timeout, auth_timeout, idle_timeout, ack_timeout, keepalive,
+            inbox, inbox_quota, on_collision, auto_share, upload_password, admin_password, admin_root,
+            announce, mdns, name, discovery_group, discovery_interface, relay, session,
+            control_users, control_groups, metrics_port
+        } => {
+            let name = name.unwrap_or_else(hostname);
+
+            // the daemon changes its working directory to /
+            let inbox = inbox.map(|dir| Inbox::new(
+                std::path::absolute(&dir).unwrap_or_else(|_| PathBuf::from(dir)),
+                inbox_quota,
+                on_collision,
+                auto_share,
+            ));
+
+            // a root daemon would read anything a remote admin names, so they are kept to this directory
+            let admin_root = match admin_root {
+                Some(root) => {
+                    let invalid = |e: &dyn std::fmt::Display| CliError::new(ErrorKind::Usage, format!("Can't use {root} as the admin root: {e}"));
+                    let canonical = std::fs::canonicalize(&root).map_err(|e| invalid(&e))?;
+                    let meta = std::fs::metadata(&canonical).map_err(|e| invalid(&e))?;
+                    if !meta.is_dir() {
+                        return Err(invalid(&"not a directory"));
+                    }
+                    Some((canonical, Owner::of_file(&meta)))
+                }
+                None => None,
+            };
+
+            let access = ControlAccess { uids: control_users, gids: control_groups };
+            let state = |file| state_path(file)
+                .map_err(|e| CliError::new(ErrorKind::Io, format!("Can't set up the state directory: {e}")));
+            let shares_path = state(SERVER_SHARES_FILE)?;
+            let audit_path = state(SERVER_AUDIT_FILE)?;
+
+            start_daemon(move |tx, rx| async move {
+                let listener = match take_tcp_listener() {
+                    Some(listener) => listener,
+                    None => std::net::TcpListener::bind(("0.0.0.0", port))?,
+                };
+                let codec = Codec::default().with_max_message_size(max_message_size);
+                let timeouts = Timeouts::from_secs(
+                    handshake_timeout, auth_timeout, idle_timeout, ack_timeout, keepalive
+                );
+                let mut server = Server::new(password)
+                    .with_port(listener.local_addr()?.port())
+                    .with_listener(listener)
+                    .with_codec(codec)
+                    .with_timeouts(timeouts)
+                    .with_registry(shares_path)
+                    .with_audit(audit_path);
+                if let Some(inbox) = inbox {
+                    server = server.with_inbox(inbox, upload_password);
+                }
+                if let Some(admin_password) = admin_password {
+                    server = server.with_admin(admin_password, tx);
+                }
+                if let Some((root, owner)) = admin_root {
+                    server = server.with_admin_root(root, owner);
+                }
+                if announce {
+                    let config = DiscoveryConfig { group: discovery_group, interface: discovery_interface };
+                    server = server.with_announce(config, name.clone());
+                }
+                if mdns {
+                    server = server.with_mdns(name);
+                }
+                if let (Some(relay), Some(session)) = (relay, session) {
+                    server = server.with_relay(relay, session);
+                }
+                if let Some(port) = metrics_port {
+                    server = server.with_metrics(port);
                 }
 
-                handle_daemon_message(rx, server).await;
-            }, start_server_listener, SERVER_DAEMON_OUT_PATH, SERVER_DAEMON_ERR_PATH, SERVER_DAEMON_PID_PATH);
+                // installed before systemd hears we are up, so an early SIGHUP doesn't kill us
+                let hangup = signal(SignalKind::hangup())?;
+                notify_ready();
+                tokio::select! {
+                    res = server.run() => res.context("Error while running server"),
+                    _ = handle_daemon_message(rx, &server) => Ok(()),
+                    _ = reload_on_hangup(&server, hangup) => Ok(()),
+                }
+            }, |tx| start_listener(SERVER_DAEMON_SOCKET_PATH, access, tx),
+            foreground, SERVER_DAEMON_LOG_PATH, SERVER_DAEMON_ERR_PATH, SERVER_DAEMON_PID_PATH)
         }
         ServerCliCommand::Stop => {
-            stop_daemon(SERVER_DAEMON_PID_PATH);
+            stop_daemon(SERVER_DAEMON_PID_PATH)
+        }
+        ServerCliCommand::Restart => {
+            restart_daemon(SERVER_DAEMON_PID_PATH)
+        }
+        ServerCliCommand::Reload => {
+            reload_daemon(SERVER_DAEMON_PID_PATH)
         }
         ServerCliCommand::Add { path, name } => {
-            handle_response(send_command(DaemonCommand::Add { path, name }, SERVER_DAEMON_SOCKET_PATH).await);
+            handle_response(block_on(send_command(DaemonCommand::Add { path, name }, SERVER_DAEMON_SOCKET_PATH)))
         }
         ServerCliCommand::Delete { name } => {
-            handle_response(send_command(DaemonCommand::Delete { name }, SERVER_DAEMON_SOCKET_PATH).await);
+            handle_response(block_on(send_command(DaemonCommand::Delete { name }, SERVER_DAEMON_SOCKET_PATH)))
         }
         ServerCliCommand::List => {
-            handle_response(send_command(DaemonCommand::List, SERVER_DAEMON_SOCKET_PATH).await);
+            handle_response(block_on(send_command(DaemonCommand::List, SERVER_DAEMON_SOCKET_PATH)))
         }
-    }
-}
-
-async fn start_server_listener(tx: mpsc::Sender<DaemonMessage>) {
-    if Path::new(SERVER_DAEMON_SOCKET_PATH).exists() {
-        let _ = fs::remove_file(SERVER_DAEMON_SOCKET_PATH);
-    }
-
-    let listener = match UnixListener::bind(SERVER_DAEMON_SOCKET_PATH) {
-        Ok(l) => l,
-        Err(e) => {
-            eprintln!("Failed to bind Unix socket: {e}");
-            return;
+        ServerCliCommand::Rename { name, new_name } => {
+            handle_response(block_on(send_command(DaemonCommand::Rename { name, new_name }, SERVER_DAEMON_SOCKET_PATH)))
+        }
+        ServerCliCommand::Tag { name, tags } => {
+            handle_response(block_on(send_command(DaemonCommand::Tag { name, tags }, SERVER_DAEMON_SOCKET_PATH)))
+        }
+        ServerCliCommand::Status => {
+            handle_response(block_on(send_command(DaemonCommand::Status, SERVER_DAEMON_SOCKET_PATH)))
+        }
+        ServerCliCommand::Watch => {
+            watch_daemon(SERVER_DAEMON_SOCKET_PATH)
+        }
+        ServerCliCommand::Logs { follow, lines, level, grep } => {
+            show_logs(SERVER_DAEMON_LOG_PATH, &LogFilter { level, grep }, lines, follow)
+        }
+        ServerCliCommand::Audit { since, until, user, token, share, outcome } => {
+            // the daemon reads its own log, it may keep its state under another user
+            let cmd = DaemonCommand::Audit(AuditQuery { since, until, user, token, share, outcome });
+            handle_response(block_on(send_command(cmd, SERVER_DAEMON_SOCKET_PATH)))
+        }
+        ServerCliCommand::LogLevel { filter } => {
+            handle_response(block_on(send_command(DaemonCommand::LogLevel { filter }, SERVER_DAEMON_SOCKET_PATH)))
+        }
+        ServerCliCommand::Link { name, host, expires } => {
+            let cmd = DaemonCommand::Link { name, host, expires: (expires > 0).then_some(expires) };
+            handle_response(block_on(send_command(cmd, SERVER_DAEMON_SOCKET_PATH)))
+        }
+        ServerCliCommand::Links => {
+            handle_response(block_on(send_command(DaemonCommand::Links, SERVER_DAEMON_SOCKET_PATH)))
+        }
+        ServerCliCommand::RevokeLink { id } => {
+            handle_response(block_on(send_command(DaemonCommand::RevokeLink { id }, SERVER_DAEMON_SOCKET_PATH)))
+        }
+        ServerCliCommand::Relay { command: RelayCliCommand::Start { port, foreground } } => {
+            // the relay takes no commands, so it has no control socket
+            start_daemon(move |_tx, _rx| async move {
+                let listener = match take_tcp_listener() {
+                    Some(listener) => listener,
+                    None => std::net::TcpListener::bind(("0.0.0.0", port))?,
+                };
+                listener.set_nonblocking(true)?;
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                notify_ready();
+                run_relay(listener).await.context("Error while running relay")
+            }, |_tx| async {},
+            foreground, RELAY_DAEMON_LOG_PATH, RELAY_DAEMON_ERR_PATH, RELAY_DAEMON_PID_PATH)
+        }
+        ServerCliCommand::Relay { command: RelayCliCommand::Stop } => {
+            stop_daemon(RELAY_DAEMON_PID_PATH)
         }
-    };
-
-    loop {
-        let (mut socket, _) = match listener.accept().await {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("Failed to accept connection: {e}");
-                continue;
-            }
-        };
-        let tx = tx.clone();
-
-        tokio::spawn(async move {
-            let mut buf = Vec::new();
-            match socket.read_to_end(&mut buf).await {
-                Ok(n) if n > 0 => {
-                    match bincode::deserialize::<DaemonCommand>(&buf) {
-                        Ok(cmd) => {
-                            let (resp_tx, resp_rx) = oneshot::channel();
-                            let msg = DaemonMessage { cmd, resp_tx };
-
-                            if tx.send(msg).await.is_err() {
-                                let _ = socket.write_all(
-                                    &bincode::serialize(&DaemonResponse::Err("Daemon not running".into())).unwrap()
-                                ).await;
-                                return;
-                            }
-
-                            match resp_rx.await {
-                                Ok(resp) => {
-                                    if let Err(e) = socket.write_all(&bincode::serialize(&resp).unwrap()).await {
-                                        eprintln!("Failed to write response: {e}");
-                                    }
-                                }
-                                Err(_) => {
-                                    let _ = socket.write_all(
-                                        &bincode::serialize(&DaemonResponse::Err("Daemon failed to respond".into())).unwrap()
-                                    ).await;
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            let resp = DaemonResponse::Err(format!("Invalid command: {e}"));
-                            if let Err(e) = socket.write_all(&bincode::serialize(&resp).unwrap()).await {
-                                eprintln!("Failed to write error response: {e}");
-                            }
-                        }
-                    }
-                }
-                Ok(_) => {}
-                Err(e) => eprintln!("Failed to read from socket: {e}"),
-            }
-        });
     }
 }
-
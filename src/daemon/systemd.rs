@@ -0,0 +1,125 @@
+use std::mem;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use sd_notify::NotifyState;
+use tracing::warn;
+
+// sockets passed in by systemd, taken by whatever would otherwise bind them
+static TCP_LISTENER: Mutex<Option<TcpListener>> = Mutex::new(None);
+static CONTROL_LISTENER: Mutex<Option<UnixListener>> = Mutex::new(None);
+// loops the watchdog vouches for, each one has to beat between two pings
+static HEARTBEATS: Mutex<Vec<Weak<AtomicBool>>> = Mutex::new(Vec::new());
+
+/// Takes over the sockets passed through socket activation (`LISTEN_FDS`).
+/// The TCP one is the port clients connect to, the Unix one the control socket
+pub fn receive_sockets() {
+    let fds = match sd_notify::listen_fds() {
+        Ok(fds) => fds,
+        Err(e) => {
+            warn!("Ignoring passed sockets: {e}");
+            return;
+        }
+    };
+
+    for fd in fds {
+        match socket_family(fd) {
+            Some(libc::AF_UNIX) => {
+                *CONTROL_LISTENER.lock().unwrap() = Some(unsafe { UnixListener::from_raw_fd(fd) });
+            }
+            Some(libc::AF_INET | libc::AF_INET6) => {
+                *TCP_LISTENER.lock().unwrap() = Some(unsafe { TcpListener::from_raw_fd(fd) });
+            }
+            _ => warn!("Ignoring passed file descriptor {fd}, it is not a listening socket"),
+        }
+    }
+}
+
+fn socket_family(fd: RawFd) -> Option<i32> {
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let res = unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+    (res == 0).then_some(addr.ss_family as i32)
+}
+
+pub fn take_tcp_listener() -> Option<TcpListener> {
+    TCP_LISTENER.lock().unwrap().take()
+}
+
+pub fn take_control_listener() -> Option<UnixListener> {
+    CONTROL_LISTENER.lock().unwrap().take()
+}
+
+/// Tells systemd the daemon accepts connections, does nothing outside of a unit
+pub fn notify_ready() {
+    if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        warn!("Failed to notify systemd: {e}");
+    }
+}
+
+// watchdog interval the unit asks for, none outside of a unit or without WatchdogSec
+fn watchdog_usec() -> Option<u64> {
+    let mut usec = 0;
+    sd_notify::watchdog_enabled(false, &mut usec).then_some(usec)
+}
+
+/// Pings the systemd watchdog at half the interval the unit asks for,
+/// as long as every loop holding a `Heartbeat` got around since the last ping
+pub fn spawn_watchdog() {
+    let Some(usec) = watchdog_usec() else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    tokio::spawn(async move {
+        loop {
+            interval.tick().await;
+            let stuck = {
+                let mut beats = HEARTBEATS.lock().unwrap();
+                // a loop that ended dropped its heartbeat and no longer counts
+                beats.retain(|beat| beat.strong_count() > 0);
+                // every beat is reset, also after a stuck loop was found
+                beats.iter().filter_map(Weak::upgrade).filter(|beat| !beat.swap(false, Ordering::Relaxed)).count()
+            };
+            if stuck == 0 {
+                let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+            } else {
+                warn!("Skipping the watchdog ping, {stuck} loops are stuck");
+            }
+        }
+    });
+}
+
+/// Lets a loop show the watchdog that it keeps going. The loop selects on `tick` next to
+/// its work, so a beat only gets through while it isn't stuck in one step
+pub struct Heartbeat {
+    beat: Arc<AtomicBool>,
+    interval: Option<tokio::time::Interval>,
+}
+
+impl Heartbeat {
+    pub fn register() -> Self {
+        let beat = Arc::new(AtomicBool::new(true));
+        // twice per ping, so a loop that keeps coming back to its select gets a beat into every ping
+        let interval = watchdog_usec().map(|usec| {
+            HEARTBEATS.lock().unwrap().push(Arc::downgrade(&beat));
+            tokio::time::interval(Duration::from_micros(usec / 4))
+        });
+        Heartbeat { beat, interval }
+    }
+
+    /// Resolves every so often and counts as a beat, never without a watchdog
+    pub async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+                self.beat.store(true, Ordering::Relaxed);
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde_json::json;
+
+use super::block_on;
+use crate::daemon::{emit, CliResult};
+use crate::network::{generate_code, receive_file, send_file, DiscoveryConfig, Rendezvous};
+use crate::settings::TransferCliCommand;
+
+// send and receive run in the foreground, there is no daemon involved
+pub fn handle_transfer_command(command: TransferCliCommand) -> CliResult {
+    match command {
+        TransferCliCommand::Send { path, code, relay, discovery_group, discovery_interface } => {
+            let rendezvous = match relay {
+                Some(relay) => Rendezvous::Relay(relay),
+                None => Rendezvous::Lan(DiscoveryConfig { group: discovery_group, interface: discovery_interface }),
+            };
+            let code = code.unwrap_or_else(generate_code);
+            emit(json!({ "code": code }), || println!("On the other machine run: file_share receive {code}"));
+
+            block_on(send_file(Path::new(&path), &code, rendezvous)).context("Send failed")?;
+            emit(json!({ "message": "Transfer finished" }), || println!("Transfer finished"));
+            Ok(())
+        }
+        TransferCliCommand::Receive { code, output, relay, wait, discovery_group, discovery_interface } => {
+            let rendezvous = match relay {
+                Some(relay) => Rendezvous::Relay(relay),
+                None => Rendezvous::Lan(DiscoveryConfig { group: discovery_group, interface: discovery_interface }),
+            };
+            let output = output.unwrap_or_else(|| ".".into());
+
+            let (path, size) = block_on(receive_file(&code, rendezvous, Path::new(&output), Duration::from_secs(wait)))
+                .context("Receive failed")?;
+            emit(json!({ "size": size, "path": path }), || println!("Received {size} bytes to {}", path.display()));
+            Ok(())
+        }
+    }
+}
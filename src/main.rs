@@ -6,14 +6,19 @@ mod utils;
 use clap::Parser;
 
 use settings::cli::{Cli, Command};
-use daemon::{handle_client_command, handle_server_command};
+use daemon::{handle_client_command, handle_server_command, handle_transfer_command, init_logging, report_error, set_json_output};
 
-#[tokio::main]
-async fn main() {
+fn main() {
     let cli = Cli::parse();
+    set_json_output(cli.json);
+    init_logging();
 
-    match cli.command {
-        Command::Daemon { command } => handle_server_command(command).await,
-        Command::Client { command } => handle_client_command(command).await,
+    let res = match cli.command {
+        Command::Daemon { command } => handle_server_command(command),
+        Command::Client { command } => handle_client_command(command),
+        Command::Transfer(command) => handle_transfer_command(command),
+    };
+    if let Err(e) = res {
+        std::process::exit(report_error(&e));
     }
 }
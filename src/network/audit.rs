@@ -0,0 +1,100 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::utils::{open_private, unix_now};
+
+/// One line of the audit log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditRecord {
+    /// unix seconds
+    pub time: u64,
+    pub peer: String,
+    /// role the peer authenticated as, none before that
+    pub user: Option<String>,
+    /// id of the link token the peer authenticated with, tells link users apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+    pub outcome: AuditOutcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    Auth,
+    /// a download, a resumed one starts at `offset`. `bytes` is what went over the wire
+    Transfer { share: String, hash: String, offset: u64, bytes: u64, duration_ms: u64 },
+    /// an upload into the inbox under `share`, resumed at `offset`
+    Upload { share: String, hash: String, offset: u64, bytes: u64, duration_ms: u64 },
+    /// a share management command of a remote admin
+    Admin { command: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Ok,
+    /// wrong credentials or a command the role may not send
+    Denied,
+    /// broke off or failed on the server
+    Failed,
+}
+
+/// Who a record is about
+#[derive(Debug, Clone, Default)]
+pub struct AuditIdentity {
+    pub peer: String,
+    pub user: Option<&'static str>,
+    pub token: Option<String>,
+}
+
+/// Append-only JSON lines file recording who got what
+pub struct AuditLog {
+    path: PathBuf,
+    // keeps lines of concurrent connections whole
+    write: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        AuditLog { path: path.into(), write: Mutex::new(()) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(&self, who: &AuditIdentity, event: AuditEvent, outcome: AuditOutcome, error: Option<String>) {
+        let record = AuditRecord {
+            time: unix_now(),
+            peer: who.peer.clone(),
+            user: who.user.map(str::to_string),
+            token: who.token.clone(),
+            event,
+            outcome,
+            error,
+        };
+        if let Err(e) = self.append(&record) {
+            warn!("Failed to write the audit log {}: {e}", self.path.display());
+        }
+    }
+
+    fn append(&self, record: &AuditRecord) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let _write = self.write.lock().unwrap();
+        // opened for appending only, never truncated or rewritten, and never through a symlink
+        let mut file = open_private(&self.path, OpenOptions::new().create(true).append(true).mode(0o600))?;
+        file.write_all(&line)
+    }
+}
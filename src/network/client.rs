@@ -0,0 +1,417 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tracing::warn;
+
+use crate::daemon::{DaemonCommand, DaemonResponse};
+use crate::utils::{compress_chunk, decompress_chunk, hash_file};
+use crate::network::{
+    block_signatures, create_pinned_tls_connector, create_tls_connector, fingerprint, relay_connect, Codec, DeltaOp, KnownHosts,
+    Request, Response, SearchHit, SearchQuery, ShareEntry, ShareEvent, ShareUri
+};
+use crate::settings::{DELTA_BLOCK_SIZE, MAX_DELTA_BLOCKS, MAX_DELTA_BLOCK_SIZE};
+
+/// Called with bytes done and the total while a download runs
+pub type ProgressFn = Box<dyn FnMut(u64, u64) + Send>;
+
+/// An authenticated connection to a file server
+pub struct Client {
+    stream: TlsStream<TcpStream>,
+    codec: Codec,
+    progress: Option<ProgressFn>,
+}
+
+impl Client {
+    /// Connects to `addr`. With a `fingerprint` only that server certificate is accepted.
+    /// Without one the certificate is pinned on first use: a server listed in the user's
+    /// known hosts has to present the same one as before, a new one is added there
+    pub async fn connect(addr: &str, password: Option<String>, fingerprint: Option<&str>) -> anyhow::Result<Self> {
+        let known = KnownHosts::of_user().context("can't open the known hosts")?;
+        let remembered = match fingerprint {
+            Some(_) => None,
+            None => known.get(addr).with_context(|| format!("can't read {}", known.path.display()))?,
+        };
+        let (socket, server_name) = Client::dial(addr).await?;
+
+        let stream = match fingerprint.or(remembered.as_deref()) {
+            Some(fp) => create_pinned_tls_connector(fp).connect(server_name, socket).await.with_context(|| match &remembered {
+                Some(_) => format!(
+                    "{addr} did not present the certificate it had before, remove its line from {} if it was replaced on purpose",
+                    known.path.display(),
+                ),
+                None => format!("{addr} did not present the certificate with fingerprint {fp}"),
+            })?,
+            None => {
+                // nothing is sent before the certificate is remembered
+                let stream = create_tls_connector().connect(server_name, socket).await?;
+                let fp = stream.get_ref().1.peer_certificates()
+                    .and_then(|certs| certs.first())
+                    .map(crate::network::fingerprint)
+                    .context("server presented no certificate")?;
+                known.add(addr, &fp).with_context(|| format!("can't write {}", known.path.display()))?;
+                warn!("First connection to {addr}, remembering its certificate {fp}");
+                stream
+            }
+        };
+        Client::from_tls(stream, password).await
+    }
+
+    async fn dial(addr: &str) -> anyhow::Result<(TcpStream, ServerName<'static>)> {
+        let socket = TcpStream::connect(addr).await?;
+
+        // host part of "host:port" or "[::1]:port"
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        Ok((socket, ServerName::try_from(host.to_string())?))
+    }
+
+    /// Connects to the server waiting on `session` at `relay`. TLS runs end to end and only
+    /// a server certificate with `fingerprint` is accepted, so the relay can't step in between
+    pub async fn connect_relayed(relay: &str, session: &str, password: Option<String>, fingerprint: &str) -> anyhow::Result<Self> {
+        let socket = relay_connect(relay, session).await?;
+        // the certificate isn't checked against a name, the pin decides
+        let server_name = ServerName::try_from("relay")?;
+        let stream = create_pinned_tls_connector(fingerprint).connect(server_name, socket).await?;
+        Client::from_tls(stream, password).await
+    }
+
+    /// Connects to the server of a share link, only accepting the pinned
+    /// certificate, and authenticates with the link's token
+    pub async fn connect_uri(uri: &ShareUri) -> anyhow::Result<Self> {
+        let socket = TcpStream::connect(uri.addr()).await?;
+        let server_name = ServerName::try_from(uri.host.clone())?;
+        let stream = create_pinned_tls_connector(&uri.fingerprint).connect(server_name, socket).await?;
+
+        let auth = match &uri.token {
+            Some(token) => Request::AuthToken(token.clone()),
+            None => Request::Auth(None),
+        };
+        Client::authenticate(stream, &auth).await
+    }
+
+    /// Authenticates on a TLS stream that is already set up
+    pub async fn from_tls(stream: TlsStream<TcpStream>, password: Option<String>) -> anyhow::Result<Self> {
+        Client::authenticate(stream, &Request::Auth(password)).await
+    }
+
+    async fn authenticate(stream: TlsStream<TcpStream>, auth: &Request) -> anyhow::Result<Self> {
+        let mut client = Client { stream, codec: Codec::default(), progress: None };
+
+        match client.request(auth).await? {
+            Response::AuthOk => Ok(client),
+            Response::AuthErr => bail!("Authentication failed"),
+            other => bail!("Unexpected response: {other:?}"),
+        }
+    }
+
+    pub async fn request(&mut self, req: &Request) -> anyhow::Result<Response> {
+        self.codec.send(&mut self.stream, req).await?;
+        Ok(self.codec.recv(&mut self.stream).await?)
+    }
+
+    /// sha256 of the certificate the server presented
+    pub fn server_fingerprint(&self) -> Option<String> {
+        let (_, conn) = self.stream.get_ref();
+        conn.peer_certificates()?.first().map(fingerprint)
+    }
+
+    /// Reports the progress of the downloads that follow, `None` stops reporting
+    pub fn set_progress(&mut self, progress: Option<ProgressFn>) {
+        self.progress = progress;
+    }
+
+    pub async fn list(&mut self) -> anyhow::Result<Vec<ShareEntry>> {
+        match self.request(&Request::List).await? {
+            Response::List(files) => Ok(files),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        }
+    }
+
+    /// Hands every share event of the server to `on_event` until the connection closes.
+    /// The connection can't be used for anything else afterwards
+    pub async fn subscribe<F: FnMut(ShareEvent)>(mut self, mut on_event: F) -> anyhow::Result<()> {
+        self.codec.send(&mut self.stream, &Request::Subscribe).await?;
+        // events may be far apart
+        let codec = self.codec.with_read_timeout(None);
+        loop {
+            match codec.recv(&mut self.stream).await? {
+                Response::Event(event) => on_event(event),
+                Response::Error(e) => bail!(e),
+                other => bail!("Unexpected response: {other:?}"),
+            }
+        }
+    }
+
+    pub async fn search(&mut self, query: SearchQuery) -> anyhow::Result<(u64, Vec<SearchHit>)> {
+        match self.request(&Request::Search(query)).await? {
+            Response::SearchResults { total, hits } => Ok((total, hits)),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        }
+    }
+
+    /// Chunk hashes of the file with content `hash`, starting at chunk `start`.
+    /// Returns the file size, the server's chunk size and the hashes
+    pub async fn chunk_hashes(&mut self, hash: &str, start: u64, count: u64) -> anyhow::Result<(u64, u64, Vec<[u8; 32]>)> {
+        let req = Request::ChunkHashes { hash: hash.to_string(), start, count };
+        match self.request(&req).await? {
+            Response::ChunkHashes { size, chunk_size, hashes, .. } => Ok((size, chunk_size, hashes)),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        }
+    }
+
+    /// Fetches chunks `[start, start + count)` of the file with content `hash`.
+    /// Every decompressed chunk goes through `on_chunk` before it is acked,
+    /// an error from it aborts the transfer and leaves the connection unusable
+    pub async fn download_range<F>(&mut self, hash: &str, start: u64, count: u64, on_chunk: F) -> anyhow::Result<()>
+    where F: FnMut(u64, &[u8]) -> anyhow::Result<()>
+    {
+        let req = Request::DownloadRange { hash: hash.to_string(), start, count };
+        let chunk_size = match self.request(&req).await? {
+            Response::FileInfo { chunk_size, .. } => chunk_size,
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        };
+        self.recv_chunks(chunk_size, on_chunk).await
+    }
+
+    /// Downloads a whole file, `req` is `Request::Download` or `Request::DownloadByHash`.
+    /// A directory `output` gets the file under the name the server reports.
+    /// Returns the saved path and size after checking the content hash
+    pub async fn download(&mut self, req: &Request, output: &Path) -> anyhow::Result<(PathBuf, u64)> {
+        let (name, size, hash, chunk_size) = match self.request(req).await? {
+            Response::FileInfo { name, size, hash, chunk_size } => (name, size, hash, chunk_size),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        };
+
+        let path = if output.is_dir() {
+            // never let the server pick a path outside `output`
+            let Some(file_name) = Path::new(&name).file_name() else {
+                bail!("Invalid file name '{name}'");
+            };
+            output.join(file_name)
+        } else {
+            output.to_path_buf()
+        };
+
+        let mut file = File::create(&path)?;
+        let mut received = 0;
+        let mut progress = self.progress.take();
+        let res = self.recv_chunks(chunk_size, |_, data| {
+            file.write_all(data)?;
+            received += data.len() as u64;
+            if let Some(progress) = &mut progress {
+                progress(received, size);
+            }
+            Ok(())
+        }).await;
+        self.progress = progress;
+        res?;
+        file.sync_all()?;
+
+        if received != size {
+            bail!("Expected {size} bytes, got {received}");
+        }
+        if !hash_file(&path).await?.eq_ignore_ascii_case(&hash) {
+            bail!("Hash mismatch after download");
+        }
+        Ok((path, size))
+    }
+
+    /// Downloads share `name` into `output`, a file or directory, as a delta against the
+    /// file already there: only data the old copy doesn't have is transferred.
+    /// Returns the saved path, its size and how many bytes were reused from the old copy
+    pub async fn download_delta(&mut self, name: &str, output: &Path) -> anyhow::Result<(PathBuf, u64, u64)> {
+        let path = if output.is_dir() {
+            let Some(file_name) = Path::new(name).file_name() else {
+                bail!("Invalid file name '{name}'");
+            };
+            output.join(file_name)
+        } else {
+            output.to_path_buf()
+        };
+
+        // without an old copy everything simply comes as literal data
+        let old = File::open(&path).ok();
+        let old_len = old.as_ref().and_then(|f| f.metadata().ok()).map_or(0, |m| m.len());
+        let block_size = old_len.div_ceil(MAX_DELTA_BLOCKS).clamp(DELTA_BLOCK_SIZE, MAX_DELTA_BLOCK_SIZE);
+        let mut signatures = match &old {
+            Some(file) => {
+                let file = file.try_clone()?;
+                tokio::task::spawn_blocking(move || block_signatures(file, block_size as usize)).await??
+            }
+            None => Vec::new(),
+        };
+        signatures.truncate(MAX_DELTA_BLOCKS as usize);
+
+        let req = Request::DownloadDelta { name: name.to_string(), block_size, signatures };
+        let (size, hash, chunk_size) = match self.request(&req).await? {
+            Response::FileInfo { size, hash, chunk_size, .. } => (size, hash, chunk_size),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        };
+
+        // the old copy is read while the new one is built, so it goes next to it first
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = path.with_file_name(format!(".{file_name}.delta"));
+        let res = self.recv_delta(&tmp_path, old.as_ref(), block_size, chunk_size, size).await;
+        let reused = match res {
+            Ok(reused) if hash_file(&tmp_path).await?.eq_ignore_ascii_case(&hash) => reused,
+            Ok(_) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                bail!("Hash mismatch after download");
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e);
+            }
+        };
+
+        std::fs::rename(&tmp_path, &path)?;
+        Ok((path, size, reused))
+    }
+
+    // applies Delta batches until Done, returns the bytes copied from the old file
+    async fn recv_delta(
+        &mut self,
+        tmp_path: &Path,
+        old: Option<&File>,
+        block_size: u64,
+        chunk_size: u64,
+        size: u64,
+    ) -> anyhow::Result<u64> {
+        let mut out = BufWriter::new(File::create(tmp_path)?);
+        let mut block = vec![0u8; block_size as usize];
+        let (mut written, mut reused) = (0, 0);
+
+        loop {
+            match self.codec.recv(&mut self.stream).await? {
+                Response::Delta { index, ops } => {
+                    for op in ops {
+                        match op {
+                            DeltaOp::Copy { start, count } => {
+                                let Some(old) = old else {
+                                    bail!("Server referenced blocks of a file we don't have");
+                                };
+                                for i in start..start.saturating_add(count) {
+                                    old.read_exact_at(&mut block, i * block_size)?;
+                                    out.write_all(&block)?;
+                                    written += block_size;
+                                    reused += block_size;
+                                }
+                            }
+                            DeltaOp::Literal(data) => {
+                                let data = decompress_chunk(&data, chunk_size as usize).await?;
+                                out.write_all(&data)?;
+                                written += data.len() as u64;
+                            }
+                        }
+                        if written > size {
+                            bail!("Delta larger than announced");
+                        }
+                    }
+                    if let Some(progress) = &mut self.progress {
+                        progress(written, size);
+                    }
+                    self.codec.send(&mut self.stream, &Request::Ack { index }).await?;
+                }
+                Response::Done => break,
+                Response::Error(e) => bail!(e),
+                other => bail!("Unexpected response: {other:?}"),
+            }
+        }
+
+        out.into_inner()?.sync_all()?;
+        if written != size {
+            bail!("Expected {size} bytes, got {written}");
+        }
+        Ok(reused)
+    }
+
+    // chunks until Done, each one is acked after `on_chunk` accepted it
+    async fn recv_chunks<F>(&mut self, chunk_size: u64, mut on_chunk: F) -> anyhow::Result<()>
+    where F: FnMut(u64, &[u8]) -> anyhow::Result<()>
+    {
+        loop {
+            match self.codec.recv(&mut self.stream).await? {
+                Response::Chunck { index, data } => {
+                    let data = decompress_chunk(&data, chunk_size as usize).await?;
+                    on_chunk(index, &data)?;
+                    self.codec.send(&mut self.stream, &Request::Ack { index }).await?;
+                }
+                Response::Done => return Ok(()),
+                Response::Error(e) => bail!(e),
+                other => bail!("Unexpected response: {other:?}"),
+            }
+        }
+    }
+
+    /// Uploads the file at `path` into the server's inbox as `name`, resuming an earlier
+    /// attempt of the same content. Returns the name the server stored it under and the size
+    pub async fn upload(&mut self, path: &Path, name: &str) -> anyhow::Result<(String, u64)> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let hash = hash_file(&path.to_path_buf()).await?;
+
+        let req = Request::Upload { name: name.to_string(), size, hash };
+        let (offset, chunk_size) = match self.request(&req).await? {
+            Response::UploadReady { offset, chunk_size } => (offset, chunk_size),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        };
+        if chunk_size == 0 || offset > size || offset % chunk_size != 0 {
+            bail!("Server asked for an invalid offset {offset}");
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0u8; chunk_size as usize];
+        let mut sent = offset;
+        let mut index = offset / chunk_size;
+        while sent < size {
+            let len = chunk_size.min(size - sent) as usize;
+            file.read_exact(&mut buf[..len])?;
+            let data = compress_chunk(&buf[..len]).await?;
+
+            match self.request(&Request::Chunk { index, data }).await? {
+                Response::Ack { index: acked } if acked == index => {}
+                Response::Error(e) => bail!(e),
+                other => bail!("Unexpected response: {other:?}"),
+            }
+            sent += len as u64;
+            index += 1;
+            if let Some(progress) = &mut self.progress {
+                progress(sent, size);
+            }
+        }
+
+        match self.codec.recv(&mut self.stream).await? {
+            Response::UploadDone { name } => Ok((name, size)),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        }
+    }
+
+    pub async fn admin(&mut self, cmd: DaemonCommand) -> anyhow::Result<DaemonResponse> {
+        match self.request(&Request::Admin(cmd)).await? {
+            Response::Admin(resp) => Ok(resp),
+            Response::Error(e) => bail!(e),
+            other => bail!("Unexpected response: {other:?}"),
+        }
+    }
+
+    pub async fn ping(&mut self) -> anyhow::Result<()> {
+        match self.request(&Request::Ping).await? {
+            Response::Pong => Ok(()),
+            other => bail!("Unexpected response: {other:?}"),
+        }
+    }
+}
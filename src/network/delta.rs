@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+
+use serde::{Deserialize, Serialize};
+
+use crate::settings::CHUNK_SIZE;
+
+// ops per Response::Delta, keeps a batch of block references well under the message limit
+const MAX_BATCH_OPS: usize = 4096;
+// how much of the new file is read at once while looking for matches
+const READ_SIZE: usize = 1024 * 1024;
+
+/// Checksums of one full block of the client's old copy
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BlockSignature {
+    /// rolling checksum, cheap to slide over the new file byte by byte
+    pub weak: u32,
+    /// truncated blake3, confirms a weak match
+    pub strong: [u8; 16],
+}
+
+/// One step of rebuilding the new file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DeltaOp {
+    /// `count` blocks of the old copy, starting at block `start`
+    Copy { start: u64, count: u64 },
+    /// new data, a zstd frame on the wire
+    Literal(Vec<u8>),
+}
+
+// rsync's rolling checksum, both sums are taken mod 2^16
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let len = block.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &x) in block.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        Rolling { a, b, len }
+    }
+
+    // slides the window one byte: `out` leaves at the front, `inp` enters at the back
+    fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+fn strong_hash(block: &[u8]) -> [u8; 16] {
+    let mut strong = [0u8; 16];
+    strong.copy_from_slice(&blake3::hash(block).as_bytes()[..16]);
+    strong
+}
+
+/// Signatures of every full `block_size` block of `reader`, a shorter tail is left out
+pub fn block_signatures<R: Read>(mut reader: R, block_size: usize) -> io::Result<Vec<BlockSignature>> {
+    let mut signatures = Vec::new();
+    let mut buf = vec![0u8; block_size];
+    while fill(&mut reader, &mut buf)? == block_size {
+        signatures.push(BlockSignature { weak: Rolling::new(&buf).digest(), strong: strong_hash(&buf) });
+    }
+    Ok(signatures)
+}
+
+/// Compares the new file in `reader` against the signatures of the client's old copy
+/// and hands batches of ops to `emit`. Literal data in the ops is not compressed yet
+pub fn diff<R, F>(mut reader: R, block_size: usize, signatures: &[BlockSignature], emit: F) -> anyhow::Result<()>
+where
+    R: Read,
+    F: FnMut(Vec<DeltaOp>) -> anyhow::Result<()>,
+{
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (i, signature) in signatures.iter().enumerate() {
+        by_weak.entry(signature.weak).or_default().push(i);
+    }
+
+    let mut out = Batcher { emit, ops: Vec::new(), literal: Vec::new(), batch_bytes: 0 };
+    let mut data = Vec::new();
+    // start of the window in `data`, everything before it is already handled
+    let mut pos = 0;
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+
+    loop {
+        if !eof && data.len() - pos < block_size {
+            data.drain(..pos);
+            pos = 0;
+            let old = data.len();
+            data.resize(old + READ_SIZE, 0);
+            let n = fill(&mut reader, &mut data[old..])?;
+            data.truncate(old + n);
+            eof = n < READ_SIZE;
+            continue;
+        }
+        if data.len() - pos < block_size {
+            break;
+        }
+
+        let window = &data[pos..pos + block_size];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let matched = by_weak.get(&weak).and_then(|candidates| {
+            let strong = strong_hash(window);
+            candidates.iter().find(|&&i| signatures[i].strong == strong)
+        });
+
+        if let Some(&block) = matched {
+            out.copy(block as u64)?;
+            pos += block_size;
+            rolling = None;
+            continue;
+        }
+
+        out.literal(&data[pos..pos + 1])?;
+        match (&mut rolling, data.get(pos + block_size)) {
+            (Some(r), Some(&next)) => r.roll(data[pos], next),
+            _ => rolling = None,
+        }
+        pos += 1;
+    }
+
+    // the tail is shorter than a block and never matches
+    out.literal(&data[pos..])?;
+    out.finish()
+}
+
+// groups ops into batches, merging adjacent block copies and cutting
+// literals at CHUNK_SIZE so each one decompresses within that bound
+struct Batcher<F> {
+    emit: F,
+    ops: Vec<DeltaOp>,
+    literal: Vec<u8>,
+    batch_bytes: usize,
+}
+
+impl<F: FnMut(Vec<DeltaOp>) -> anyhow::Result<()>> Batcher<F> {
+    fn copy(&mut self, block: u64) -> anyhow::Result<()> {
+        self.flush_literal()?;
+        if let Some(DeltaOp::Copy { start, count }) = self.ops.last_mut() {
+            if *start + *count == block {
+                *count += 1;
+                return Ok(());
+            }
+        }
+        self.ops.push(DeltaOp::Copy { start: block, count: 1 });
+        if self.ops.len() >= MAX_BATCH_OPS {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn literal(&mut self, mut data: &[u8]) -> anyhow::Result<()> {
+        while !data.is_empty() {
+            let n = (CHUNK_SIZE - self.literal.len()).min(data.len());
+            self.literal.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.literal.len() == CHUNK_SIZE {
+                self.flush_literal()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush_literal(&mut self) -> anyhow::Result<()> {
+        if self.literal.is_empty() {
+            return Ok(());
+        }
+        self.batch_bytes += self.literal.len();
+        self.ops.push(DeltaOp::Literal(std::mem::take(&mut self.literal)));
+        if self.batch_bytes >= CHUNK_SIZE || self.ops.len() >= MAX_BATCH_OPS {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self) -> anyhow::Result<()> {
+        self.batch_bytes = 0;
+        (self.emit)(std::mem::take(&mut self.ops))
+    }
+
+    fn finish(mut self) -> anyhow::Result<()> {
+        self.flush_literal()?;
+        if !self.ops.is_empty() {
+            self.send()?;
+        }
+        Ok(())
+    }
+}
+
+// reads until `buf` is full or EOF
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 64;
+
+    // pseudo-random bytes, so no two blocks look alike
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| { x = x.wrapping_mul(1_103_515_245).wrapping_add(12_345); (x >> 16) as u8 }).collect()
+    }
+
+    fn delta(old: &[u8], new: &[u8]) -> Vec<DeltaOp> {
+        let signatures = block_signatures(old, BLOCK).unwrap();
+        let mut ops = Vec::new();
+        diff(new, BLOCK, &signatures, |batch| { ops.extend(batch); Ok(()) }).unwrap();
+        ops
+    }
+
+    // rebuilds the new file the way the client does
+    fn apply(old: &[u8], ops: &[DeltaOp]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for op in ops {
+            match op {
+                DeltaOp::Copy { start, count } => {
+                    let start = *start as usize * BLOCK;
+                    out.extend_from_slice(&old[start..start + *count as usize * BLOCK]);
+                }
+                DeltaOp::Literal(data) => out.extend_from_slice(data),
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let bytes = data(3 * BLOCK, 1);
+        let mut rolling = Rolling::new(&bytes[..BLOCK]);
+        for i in 0..2 * BLOCK {
+            rolling.roll(bytes[i], bytes[i + BLOCK]);
+            assert_eq!(rolling.digest(), Rolling::new(&bytes[i + 1..i + 1 + BLOCK]).digest(), "offset {}", i + 1);
+        }
+    }
+
+    #[test]
+    fn unchanged_file_is_one_copy() {
+        let old = data(10 * BLOCK, 2);
+        let ops = delta(&old, &old);
+        assert!(matches!(ops[..], [DeltaOp::Copy { start: 0, count: 10 }]), "{ops:?}");
+    }
+
+    #[test]
+    fn inserted_bytes_become_a_literal_between_copies() {
+        let old = data(10 * BLOCK + 5, 3);
+        let mut new = old.clone();
+        new.splice(4 * BLOCK + 10..4 * BLOCK + 10, *b"inserted");
+
+        let ops = delta(&old, &new);
+        assert!(matches!(ops.first(), Some(DeltaOp::Copy { start: 0, count: 4 })), "{ops:?}");
+        assert!(ops.iter().any(|op| matches!(op, DeltaOp::Copy { start: 5, count: 5 })), "{ops:?}");
+        // the changed block and the short tail are sent as they are
+        let literal: usize = ops.iter().map(|op| match op {
+            DeltaOp::Literal(data) => data.len(),
+            DeltaOp::Copy { .. } => 0,
+        }).sum();
+        assert_eq!(literal, BLOCK + 8 + 5);
+        assert_eq!(apply(&old, &ops), new);
+    }
+
+    #[test]
+    fn shifted_blocks_are_still_found() {
+        let old = data(8 * BLOCK, 4);
+        let new = [b"x".as_slice(), &old].concat();
+        let ops = delta(&old, &new);
+        assert!(matches!(ops[..], [DeltaOp::Literal(ref x), DeltaOp::Copy { start: 0, count: 8 }] if x == b"x"), "{ops:?}");
+    }
+
+    #[test]
+    fn literals_are_cut_at_the_chunk_size() {
+        let new = data(2 * CHUNK_SIZE + 100, 5);
+        let ops = delta(&[], &new);
+        assert!(ops.iter().all(|op| matches!(op, DeltaOp::Literal(data) if data.len() <= CHUNK_SIZE)));
+        assert_eq!(apply(&[], &ops), new);
+    }
+}
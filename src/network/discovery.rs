@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::settings::ANNOUNCE_INTERVAL_SECS;
+
+// first bytes of every datagram, anything else on the group is ignored
+const MAGIC: &[u8; 4] = b"FSHR";
+const MAX_DATAGRAM: usize = 1024;
+
+/// What a server daemon periodically multicasts about itself
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Announcement {
+    pub name: String,
+    pub port: u16,
+    pub fingerprint: String,
+    pub auth_required: bool,
+}
+
+/// Where announcements are sent and listened for
+#[derive(Debug, Clone, Copy)]
+pub struct DiscoveryConfig {
+    pub group: SocketAddrV4,
+    /// local interface to send and join on, 127.0.0.1 keeps it on loopback
+    pub interface: Ipv4Addr,
+}
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_DATAGRAM as u64)
+}
+
+/// Sends `announcement` every few seconds until the task is dropped
+pub async fn announce(config: DiscoveryConfig, announcement: Announcement) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(SocketAddrV4::new(config.interface, 0)).await?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_ttl_v4(1)?;
+
+    let mut datagram = MAGIC.to_vec();
+    datagram.extend(options().serialize(&announcement)?);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(ANNOUNCE_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        if let Err(e) = socket.send_to(&datagram, config.group).await {
+            warn!("Failed to send announcement: {e}");
+        }
+    }
+}
+
+/// Listens for `wait` and returns every peer heard from, keyed by its share address
+pub async fn discover(config: DiscoveryConfig, wait: Duration) -> anyhow::Result<HashMap<SocketAddr, Announcement>> {
+    let socket = bind_group(config)?;
+    let mut peers = HashMap::new();
+    let mut buf = [0u8; MAX_DATAGRAM];
+
+    // running out of time is the normal way to stop listening
+    let deadline = Instant::now() + wait;
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n, from) = res?;
+        if let Some(announcement) = parse(&buf[..n]) {
+            peers.insert(SocketAddr::new(from.ip(), announcement.port), announcement);
+        }
+    }
+    Ok(peers)
+}
+
+/// Like `discover`, but returns as soon as a peer announces itself as `name`
+pub async fn find_announcement(
+    config: DiscoveryConfig,
+    name: &str,
+    wait: Duration,
+) -> anyhow::Result<Option<(SocketAddr, Announcement)>> {
+    let socket = bind_group(config)?;
+    let mut buf = [0u8; MAX_DATAGRAM];
+
+    let deadline = Instant::now() + wait;
+    while let Ok(res) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let (n, from) = res?;
+        if let Some(announcement) = parse(&buf[..n]).filter(|a| a.name == name) {
+            return Ok(Some((SocketAddr::new(from.ip(), announcement.port), announcement)));
+        }
+    }
+    Ok(None)
+}
+
+fn parse(datagram: &[u8]) -> Option<Announcement> {
+    let payload = datagram.strip_prefix(MAGIC)?;
+    options().deserialize(payload).ok()
+}
+
+// several listeners on one host have to share the group port
+fn bind_group(config: DiscoveryConfig) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, config.group.port()).into())?;
+    socket.join_multicast_v4(config.group.ip(), &config.interface)?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loopback(port: u16) -> DiscoveryConfig {
+        DiscoveryConfig { group: SocketAddrV4::new(Ipv4Addr::new(239, 255, 70, 84), port), interface: Ipv4Addr::LOCALHOST }
+    }
+
+    fn announcement(name: &str) -> Announcement {
+        Announcement { name: name.into(), port: 4000, fingerprint: "ab".repeat(32), auth_required: true }
+    }
+
+    #[tokio::test]
+    async fn announcement_is_heard_on_loopback() {
+        // a port of its own, so parallel test runs don't hear each other
+        let config = loopback(47_200 + (std::process::id() % 500) as u16);
+        let listener = tokio::spawn(find_announcement(config, "desk", Duration::from_secs(3)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let announcer = tokio::spawn(announce(config, announcement("desk")));
+
+        let found = listener.await.unwrap().unwrap();
+        announcer.abort();
+        let (addr, found) = found.expect("no announcement heard");
+        assert_eq!(addr, SocketAddr::from((Ipv4Addr::LOCALHOST, 4000)));
+        assert_eq!(found.fingerprint, "ab".repeat(32));
+        assert!(found.auth_required);
+    }
+
+    #[test]
+    fn foreign_datagrams_are_ignored() {
+        let mut datagram = MAGIC.to_vec();
+        datagram.extend(options().serialize(&announcement("desk")).unwrap());
+        assert_eq!(parse(&datagram).map(|a| a.name).as_deref(), Some("desk"));
+
+        assert!(parse(&datagram[4..]).is_none());
+        assert!(parse(&datagram[..datagram.len() - 1]).is_none());
+        assert!(parse(b"FSHR").is_none());
+    }
+}
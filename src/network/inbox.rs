@@ -0,0 +1,328 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tracing::info;
+
+use crate::network::{Codec, Connection, Request, Response, Timeouts, Transfer};
+use crate::settings::{CHUNK_SIZE, INBOX_PART_EXPIRY_SECS};
+use crate::utils::{decompress_chunk, hash_file};
+
+/// What to do when an upload has the same name as a file already in the inbox
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CollisionPolicy {
+    Reject,
+    Rename,
+    Overwrite,
+}
+
+/// Server-side directory that receives uploads
+#[derive(Debug, Clone)]
+pub struct Inbox {
+    pub dir: PathBuf,
+    /// max total bytes stored in the inbox
+    pub quota: Option<u64>,
+    pub on_collision: CollisionPolicy,
+    /// share finished uploads under their inbox name
+    pub auto_share: bool,
+    uploads: Arc<Uploads>,
+}
+
+// uploads in progress, content hash -> announced size. The sizes are reserved against the quota
+#[derive(Debug, Default)]
+struct Uploads {
+    running: std::sync::Mutex<HashMap<String, u64>>,
+    // held while checking the quota and claiming a hash, and while picking the final name
+    admit: tokio::sync::Mutex<()>,
+}
+
+// an upload's claim on its hash and quota, given back when the upload ends either way
+struct Reservation<'a> {
+    uploads: &'a Uploads,
+    hash: String,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.uploads.running.lock().unwrap().remove(&self.hash);
+    }
+}
+
+impl Inbox {
+    pub fn new(dir: PathBuf, quota: Option<u64>, on_collision: CollisionPolicy, auto_share: bool) -> Self {
+        Inbox { dir, quota, on_collision, auto_share, uploads: Arc::default() }
+    }
+
+    /// Runs one upload after the client sent `Request::Upload`.
+    /// Returns the final name and path once the file is complete and verified.
+    /// Unfinished uploads stay as `.<hash>.part` and resume on the next attempt,
+    /// only one upload of the same content runs at a time. It is registered as a transfer of `conn`.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn receive<S>(
+        &self,
+        socket: &mut S,
+        conn: &Connection,
+        codec: Codec,
+        timeouts: Timeouts,
+        name: String,
+        size: u64,
+        hash: String,
+    ) -> anyhow::Result<Option<(String, PathBuf)>>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        if !is_plain_name(&name) {
+            codec.send(socket, &Response::Error("Invalid file name".into())).await?;
+            return Ok(None);
+        }
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            codec.send(socket, &Response::Error("Invalid hash".into())).await?;
+            return Ok(None);
+        }
+        if self.target_name(&name).await.is_none() {
+            codec.send(socket, &Response::Error("File already exists".into())).await?;
+            return Ok(None);
+        }
+
+        fs::create_dir_all(&self.dir).await?;
+        let hash = hash.to_ascii_lowercase();
+        let part_path = self.dir.join(format!(".{hash}.part"));
+
+        let Some(_reservation) = self.reserve(socket, codec, &hash, size).await? else {
+            return Ok(None);
+        };
+
+        // resume from the last complete chunk of an earlier attempt
+        let existing = fs::metadata(&part_path).await.map(|m| m.len()).unwrap_or(0);
+        let offset = if existing > size { 0 } else { existing - existing % CHUNK_SIZE as u64 };
+
+        let mut file = OpenOptions::new().create(true).write(true).truncate(false).open(&part_path).await?;
+        file.set_len(offset).await?;
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+
+        let transfer = conn.upload(&name, &hash, offset, size);
+        codec.send(socket, &Response::UploadReady { offset, chunk_size: CHUNK_SIZE as u64 }).await?;
+
+        let mut received = offset;
+        let mut index = offset / CHUNK_SIZE as u64;
+        let ack_codec = codec.with_read_timeout(timeouts.ack);
+
+        while received < size {
+            let (chunk_idx, data) = match ack_codec.recv(socket).await? {
+                Request::Chunk { index, data } => (index, data),
+                other => anyhow::bail!("unexpected request during upload: {other:?}"),
+            };
+            if chunk_idx != index {
+                return self.abandon(socket, codec, &transfer, &part_path, "Chunk out of order").await;
+            }
+
+            let wire_len = data.len() as u64;
+            let data = decompress_chunk(&data, CHUNK_SIZE).await?;
+            received += data.len() as u64;
+            if data.is_empty() || received > size {
+                return self.abandon(socket, codec, &transfer, &part_path, "Upload larger than announced").await;
+            }
+
+            file.write_all(&data).await?;
+            transfer.advance(data.len() as u64, wire_len);
+            codec.send(socket, &Response::Ack { index }).await?;
+            index += 1;
+        }
+
+        file.flush().await?;
+        drop(file);
+
+        if !hash_file(&part_path).await?.eq_ignore_ascii_case(&hash) {
+            return self.abandon(socket, codec, &transfer, &part_path, "Hash mismatch").await;
+        }
+
+        // the name may have been taken while the upload was running
+        let admit = self.uploads.admit.lock().await;
+        let Some(final_name) = self.target_name(&name).await else {
+            transfer.fail("File already exists");
+            codec.send(socket, &Response::Error("File already exists".into())).await?;
+            return Ok(None);
+        };
+        let final_path = self.dir.join(&final_name);
+        fs::rename(&part_path, &final_path).await?;
+        drop(admit);
+
+        codec.send(socket, &Response::UploadDone { name: final_name.clone() }).await?;
+        info!("Received upload '{final_name}' ({size} bytes)");
+        Ok(Some((final_name, final_path)))
+    }
+
+    // drops an upload the client got wrong, its partial file isn't worth resuming
+    async fn abandon<S>(&self, socket: &mut S, codec: Codec, transfer: &Transfer, part_path: &Path, error: &str)
+        -> anyhow::Result<Option<(String, PathBuf)>>
+    where S: AsyncWrite + Unpin
+    {
+        let _ = fs::remove_file(part_path).await;
+        transfer.fail(error);
+        codec.send(socket, &Response::Error(error.into())).await?;
+        Ok(None)
+    }
+
+    // claims `hash` for this upload and reserves `size` bytes of the quota, the client is told
+    // why if that isn't possible. Partial files of running uploads count with their full size
+    async fn reserve<S>(&self, socket: &mut S, codec: Codec, hash: &str, size: u64) -> anyhow::Result<Option<Reservation<'_>>>
+    where S: AsyncWrite + Unpin
+    {
+        let _admit = self.uploads.admit.lock().await;
+        let running = self.uploads.running.lock().unwrap().clone();
+        if running.contains_key(hash) {
+            codec.send(socket, &Response::Error("This file is already being uploaded".into())).await?;
+            return Ok(None);
+        }
+
+        expire_parts(&self.dir, &running).await?;
+
+        if let Some(quota) = self.quota {
+            let reserved = running.values().sum::<u64>();
+            let skip = running.keys().map(String::as_str).chain([hash]).map(|h| format!(".{h}.part")).collect();
+            let used = dir_usage(&self.dir, &skip).await?.saturating_add(reserved);
+            if used.saturating_add(size) > quota {
+                codec.send(socket, &Response::Error("Inbox quota exceeded".into())).await?;
+                return Ok(None);
+            }
+        }
+
+        self.uploads.running.lock().unwrap().insert(hash.to_string(), size);
+        Ok(Some(Reservation { uploads: &self.uploads, hash: hash.to_string() }))
+    }
+
+    // name the upload will be stored under, None if the policy rejects it
+    async fn target_name(&self, name: &str) -> Option<String> {
+        if !fs::try_exists(self.dir.join(name)).await.unwrap_or(false) {
+            return Some(name.to_string());
+        }
+
+        match self.on_collision {
+            CollisionPolicy::Reject => None,
+            CollisionPolicy::Overwrite => Some(name.to_string()),
+            CollisionPolicy::Rename => {
+                // test.txt -> test (1).txt
+                let path = Path::new(name);
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                let ext = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+                for i in 1.. {
+                    let candidate = format!("{stem} ({i}){ext}");
+                    if !fs::try_exists(self.dir.join(&candidate)).await.unwrap_or(false) {
+                        return Some(candidate);
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+// only a bare file name, nothing that could escape the inbox
+fn is_plain_name(name: &str) -> bool {
+    !name.starts_with('.') && Path::new(name).file_name().is_some_and(|n| n == name)
+}
+
+// removes the partial files of uploads that are neither running nor were resumed for a while
+async fn expire_parts(dir: &Path, running: &HashMap<String, u64>) -> std::io::Result<()> {
+    let expiry = Duration::from_secs(INBOX_PART_EXPIRY_SECS);
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(hash) = name.strip_prefix('.').and_then(|n| n.strip_suffix(".part")) else {
+            continue;
+        };
+        if running.contains_key(hash) {
+            continue;
+        }
+        let modified = entry.metadata().await?.modified()?;
+        if SystemTime::now().duration_since(modified).is_ok_and(|age| age > expiry) {
+            info!("Removing the abandoned upload {name}");
+            fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+// bytes of the files in `dir`, leaving out the `skip` names
+async fn dir_usage(dir: &Path, skip: &HashSet<String>) -> std::io::Result<u64> {
+    let mut total = 0;
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if skip.contains(&*entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        let meta = entry.metadata().await?;
+        if meta.is_file() {
+            total += meta.len();
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inbox(test: &str, on_collision: CollisionPolicy) -> Inbox {
+        let dir = std::env::temp_dir().join(format!("file_share_inbox_{}_{test}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), b"taken").unwrap();
+        std::fs::write(dir.join("notes (1).txt"), b"taken").unwrap();
+        std::fs::write(dir.join("README"), b"taken").unwrap();
+        Inbox::new(dir, None, on_collision, false)
+    }
+
+    #[tokio::test]
+    async fn free_names_are_kept() {
+        for policy in [CollisionPolicy::Reject, CollisionPolicy::Rename, CollisionPolicy::Overwrite] {
+            let inbox = inbox(&format!("free_{policy:?}"), policy);
+            assert_eq!(inbox.target_name("new.txt").await.as_deref(), Some("new.txt"));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_refuses_taken_names() {
+        let inbox = inbox("reject", CollisionPolicy::Reject);
+        assert_eq!(inbox.target_name("notes.txt").await, None);
+    }
+
+    #[tokio::test]
+    async fn overwrite_keeps_taken_names() {
+        let inbox = inbox("overwrite", CollisionPolicy::Overwrite);
+        assert_eq!(inbox.target_name("notes.txt").await.as_deref(), Some("notes.txt"));
+    }
+
+    #[tokio::test]
+    async fn rename_counts_up_to_a_free_name() {
+        let inbox = inbox("rename", CollisionPolicy::Rename);
+        assert_eq!(inbox.target_name("notes.txt").await.as_deref(), Some("notes (2).txt"));
+        assert_eq!(inbox.target_name("README").await.as_deref(), Some("README (1)"));
+    }
+
+    #[tokio::test]
+    async fn only_stale_parts_expire() {
+        let inbox = inbox("expire", CollisionPolicy::Reject);
+        let old = SystemTime::now() - Duration::from_secs(INBOX_PART_EXPIRY_SECS + 60);
+        for name in [".stale.part", ".running.part", "old.txt"] {
+            std::fs::File::create(inbox.dir.join(name)).unwrap().set_modified(old).unwrap();
+        }
+        std::fs::write(inbox.dir.join(".fresh.part"), b"").unwrap();
+
+        let running = HashMap::from([("running".to_string(), 1)]);
+        expire_parts(&inbox.dir, &running).await.unwrap();
+        for (name, kept) in [(".stale.part", false), (".running.part", true), ("old.txt", true), (".fresh.part", true)] {
+            assert_eq!(inbox.dir.join(name).exists(), kept, "{name}");
+        }
+    }
+
+    #[test]
+    fn only_plain_names_are_accepted() {
+        assert!(is_plain_name("notes.txt"));
+        for bad in ["", ".", "..", ".hidden", "../notes.txt", "dir/notes.txt", "/etc/passwd", "notes.txt/"] {
+            assert!(!is_plain_name(bad), "{bad}");
+        }
+    }
+}
@@ -1,30 +1,234 @@
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+use bincode::Options;
 use serde::{Serialize, de::DeserializeOwned};
-use anyhow::Result;
-use bincode;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-pub async fn send_message<T, S>(stream: &mut S, msg: &T) -> Result<()>
-where
-    T: Serialize,
-    S: AsyncWrite + Unpin
-{
-    let data = bincode::serialize(msg)?;
-    let len = (data.len() as u32).to_be_bytes();
-    stream.write_all(&len).await?;
-    stream.write_all(&data).await?;
-    Ok(())
+use crate::settings::{MAX_MESSAGE_SIZE, READ_TIMEOUT, WRITE_TIMEOUT};
+
+// everything that can go wrong while reading or writing a frame
+#[derive(Debug)]
+pub enum CodecError {
+    /// Peer closed the connection between two frames
+    Disconnected,
+    /// Frame length prefix is bigger than the configured limit
+    Oversize { len: u64, max: u64 },
+    /// Frame could not be decoded or was cut off
+    Protocol(String),
+    /// Peer did not send or receive a frame in time
+    Timeout,
+    Io(io::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Disconnected => write!(f, "peer disconnected"),
+            CodecError::Oversize { len, max } => write!(f, "frame of {len} bytes exceeds limit of {max} bytes"),
+            CodecError::Protocol(msg) => write!(f, "protocol violation: {msg}"),
+            CodecError::Timeout => write!(f, "timed out"),
+            CodecError::Io(e) => write!(f, "io error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
 }
 
-pub async fn recv_message<T, S>(stream: &mut S) -> Result<T>
-where 
-    T: DeserializeOwned,
-    S: AsyncRead + Unpin
+impl From<io::Error> for CodecError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::BrokenPipe => CodecError::Disconnected,
+            io::ErrorKind::TimedOut => CodecError::Timeout,
+            _ => CodecError::Io(e),
+        }
+    }
+}
+
+impl From<bincode::Error> for CodecError {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(e) => e.into(),
+            bincode::ErrorKind::SizeLimit => CodecError::Protocol("message exceeds size limit".into()),
+            other => CodecError::Protocol(other.to_string()),
+        }
+    }
+}
+
+/// Length-prefixed bincode framing with a size limit and timeouts.
+/// Frames are a 4-byte big-endian length followed by the bincode payload.
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub max_message_size: u32,
+    pub read_timeout: Option<Duration>,
+    pub write_timeout: Option<Duration>,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec {
+            max_message_size: MAX_MESSAGE_SIZE,
+            read_timeout: Some(READ_TIMEOUT),
+            write_timeout: Some(WRITE_TIMEOUT),
+        }
+    }
+}
+
+impl Codec {
+    pub fn with_max_message_size(mut self, max: u32) -> Self {
+        self.max_message_size = max;
+        self
+    }
+
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    // same layout as bincode::serialize, but bounded so a bogus length inside
+    // the payload can't make us allocate more than the frame itself
+    fn options(&self) -> impl Options {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(self.max_message_size as u64)
+    }
+
+    pub async fn send<T, S>(&self, stream: &mut S, msg: &T) -> Result<(), CodecError>
+    where
+        T: Serialize,
+        S: AsyncWrite + Unpin
+    {
+        let data = self.options().serialize(msg)?;
+        let len = data.len() as u64;
+        if len > self.max_message_size as u64 {
+            return Err(CodecError::Oversize { len, max: self.max_message_size as u64 });
+        }
+
+        with_timeout(self.write_timeout, async {
+            stream.write_all(&(len as u32).to_be_bytes()).await?;
+            stream.write_all(&data).await?;
+            stream.flush().await?;
+            Ok(())
+        }).await
+    }
+
+    pub async fn recv<T, S>(&self, stream: &mut S) -> Result<T, CodecError>
+    where
+        T: DeserializeOwned,
+        S: AsyncRead + Unpin
+    {
+        let buf = with_timeout(self.read_timeout, async {
+            let mut len_buf = [0u8; 4];
+            stream.read_exact(&mut len_buf).await?;
+            let len = u32::from_be_bytes(len_buf);
+            if len > self.max_message_size {
+                return Err(CodecError::Oversize { len: len as u64, max: self.max_message_size as u64 });
+            }
+
+            // EOF in the middle of a frame is not a clean disconnect
+            let mut buf = vec![0u8; len as usize];
+            if let Err(e) = stream.read_exact(&mut buf).await {
+                return Err(match e.kind() {
+                    io::ErrorKind::UnexpectedEof => CodecError::Protocol("truncated frame".into()),
+                    _ => e.into(),
+                });
+            }
+            Ok(buf)
+        }).await?;
+
+        // the frame is already read, running out of it means the message is cut off, not the connection
+        self.options().deserialize(&buf).map_err(|e| match *e {
+            bincode::ErrorKind::Io(_) => CodecError::Protocol("message runs past the end of its frame".into()),
+            e => Box::new(e).into(),
+        })
+    }
+}
+
+async fn with_timeout<T, F>(timeout: Option<Duration>, fut: F) -> Result<T, CodecError>
+where F: Future<Output = Result<T, CodecError>>
 {
-    let mut len_buf = [0u8; 4];
-    stream.read_exact(&mut len_buf).await?;
-    let len = u32::from_be_bytes(len_buf);
+    match timeout {
+        Some(t) => tokio::time::timeout(t, fut).await.map_err(|_| CodecError::Timeout)?,
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec() -> Codec {
+        Codec::default().with_max_message_size(64).with_read_timeout(Some(Duration::from_millis(100)))
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        codec().send(&mut a, &("name".to_string(), 7u64)).await.unwrap();
+        let msg: (String, u64) = codec().recv(&mut b).await.unwrap();
+        assert_eq!(msg, ("name".to_string(), 7));
+    }
+
+    #[tokio::test]
+    async fn oversize_messages_are_not_sent() {
+        let (mut a, _b) = tokio::io::duplex(1024);
+        // bincode's limit catches it while encoding
+        let res = codec().send(&mut a, &vec![0u8; 100]).await;
+        assert!(matches!(res, Err(CodecError::Protocol(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn oversize_length_prefix_is_refused_before_reading() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(&65u32.to_be_bytes()).await.unwrap();
+        let res = codec().recv::<Vec<u8>, _>(&mut b).await;
+        assert!(matches!(res, Err(CodecError::Oversize { len: 65, max: 64 })), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn length_past_the_frame_is_a_protocol_error() {
+        // a Vec claiming u64::MAX elements in an otherwise valid frame
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(&8u32.to_be_bytes()).await.unwrap();
+        a.write_all(&u64::MAX.to_le_bytes()).await.unwrap();
+        let res = codec().recv::<Vec<u8>, _>(&mut b).await;
+        assert!(matches!(res, Err(CodecError::Protocol(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_is_a_protocol_error() {
+        let (mut a, mut b) = tokio::io::duplex(1024);
+        a.write_all(&10u32.to_be_bytes()).await.unwrap();
+        a.write_all(b"short").await.unwrap();
+        drop(a);
+        let res = codec().recv::<Vec<u8>, _>(&mut b).await;
+        assert!(matches!(res, Err(CodecError::Protocol(_))), "{res:?}");
+    }
+
+    #[tokio::test]
+    async fn close_between_frames_is_a_disconnect() {
+        let (a, mut b) = tokio::io::duplex(1024);
+        drop(a);
+        let res = codec().recv::<Vec<u8>, _>(&mut b).await;
+        assert!(matches!(res, Err(CodecError::Disconnected)), "{res:?}");
+    }
 
-    let mut buf = vec![0u8; len as usize];
-    stream.read_exact(&mut buf).await?;
-    Ok(bincode::deserialize(&buf)?)
+    #[tokio::test]
+    async fn silent_peer_times_out() {
+        let (_a, mut b) = tokio::io::duplex(1024);
+        let res = codec().recv::<Vec<u8>, _>(&mut b).await;
+        assert!(matches!(res, Err(CodecError::Timeout)), "{res:?}");
+    }
 }
@@ -0,0 +1,38 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+
+use crate::settings::KNOWN_HOSTS_FILE;
+use crate::utils::config_path;
+
+/// Certificate fingerprints of servers connected to before, one `host:port fingerprint`
+/// per line. A server seen once has to present the same certificate from then on
+pub struct KnownHosts {
+    pub path: PathBuf,
+}
+
+impl KnownHosts {
+    /// The known hosts of the calling user, in their config directory
+    pub fn of_user() -> io::Result<Self> {
+        Ok(KnownHosts { path: config_path(KNOWN_HOSTS_FILE)? })
+    }
+
+    /// Fingerprint remembered for `addr`, the first one if it is listed twice
+    pub fn get(&self, addr: &str) -> io::Result<Option<String>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(content.lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(host, _)| *host == addr)
+            .map(|(_, fp)| fp.trim().to_string()))
+    }
+
+    pub fn add(&self, addr: &str, fingerprint: &str) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).mode(0o600).open(&self.path)?;
+        writeln!(file, "{addr} {fingerprint}")
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::settings::PROTOCOL_VERSION;
+
+pub const SERVICE_TYPE: &str = "_fileshare._tcp.local.";
+
+/// A `_fileshare._tcp` instance found on the network
+#[derive(Serialize, Debug, Clone)]
+pub struct ServiceRecord {
+    pub instance: String,
+    pub host: String,
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+    pub fingerprint: Option<String>,
+    pub version: Option<String>,
+    pub auth_required: bool,
+}
+
+impl ServiceRecord {
+    fn from_info(info: &ServiceInfo) -> Self {
+        let suffix = format!(".{SERVICE_TYPE}");
+        let fullname = info.get_fullname();
+        let mut addrs = info.get_addresses().iter().copied().collect::<Vec<_>>();
+        addrs.sort();
+
+        ServiceRecord {
+            instance: fullname.strip_suffix(&suffix).unwrap_or(fullname).to_string(),
+            host: info.get_hostname().to_string(),
+            addrs,
+            port: info.get_port(),
+            fingerprint: info.get_property_val_str("fp").map(str::to_string),
+            version: info.get_property_val_str("proto").map(str::to_string),
+            auth_required: info.get_property_val_str("auth") == Some("1"),
+        }
+    }
+}
+
+/// Registers the TLS listener, the service stays up as long as the returned daemon lives
+pub fn advertise(instance: &str, port: u16, fingerprint: &str, auth_required: bool) -> anyhow::Result<ServiceDaemon> {
+    let mdns = ServiceDaemon::new()?;
+    let host = format!("{}.local.", crate::utils::hostname());
+    let version = PROTOCOL_VERSION.to_string();
+    let properties = [
+        ("fp", fingerprint),
+        ("proto", version.as_str()),
+        ("auth", if auth_required { "1" } else { "0" }),
+    ];
+
+    let info = ServiceInfo::new(SERVICE_TYPE, instance, &host, "", port, &properties[..])?
+        .enable_addr_auto();
+    mdns.register(info)?;
+    Ok(mdns)
+}
+
+/// Collects every instance resolved within `wait`
+pub async fn browse(wait: Duration) -> anyhow::Result<Vec<ServiceRecord>> {
+    let mdns = ServiceDaemon::new()?;
+    let events = mdns.browse(SERVICE_TYPE)?;
+    let mut found = HashMap::new();
+
+    let deadline = Instant::now() + wait;
+    while let Ok(event) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        if let ServiceEvent::ServiceResolved(info) = event? {
+            let record = ServiceRecord::from_info(&info);
+            found.insert(record.instance.clone(), record);
+        }
+    }
+
+    let _ = mdns.shutdown();
+    let mut found = found.into_values().collect::<Vec<_>>();
+    found.sort_by(|a, b| a.instance.cmp(&b.instance));
+    Ok(found)
+}
+
+/// Looks up one instance by name, stops as soon as it's resolved
+pub async fn resolve(instance: &str, wait: Duration) -> anyhow::Result<Option<ServiceRecord>> {
+    let mdns = ServiceDaemon::new()?;
+    let events = mdns.browse(SERVICE_TYPE)?;
+    let mut found = None;
+
+    let deadline = Instant::now() + wait;
+    while let Ok(event) = tokio::time::timeout_at(deadline, events.recv_async()).await {
+        if let ServiceEvent::ServiceResolved(info) = event? {
+            let record = ServiceRecord::from_info(&info);
+            if record.instance.eq_ignore_ascii_case(instance) {
+                found = Some(record);
+                break;
+            }
+        }
+    }
+
+    let _ = mdns.shutdown();
+    Ok(found)
+}
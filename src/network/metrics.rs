@@ -0,0 +1,130 @@
+use std::fmt::Write as _;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::network::ServerStats;
+use crate::settings::{METRICS_MAX_REQUEST, METRICS_TIMEOUT_SECS, TRANSFER_DURATION_BUCKETS};
+
+/// Answers `GET /metrics` on `listener` with the counters of `stats` in Prometheus text format
+pub async fn serve_metrics(listener: TcpListener, stats: Arc<ServerStats>) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{addr}/metrics");
+    }
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to accept metrics connection: {e}");
+                continue;
+            }
+        };
+        let stats = Arc::clone(&stats);
+        tokio::spawn(async move {
+            let scrape = answer(socket, &stats);
+            match tokio::time::timeout(Duration::from_secs(METRICS_TIMEOUT_SECS), scrape).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!(%peer, "Metrics request failed: {e}"),
+                Err(_) => debug!(%peer, "Metrics request timed out"),
+            }
+        });
+    }
+}
+
+// one request per connection, the body is rendered fresh for each scrape
+async fn answer(mut socket: TcpStream, stats: &ServerStats) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+        if request.len() > METRICS_MAX_REQUEST {
+            return respond(&mut socket, "413 Payload Too Large", "text/plain", "request too large\n").await;
+        }
+    }
+
+    let line = request.split(|&b| b == b'\r').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            respond(&mut socket, "200 OK", "text/plain; version=0.0.4", &render(stats)).await
+        }
+        (Some(b"GET"), _) => respond(&mut socket, "404 Not Found", "text/plain", "not found\n").await,
+        _ => respond(&mut socket, "405 Method Not Allowed", "text/plain", "method not allowed\n").await,
+    }
+}
+
+async fn respond(socket: &mut TcpStream, status: &str, content_type: &str, body: &str) -> std::io::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len(),
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body.as_bytes()).await?;
+    socket.shutdown().await
+}
+
+/// The Prometheus text exposition of `stats`
+pub fn render(stats: &ServerStats) -> String {
+    let counters = &stats.counters;
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}");
+    };
+
+    metric("file_share_connections_accepted_total", "counter", "TCP connections accepted.",
+        counters.connections_accepted.load(Ordering::Relaxed).to_string());
+    metric("file_share_connections_rejected_total", "counter", "Connections closed before authenticating.",
+        counters.connections_rejected.load(Ordering::Relaxed).to_string());
+    metric("file_share_auth_failures_total", "counter", "Failed authentication attempts.",
+        counters.auth_failures.load(Ordering::Relaxed).to_string());
+    metric("file_share_connections_active", "gauge", "Client sessions, counting ones still authenticating.",
+        stats.active_connections().to_string());
+    metric("file_share_transfers_active", "gauge", "Transfers in progress.",
+        stats.active_transfers().to_string());
+    metric("file_share_hash_cache_hits_total", "counter", "Share hashes served from the cache.",
+        counters.hash_cache_hits.load(Ordering::Relaxed).to_string());
+    metric("file_share_hash_cache_misses_total", "counter", "Share hashes computed because the file changed.",
+        counters.hash_cache_misses.load(Ordering::Relaxed).to_string());
+
+    let uncompressed = counters.uncompressed_bytes.load(Ordering::Relaxed);
+    let compressed = counters.compressed_bytes.load(Ordering::Relaxed);
+    metric("file_share_compression_input_bytes_total", "counter", "Chunk bytes before compression.",
+        uncompressed.to_string());
+    metric("file_share_compression_output_bytes_total", "counter", "Chunk bytes after compression.",
+        compressed.to_string());
+    // 1 until anything was compressed
+    let ratio = if compressed == 0 { 1.0 } else { uncompressed as f64 / compressed as f64 };
+    metric("file_share_compression_ratio", "gauge", "Bytes before compression per byte sent.", ratio.to_string());
+
+    let _ = writeln!(out, "# HELP file_share_bytes_sent_total File data sent to clients.");
+    let _ = writeln!(out, "# TYPE file_share_bytes_sent_total counter");
+    for (share, bytes) in counters.bytes_by_share.lock().unwrap().iter() {
+        let _ = writeln!(out, "file_share_bytes_sent_total{{share=\"{}\"}} {bytes}", escape_label(share));
+    }
+
+    let durations = counters.durations.lock().unwrap();
+    let _ = writeln!(out, "# HELP file_share_transfer_duration_seconds Time from the start to the end of a transfer.");
+    let _ = writeln!(out, "# TYPE file_share_transfer_duration_seconds histogram");
+    let mut cumulative = 0;
+    for (bound, count) in TRANSFER_DURATION_BUCKETS.iter().zip(durations.counts) {
+        cumulative += count;
+        let _ = writeln!(out, "file_share_transfer_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}");
+    }
+    let _ = writeln!(out, "file_share_transfer_duration_seconds_bucket{{le=\"+Inf\"}} {}", durations.count);
+    let _ = writeln!(out, "file_share_transfer_duration_seconds_sum {}", durations.sum);
+    let _ = writeln!(out, "file_share_transfer_duration_seconds_count {}", durations.count);
+    out
+}
+
+// label values escape backslashes, quotes and newlines
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
@@ -2,10 +2,42 @@ pub mod server;
 pub mod protocol;
 pub mod io;
 pub mod tls;
+pub mod inbox;
+pub mod search;
+pub mod discovery;
+pub mod mdns;
+pub mod swarm;
 pub mod client;
+pub mod relay;
+pub mod pake;
+pub mod transfer;
+pub mod uri;
+pub mod delta;
+pub mod sync;
+pub mod watch;
+pub mod stats;
+pub mod audit;
+pub mod metrics;
+pub mod known_hosts;
 
 pub use server::*;
 pub use protocol::*;
 pub use io::*;
 pub use tls::*;
-pub use client::*;
\ No newline at end of file
+pub use inbox::*;
+pub use search::*;
+pub use discovery::*;
+pub use mdns::*;
+pub use swarm::*;
+pub use client::*;
+pub use relay::*;
+pub use pake::*;
+pub use transfer::*;
+pub use uri::*;
+pub use delta::*;
+pub use sync::*;
+pub use watch::*;
+pub use stats::*;
+pub use audit::*;
+pub use metrics::*;
+pub use known_hosts::*;
\ No newline at end of file
@@ -0,0 +1,89 @@
+use anyhow::anyhow;
+use spake2::{Ed25519Group, Identity, Password};
+
+// both sides name themselves and each other the same way
+const SENDER_ID: &[u8] = b"file_share sender";
+const RECEIVER_ID: &[u8] = b"file_share receiver";
+
+/// Side of the exchange, the two sides blind their message with different points
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PakeRole {
+    Sender,
+    Receiver,
+}
+
+/// One side of a SPAKE2 exchange, run by the `spake2` crate.
+/// Both sides end up with the same key only if they started from the same password,
+/// and an eavesdropper learns nothing it could check password guesses against
+pub struct Spake2 {
+    state: spake2::Spake2<Ed25519Group>,
+    message: Vec<u8>,
+}
+
+impl Spake2 {
+    pub fn start(role: PakeRole, password: &[u8]) -> Self {
+        let (password, sender, receiver) = (Password::new(password), Identity::new(SENDER_ID), Identity::new(RECEIVER_ID));
+        let (state, message) = match role {
+            PakeRole::Sender => spake2::Spake2::start_a(&password, &sender, &receiver),
+            PakeRole::Receiver => spake2::Spake2::start_b(&password, &sender, &receiver),
+        };
+        Spake2 { state, message }
+    }
+
+    /// Message to send to the other side
+    pub fn message(&self) -> &Vec<u8> {
+        &self.message
+    }
+
+    /// Shared key from the other side's message.
+    /// `binding` is mixed into the key to tie it to the channel the exchange ran over
+    pub fn finish(self, peer: &[u8], binding: &[u8]) -> anyhow::Result<[u8; 32]> {
+        let key = self.state.finish(peer).map_err(|e| anyhow!("invalid PAKE message: {e}"))?;
+        let mut hasher = blake3::Hasher::new_derive_key("file_share spake2 v2 channel binding");
+        hasher.update(&key);
+        hasher.update(binding);
+        Ok(*hasher.finalize().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(sender_password: &[u8], receiver_password: &[u8]) -> ([u8; 32], [u8; 32]) {
+        let sender = Spake2::start(PakeRole::Sender, sender_password);
+        let receiver = Spake2::start(PakeRole::Receiver, receiver_password);
+        let (to_receiver, to_sender) = (sender.message().clone(), receiver.message().clone());
+        (sender.finish(&to_sender, b"channel").unwrap(), receiver.finish(&to_receiver, b"channel").unwrap())
+    }
+
+    #[test]
+    fn same_password_agrees_on_a_key() {
+        let (sender, receiver) = exchange(b"correct horse", b"correct horse");
+        assert_eq!(sender, receiver);
+        // every exchange picks fresh secrets
+        assert_ne!(exchange(b"correct horse", b"correct horse").0, sender);
+    }
+
+    #[test]
+    fn different_passwords_disagree() {
+        let (sender, receiver) = exchange(b"correct horse", b"battery staple");
+        assert_ne!(sender, receiver);
+    }
+
+    #[test]
+    fn binding_is_part_of_the_key() {
+        let sender = Spake2::start(PakeRole::Sender, b"pw");
+        let receiver = Spake2::start(PakeRole::Receiver, b"pw");
+        let (to_receiver, to_sender) = (sender.message().clone(), receiver.message().clone());
+        assert_ne!(sender.finish(&to_sender, b"one").unwrap(), receiver.finish(&to_receiver, b"two").unwrap());
+    }
+
+    #[test]
+    fn invalid_message_is_refused() {
+        let sender = Spake2::start(PakeRole::Sender, b"pw");
+        assert!(Spake2::start(PakeRole::Sender, b"pw").finish(&[0xff; 33], b"").is_err());
+        // a message of the wrong length
+        assert!(sender.finish(&[0; 32], b"").is_err());
+    }
+}
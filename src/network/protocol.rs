@@ -1,15 +1,49 @@
 use serde::{Deserialize, Serialize};
 
+use crate::daemon::{DaemonCommand, DaemonResponse};
+use crate::network::{BlockSignature, DeltaOp, SearchHit, SearchQuery, ShareEvent};
+
+/// A share as listed to clients
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShareEntry {
+    pub name: String,
+    /// blake3 of the content, `None` if the file can't be read
+    pub hash: Option<String>,
+    /// false while the shared file is missing from disk
+    pub available: bool,
+}
+
 // client -> server
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Request {
     Auth(Option<String>),
+    // token from a share link, only allows downloading that share
+    AuthToken(String),
     Quit,
+    Ping,
 
     List,
+    Search(SearchQuery),
+    // turns the connection into a stream of Event responses
+    Subscribe,
 
     Download { name: String, offset: u64 },
-    Ack { index: u64 }
+    // content addressed, keeps working when the share is renamed
+    DownloadByHash { hash: String, offset: u64 },
+    // signatures of the client's old copy, answered with Delta batches
+    DownloadDelta { name: String, block_size: u64, signatures: Vec<BlockSignature> },
+    Ack { index: u64 },
+
+    // swarm downloads address content by blake3 hash and fetch chunk ranges
+    ChunkHashes { hash: String, start: u64, count: u64 },
+    DownloadRange { hash: String, start: u64, count: u64 },
+
+    Upload { name: String, size: u64, hash: String },
+    // data is a zstd frame
+    Chunk { index: u64, data: Vec<u8> },
+
+    // share management, needs the admin role
+    Admin(DaemonCommand),
 }
 
 // server -> client
@@ -18,9 +52,12 @@ pub enum Response {
     AuthOk,
     AuthErr,
     Bye,
+    Pong,
 
-    List(Vec<String>),
+    List(Vec<ShareEntry>),
+    SearchResults { total: u64, hits: Vec<SearchHit> },
     Error(String),
+    Event(ShareEvent),
 
     FileInfo {
         name: String,
@@ -28,6 +65,17 @@ pub enum Response {
         hash: String,
         chunk_size: u64,
     },
+    // data is a zstd frame
     Chunck { index: u64, data: Vec<u8> },
+    // acked like chunks
+    Delta { index: u64, ops: Vec<DeltaOp> },
     Done,
+
+    ChunkHashes { size: u64, chunk_size: u64, start: u64, hashes: Vec<[u8; 32]> },
+
+    UploadReady { offset: u64, chunk_size: u64 },
+    Ack { index: u64 },
+    UploadDone { name: String },
+
+    Admin(DaemonResponse),
 }
\ No newline at end of file
@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tokio::io::copy_bidirectional;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::daemon::Heartbeat;
+use crate::network::{set_keepalive, Codec, CodecError};
+use crate::settings::{KEEPALIVE_SECS, MAX_RELAY_WAITING, MAX_SESSION_ID_LEN, RELAY_HELLO_TIMEOUT_SECS};
+
+/// First message a peer sends to the relay
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RelayRequest {
+    /// wait for a peer to join `session`
+    Listen { session: String },
+    /// join the peer waiting on `session`
+    Connect { session: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum RelayResponse {
+    Waiting,
+    /// from here on the connection carries the other peer's bytes
+    Paired,
+    Error(String),
+}
+
+// a session is reserved with `None` while its peer is told to wait
+type Waiting = Arc<Mutex<HashMap<String, Option<TcpStream>>>>;
+
+// hello messages are tiny, a peer that says nothing is dropped quickly
+fn hello_codec() -> Codec {
+    Codec::default()
+        .with_max_message_size(1024)
+        .with_read_timeout(Some(Duration::from_secs(RELAY_HELLO_TIMEOUT_SECS)))
+}
+
+/// Pairs the peers connecting to `listener` by session id and forwards bytes between them.
+/// The peers run TLS over the forwarded connection, so the relay never sees plaintext
+pub async fn run_relay(listener: TcpListener) -> anyhow::Result<()> {
+    let waiting = Waiting::default();
+    info!("Relay listening on {}", listener.local_addr()?);
+
+    let mut heartbeat = Heartbeat::register();
+    loop {
+        let (socket, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = heartbeat.tick() => continue,
+        };
+        if let Err(e) = set_keepalive(&socket, Duration::from_secs(KEEPALIVE_SECS)) {
+            warn!("Failed to enable keepalive for {peer}: {e}");
+        }
+
+        let waiting = Arc::clone(&waiting);
+        tokio::spawn(async move {
+            if let Err(e) = relay_peer(socket, waiting).await {
+                match e.downcast_ref::<CodecError>() {
+                    Some(CodecError::Disconnected) => info!("Peer disconnected"),
+                    _ => warn!("Error relaying: {e:#}"),
+                }
+            }
+        }.instrument(info_span!("conn", %peer)));
+    }
+}
+
+async fn relay_peer(mut socket: TcpStream, waiting: Waiting) -> anyhow::Result<()> {
+    let codec = hello_codec();
+
+    match codec.recv(&mut socket).await? {
+        RelayRequest::Listen { session } => {
+            if session.is_empty() || session.len() > MAX_SESSION_ID_LEN {
+                codec.send(&mut socket, &RelayResponse::Error("Invalid session id".into())).await?;
+                return Ok(());
+            }
+            // a session belongs to whoever registered it first, until their connection is gone.
+            // A reconnecting peer finds its stale registration dropped
+            let refusal = {
+                let mut sessions = waiting.lock().await;
+                sessions.retain(|_, peer| peer.as_ref().is_none_or(is_open));
+                if sessions.contains_key(&session) {
+                    Some("Session is taken")
+                } else if sessions.len() >= MAX_RELAY_WAITING {
+                    Some("Relay is full")
+                } else {
+                    sessions.insert(session.clone(), None);
+                    None
+                }
+            };
+            if let Some(refusal) = refusal {
+                warn!("Refused a peer waiting on session '{session}': {refusal}");
+                codec.send(&mut socket, &RelayResponse::Error(refusal.into())).await?;
+                return Ok(());
+            }
+
+            let sent = codec.send(&mut socket, &RelayResponse::Waiting).await;
+            let mut sessions = waiting.lock().await;
+            if let Err(e) = sent {
+                sessions.remove(&session);
+                return Err(e.into());
+            }
+            info!("Waiting on session '{session}'");
+            sessions.insert(session, Some(socket));
+        }
+        RelayRequest::Connect { session } => {
+            let other = {
+                let mut sessions = waiting.lock().await;
+                match sessions.get(&session) {
+                    Some(Some(_)) => sessions.remove(&session).flatten(),
+                    _ => None,
+                }
+            };
+            let Some(mut other) = other else {
+                codec.send(&mut socket, &RelayResponse::Error("No peer waiting on this session".into())).await?;
+                return Ok(());
+            };
+            if codec.send(&mut other, &RelayResponse::Paired).await.is_err() {
+                codec.send(&mut socket, &RelayResponse::Error("Waiting peer went away".into())).await?;
+                return Ok(());
+            }
+            codec.send(&mut socket, &RelayResponse::Paired).await?;
+            info!("Session '{session}' paired");
+
+            let (up, down) = copy_bidirectional(&mut socket, &mut other).await?;
+            info!("Session '{session}' closed, {up} bytes up, {down} bytes down");
+        }
+    }
+    Ok(())
+}
+
+// a waiting peer sends nothing until it is paired, so anything readable means it hung up
+fn is_open(socket: &TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    matches!(socket.try_read(&mut byte), Err(e) if e.kind() == std::io::ErrorKind::WouldBlock)
+}
+
+/// Registers on `relay` as the peer waiting on `session`.
+/// Returns once another peer joined, the stream then leads to that peer
+pub async fn relay_listen(relay: &str, session: &str) -> anyhow::Result<TcpStream> {
+    let mut socket = relay_hello(relay, RelayRequest::Listen { session: session.to_string() }).await?;
+    if let Err(e) = set_keepalive(&socket, Duration::from_secs(KEEPALIVE_SECS)) {
+        warn!("Failed to enable keepalive for relay {relay}: {e}");
+    }
+
+    // a client may take arbitrarily long to show up
+    match hello_codec().with_read_timeout(None).recv(&mut socket).await? {
+        RelayResponse::Paired => Ok(socket),
+        RelayResponse::Error(e) => bail!(e),
+        other => bail!("Unexpected relay response: {other:?}"),
+    }
+}
+
+/// Joins the peer waiting on `session` at `relay`
+pub async fn relay_connect(relay: &str, session: &str) -> anyhow::Result<TcpStream> {
+    relay_hello(relay, RelayRequest::Connect { session: session.to_string() }).await
+}
+
+// sends the hello and returns the stream once the relay accepted it
+async fn relay_hello(relay: &str, req: RelayRequest) -> anyhow::Result<TcpStream> {
+    let mut socket = TcpStream::connect(relay).await?;
+    let codec = hello_codec();
+    codec.send(&mut socket, &req).await?;
+
+    match (req, codec.recv(&mut socket).await?) {
+        (RelayRequest::Listen { .. }, RelayResponse::Waiting)
+        | (RelayRequest::Connect { .. }, RelayResponse::Paired) => Ok(socket),
+        (_, RelayResponse::Error(e)) => bail!(e),
+        (_, other) => bail!("Unexpected relay response: {other:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{test_acceptor, Client, Server};
+
+    async fn start_relay() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(run_relay(listener));
+        addr
+    }
+
+    // registers a server sharing one file on `session`, it takes a single client in the background
+    async fn serve_once(relay: &str, session: &str, acceptor: tokio_rustls::TlsAcceptor) {
+        let dir = std::env::temp_dir().join(format!("file_share_relay_{}_{session}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.txt"), b"relayed").unwrap();
+        let server = Server::new(None);
+        server.add_file("notes.txt".into(), dir.join("notes.txt"), None).await;
+
+        let mut socket = relay_hello(relay, RelayRequest::Listen { session: session.to_string() }).await.unwrap();
+        tokio::spawn(async move {
+            let paired = hello_codec().with_read_timeout(None).recv(&mut socket).await;
+            assert!(matches!(paired, Ok(RelayResponse::Paired)));
+            let peer = socket.peer_addr().unwrap();
+            if let Ok(tls) = acceptor.accept(socket).await {
+                let _ = server.handle_client(tls, peer).await;
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn client_reaches_server_through_relay() {
+        let relay = start_relay().await;
+        let (acceptor, fp) = test_acceptor();
+        serve_once(&relay, "pinned", acceptor).await;
+
+        let mut client = Client::connect_relayed(&relay, "pinned", None, &fp).await.unwrap();
+        let names = client.list().await.unwrap().into_iter().map(|entry| entry.name).collect::<Vec<_>>();
+        assert_eq!(names, ["notes.txt"]);
+    }
+
+    #[tokio::test]
+    async fn relayed_client_refuses_other_certificate() {
+        let relay = start_relay().await;
+        let (acceptor, _) = test_acceptor();
+        let (_, other_fp) = test_acceptor();
+        serve_once(&relay, "mitm", acceptor).await;
+
+        assert!(Client::connect_relayed(&relay, "mitm", None, &other_fp).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn second_listener_is_refused() {
+        let relay = start_relay().await;
+        let first = relay_hello(&relay, RelayRequest::Listen { session: "taken".into() }).await.unwrap();
+        let second = relay_hello(&relay, RelayRequest::Listen { session: "taken".into() }).await;
+        assert!(second.is_err());
+
+        // once the first one is gone the session is free again
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(relay_hello(&relay, RelayRequest::Listen { session: "taken".into() }).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_relay_refuses_listeners() {
+        let relay = start_relay().await;
+        let mut waiting = Vec::new();
+        for i in 0..MAX_RELAY_WAITING {
+            waiting.push(relay_hello(&relay, RelayRequest::Listen { session: format!("s{i}") }).await.unwrap());
+        }
+        assert!(relay_hello(&relay, RelayRequest::Listen { session: "one more".into() }).await.is_err());
+
+        // a peer that hung up makes room
+        waiting.pop();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(relay_hello(&relay, RelayRequest::Listen { session: "one more".into() }).await.is_ok());
+    }
+}
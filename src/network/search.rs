@@ -0,0 +1,131 @@
+use std::time::UNIX_EPOCH;
+
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::network::Share;
+
+// keeps a hostile pattern from compiling into a huge automaton
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum NameMatch {
+    Substring(String),
+    Glob(String),
+    Regex(String),
+}
+
+/// Filters for `Request::Search`, every set field has to match
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchQuery {
+    pub name: Option<NameMatch>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// unix seconds
+    pub modified_after: Option<u64>,
+    pub modified_before: Option<u64>,
+    /// share must have all of these tags
+    pub tags: Vec<String>,
+    /// file extension without the dot
+    pub file_type: Option<String>,
+    pub offset: u64,
+    pub limit: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchHit {
+    pub name: String,
+    pub size: u64,
+    pub modified: u64,
+    pub tags: Vec<String>,
+}
+
+enum Matcher {
+    Substring(String),
+    Glob(glob::Pattern),
+    Regex(Regex),
+}
+
+impl Matcher {
+    fn new(name: &NameMatch) -> anyhow::Result<Self> {
+        Ok(match name {
+            NameMatch::Substring(s) => Matcher::Substring(s.to_lowercase()),
+            NameMatch::Glob(p) => Matcher::Glob(glob::Pattern::new(p)?),
+            NameMatch::Regex(r) => Matcher::Regex(RegexBuilder::new(r).size_limit(REGEX_SIZE_LIMIT).build()?),
+        })
+    }
+
+    fn is_match(&self, name: &str) -> bool {
+        match self {
+            Matcher::Substring(s) => name.to_lowercase().contains(s),
+            Matcher::Glob(p) => p.matches(name),
+            Matcher::Regex(r) => r.is_match(name),
+        }
+    }
+}
+
+/// Runs `query` against the shares and returns the total number of hits
+/// and the requested page, ordered by name
+pub async fn search_shares(
+    shares: Vec<(String, Share)>,
+    query: &SearchQuery,
+    max_limit: u64,
+) -> anyhow::Result<(u64, Vec<SearchHit>)> {
+    let matcher = query.name.as_ref().map(Matcher::new).transpose()?;
+
+    let mut hits = Vec::new();
+    for (name, share) in shares {
+        if matcher.as_ref().is_some_and(|m| !m.is_match(&name)) {
+            continue;
+        }
+        if !query.tags.iter().all(|t| share.tags.contains(t)) {
+            continue;
+        }
+        if let Some(ext) = &query.file_type {
+            let matches = share.path.extension().is_some_and(|e| e.eq_ignore_ascii_case(ext.as_str()));
+            if !matches {
+                continue;
+            }
+        }
+
+        // shares whose file is gone can't be downloaded, so they are not results
+        if !share.available {
+            continue;
+        }
+        let Ok(meta) = readable_metadata(&share).await else {
+            continue;
+        };
+        let size = meta.len();
+        let modified = meta.modified().ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+
+        if query.min_size.is_some_and(|min| size < min)
+            || query.max_size.is_some_and(|max| size > max)
+            || query.modified_after.is_some_and(|t| modified < t)
+            || query.modified_before.is_some_and(|t| modified > t)
+        {
+            continue;
+        }
+
+        hits.push(SearchHit { name, size, modified, tags: share.tags });
+    }
+
+    hits.sort_by(|a, b| a.name.cmp(&b.name));
+    let total = hits.len() as u64;
+    let limit = if query.limit == 0 { max_limit } else { query.limit.min(max_limit) };
+    let page = hits.into_iter()
+        .skip(query.offset as usize)
+        .take(limit as usize)
+        .collect();
+
+    Ok((total, page))
+}
+
+// metadata as the share's owner sees it, sizes and dates of files they can't read are not given away
+async fn readable_metadata(share: &Share) -> std::io::Result<std::fs::Metadata> {
+    match share.owner {
+        Some(_) => share.open().await?.metadata().await,
+        None => tokio::fs::metadata(&share.path).await,
+    }
+}
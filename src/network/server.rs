@@ -1,175 +1,1560 @@
 use std::sync::Arc;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
 use tokio::fs::File;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
+use tokio::io::{AsyncRead, AsyncSeekExt, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, RwLock};
 use tokio_rustls::server::TlsStream;
-use async_compression::tokio::write::ZstdEncoder;
+use tokio_rustls::TlsAcceptor;
+use anyhow::Context;
+use tracing::{debug, field, info, info_span, instrument, warn, Instrument, Span};
+use rand_core::{OsRng, RngCore};
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind, RenameMode};
+use socket2::{SockRef, TcpKeepalive};
+use serde::{Deserialize, Serialize};
 
-use crate::settings::{CERT_PATH, CHUNK_SIZE, KEY_PATH};
-use crate::network::{create_or_load_tls, recv_message, send_message, Request, Response};
-use crate::utils::{get_file_length, hash_file};
+use crate::settings::{
+    ACK_TIMEOUT_SECS, AUTH_TIMEOUT_SECS, CERT_PATH, CHUNK_SIZE, DELTA_BLOCK_SIZE, HANDSHAKE_TIMEOUT_SECS,
+    IDLE_TIMEOUT_SECS, KEEPALIVE_SECS, KEY_PATH, MAX_CHUNK_HASHES, MAX_DELTA_BLOCK_SIZE, MAX_SEARCH_RESULTS,
+    RELAY_RETRY_SECS, SHARE_EVENTS_CAPACITY
+};
+use crate::daemon::{
+    read_audit, AuditQuery, DaemonCommand, DaemonEvent, DaemonMessage, DaemonReply, DaemonResponse, DaemonStatus, ErrorKind, HandlerReply, Heartbeat, LinkInfo,
+    Owner, Responder, ShareInfo, TimedEvent
+};
+use crate::network::{
+    advertise, announce, cert_fingerprint, create_or_load_tls, diff, relay_listen, search_shares, serve_metrics,
+    Announcement, AuditLog, AuditOutcome, AuditRecord, BlockSignature, Codec, CodecError, Connection, DeltaOp, DiscoveryConfig,
+    Inbox, Request, Response, SearchHit, SearchQuery, ServerStats, ShareEntry, ShareEvent, ShareUri, ShareWatcher,
+    Transfer
+};
+use crate::utils::{
+    compress_chunk, create_private, get_file_length, hash_chunks, hash_reader, open_private, read_full, unix_now
+};
 
+/// Per-stage limits of a client session, `None` disables the limit
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub handshake: Option<Duration>,
+    pub auth: Option<Duration>,
+    /// time a client may stay silent between requests
+    pub idle: Option<Duration>,
+    /// time the server waits for each chunk ack during a download
+    pub ack: Option<Duration>,
+    /// TCP keepalive idle time on accepted sockets
+    pub keepalive: Option<Duration>,
+}
+
+impl Timeouts {
+    pub fn from_secs(handshake: u64, auth: u64, idle: u64, ack: u64, keepalive: u64) -> Self {
+        let secs = |n| (n > 0).then(|| Duration::from_secs(n));
+        Timeouts {
+            handshake: secs(handshake),
+            auth: secs(auth),
+            idle: secs(idle),
+            ack: secs(ack),
+            keepalive: secs(keepalive),
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts::from_secs(
+            HANDSHAKE_TIMEOUT_SECS,
+            AUTH_TIMEOUT_SECS,
+            IDLE_TIMEOUT_SECS,
+            ACK_TIMEOUT_SECS,
+            KEEPALIVE_SECS,
+        )
+    }
+}
+
+/// A shared file and its metadata
+#[derive(Debug, Clone)]
+pub struct Share {
+    pub path: PathBuf,
+    pub tags: Vec<String>,
+    /// blake3 of the file and the mtime it was computed at
+    pub hash: Option<(SystemTime, String)>,
+    /// false once the file disappeared from disk, until it shows up again
+    pub available: bool,
+    /// local user the file is opened as, none for the daemon's own shares
+    pub owner: Option<Owner>,
+}
+
+impl Share {
+    /// Opens the file with the access of the share's owner
+    pub async fn open(&self) -> std::io::Result<File> {
+        let (path, owner) = (self.path.clone(), self.owner.clone());
+        let file = tokio::task::spawn_blocking(move || match owner {
+            Some(owner) => owner.open(&path),
+            None => std::fs::File::open(&path),
+        }).await??;
+        Ok(File::from_std(file))
+    }
+
+    /// Cached hash, as long as the file still has the mtime it was taken at
+    pub fn hash_at(&self, mtime: SystemTime) -> Option<&str> {
+        self.hash.as_ref().filter(|(cached_at, _)| *cached_at == mtime).map(|(_, hash)| hash.as_str())
+    }
+}
+
+/// What an authenticated client is allowed to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    User,
+    /// a user that may also upload into the inbox
+    Uploader,
+    Admin,
+    /// came in with a link token, may only download the content with this hash
+    Link(String),
+}
+
+impl Role {
+    /// As logged in the `user` field of a connection
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Uploader => "uploader",
+            Role::Admin => "admin",
+            Role::Link(_) => "link",
+        }
+    }
+}
+
+// cheap to clone, every connection task gets its own copy
+#[derive(Clone)]
 pub struct Server {
     password: Option<String>,
-    files: Arc<RwLock<HashMap<String, PathBuf>>>,
+    admin_password: Option<String>,
+    upload_password: Option<String>,
+    port: u16,
+    // bound elsewhere, e.g. passed in by systemd
+    listener: Option<Arc<std::net::TcpListener>>,
+    files: Arc<RwLock<HashMap<String, Share>>>,
+    // blake3 of a link token -> what it gives access to
+    tokens: Arc<RwLock<HashMap<String, LinkToken>>>,
+    // blake3 -> path of one share with that content
+    by_hash: Arc<RwLock<HashMap<String, PathBuf>>>,
+    // shares a background task is hashing right now
+    hashing: Arc<std::sync::Mutex<HashSet<String>>>,
+    codec: Codec,
+    timeouts: Timeouts,
+    inbox: Option<Arc<Inbox>>,
+    admin_tx: Option<mpsc::Sender<DaemonMessage>>,
+    // canonical directory remote admins add files from, and whose access they are read with
+    admin_root: Option<(PathBuf, Option<Owner>)>,
+    announce: Option<(DiscoveryConfig, String)>,
+    mdns_name: Option<String>,
+    // relay address and session id
+    relay: Option<(String, String)>,
+    events: broadcast::Sender<ShareEvent>,
+    // set up by `run`, shares added before that are registered then
+    watcher: Arc<std::sync::Mutex<Option<ShareWatcher>>>,
+    stats: Arc<ServerStats>,
+    // set up by `run`, replaced on reload while established sessions keep the old one
+    tls: Arc<std::sync::RwLock<Option<Arc<TlsAcceptor>>>>,
+    registry: Option<Arc<Registry>>,
+    audit: Option<Arc<AuditLog>>,
+    metrics_port: Option<u16>,
+}
+
+// file the shares are kept in, written by one task at a time
+struct Registry {
+    path: PathBuf,
+    write: tokio::sync::Mutex<()>,
+}
+
+// what the registry file holds
+#[derive(Serialize, Deserialize, Default)]
+struct RegistryData {
+    shares: Vec<RegistryEntry>,
+    #[serde(default)]
+    links: Vec<LinkToken>,
+}
+
+// a share link as the server keeps it, only the link's holder knows the token itself
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LinkToken {
+    // blake3 of the token
+    digest: String,
+    // content the link gives access to, so renaming the share doesn't break it
+    hash: String,
+    // share the link was made for, only shown to the owner
+    name: String,
+    // unix seconds
+    expires: Option<u64>,
+}
+
+impl LinkToken {
+    fn expired(&self) -> bool {
+        self.expires.is_some_and(|expires| expires <= unix_now())
+    }
+
+    fn info(&self) -> LinkInfo {
+        LinkInfo { id: self.digest[..16].to_string(), name: self.name.clone(), hash: self.hash.clone(), expires: self.expires }
+    }
+}
+
+/// A share as stored in the registry file
+#[derive(Serialize, Deserialize)]
+struct RegistryEntry {
+    name: String,
+    path: PathBuf,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    owner: Option<Owner>,
 }
 
 impl Server {
     pub fn new(password: Option<String>) -> Self {
         Server {
             password,
-            files: Arc::new(RwLock::new(HashMap::new()))
+            admin_password: None,
+            upload_password: None,
+            port: 0,
+            listener: None,
+            files: Arc::new(RwLock::new(HashMap::new())),
+            tokens: Arc::new(RwLock::new(HashMap::new())),
+            by_hash: Arc::new(RwLock::new(HashMap::new())),
+            hashing: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            codec: Codec::default(),
+            timeouts: Timeouts::default(),
+            inbox: None,
+            admin_tx: None,
+            admin_root: None,
+            announce: None,
+            mdns_name: None,
+            relay: None,
+            events: broadcast::channel(SHARE_EVENTS_CAPACITY).0,
+            watcher: Arc::new(std::sync::Mutex::new(None)),
+            stats: Arc::new(ServerStats::default()),
+            tls: Arc::new(std::sync::RwLock::new(None)),
+            registry: None,
+            audit: None,
+            metrics_port: None,
         }
     }
 
-    pub async fn run(&self, port: u16) -> anyhow::Result<()> {
-        let addr = format!("0.0.0.0:{port}");
-        let listener = TcpListener::bind(addr).await?;
-        let acceptor = Arc::from(create_or_load_tls(CERT_PATH, KEY_PATH)?);
+    /// Serves Prometheus metrics on `http://127.0.0.1:port/metrics`
+    pub fn with_metrics(mut self, port: u16) -> Self {
+        self.metrics_port = Some(port);
+        self
+    }
+
+    /// Records auth attempts, downloads and remote admin commands in the audit log at `path`
+    pub fn with_audit(mut self, path: impl Into<PathBuf>) -> Self {
+        self.audit = Some(Arc::new(AuditLog::new(path)));
+        self
+    }
 
-        loop {
-            let (socket, peer) = listener.accept().await?;
-            println!("New connection: {peer}");
+    /// Keeps the shares in `path`, they are loaded by `run` and saved on every change
+    pub fn with_registry(mut self, path: impl Into<PathBuf>) -> Self {
+        self.registry = Some(Arc::new(Registry { path: path.into(), write: tokio::sync::Mutex::new(()) }));
+        self
+    }
+
+    /// Port `run` listens on
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    /// Accepts clients on `listener` instead of binding the port
+    pub fn with_listener(mut self, listener: std::net::TcpListener) -> Self {
+        self.listener = Some(Arc::new(listener));
+        self
+    }
+
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Accept uploads into `inbox`, without one uploads are refused.
+    /// Only admins and clients that authenticate with `upload_password` may upload
+    pub fn with_inbox(mut self, inbox: Inbox, upload_password: Option<String>) -> Self {
+        self.inbox = Some(Arc::new(inbox));
+        self.upload_password = upload_password;
+        self
+    }
+
+    /// Clients that authenticate with `password` get the admin role and can manage
+    /// shares remotely, their commands go through `tx` like local daemon commands
+    pub fn with_admin(mut self, password: String, tx: mpsc::Sender<DaemonMessage>) -> Self {
+        self.admin_password = Some(password);
+        self.admin_tx = Some(tx);
+        self
+    }
+
+    /// Lets remote admins add the files under the canonical directory `root`, opened as `owner`
+    pub fn with_admin_root(mut self, root: PathBuf, owner: Option<Owner>) -> Self {
+        self.admin_root = Some((root, owner));
+        self
+    }
 
-            let acceptor = Arc::clone(&acceptor);
-            let password = self.password.clone();
-            let files = Arc::clone(&self.files);
+    /// Multicast the listener under `name` so `client discover` can find it
+    pub fn with_announce(mut self, config: DiscoveryConfig, name: String) -> Self {
+        self.announce = Some((config, name));
+        self
+    }
 
+    /// Advertise the listener as a `_fileshare._tcp` DNS-SD service called `name`
+    pub fn with_mdns(mut self, name: String) -> Self {
+        self.mdns_name = Some(name);
+        self
+    }
+
+    /// Also take clients through `relay`, registered under `session`,
+    /// for peers that can't reach the listener directly
+    pub fn with_relay(mut self, relay: String, session: String) -> Self {
+        self.relay = Some((relay, session));
+        self
+    }
+
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let port = self.port;
+        let listener = match &self.listener {
+            Some(listener) => {
+                let listener = listener.try_clone()?;
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(format!("0.0.0.0:{port}")).await?,
+        };
+        self.load_tls()?;
+
+        if let Some((config, name)) = &self.announce {
+            let announcement = Announcement {
+                name: name.clone(),
+                port,
+                fingerprint: cert_fingerprint(CERT_PATH)?,
+                auth_required: self.password.is_some(),
+            };
+            let config = *config;
             tokio::spawn(async move {
-                let tls_stream: TlsStream<_> = match acceptor.accept(socket).await {
-                    Ok(stream) => stream,
-                    Err(err) => {
-                        eprintln!("TLS handshake failed for {peer}: {err}");
+                if let Err(e) = announce(config, announcement).await {
+                    warn!("Announcing stopped: {e}");
+                }
+            });
+        }
+
+        // kept alive for as long as the listener runs
+        let _mdns = match &self.mdns_name {
+            Some(name) => {
+                let fingerprint = cert_fingerprint(CERT_PATH)?;
+                Some(advertise(name, port, &fingerprint, self.password.is_some())?)
+            }
+            None => None,
+        };
+
+        self.start_watching().await;
+        self.load_registry().await?;
+
+        if let Some(port) = self.metrics_port {
+            let listener = TcpListener::bind(("127.0.0.1", port)).await
+                .with_context(|| format!("binding the metrics port {port}"))?;
+            tokio::spawn(serve_metrics(listener, Arc::clone(&self.stats)));
+        }
+
+        if let Some((relay, session)) = self.relay.clone() {
+            let server = self.clone();
+            tokio::spawn(async move { server.run_relayed(relay, session).await });
+        }
+
+        let mut heartbeat = Heartbeat::register();
+        loop {
+            let (socket, peer) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = heartbeat.tick() => continue,
+            };
+            info!("New connection: {peer}");
+
+            if let Some(keepalive) = self.timeouts.keepalive {
+                if let Err(e) = set_keepalive(&socket, keepalive) {
+                    warn!("Failed to enable keepalive for {peer}: {e}");
+                }
+            }
+
+            self.serve(socket, peer);
+        }
+    }
+
+    /// Re-reads the TLS certificate and the registry. Connected clients keep their sessions
+    /// and transfers, removed shares only stop being offered
+    pub async fn reload(&self) -> anyhow::Result<()> {
+        self.load_tls()?;
+        self.load_registry().await
+    }
+
+    fn load_tls(&self) -> anyhow::Result<()> {
+        let acceptor = create_or_load_tls(CERT_PATH, KEY_PATH)?;
+        *self.tls.write().unwrap() = Some(Arc::new(acceptor));
+        Ok(())
+    }
+
+    // waits on the relay for one client at a time and registers again once paired
+    async fn run_relayed(&self, relay: String, session: String) {
+        loop {
+            match relay_listen(&relay, &session).await {
+                Ok(socket) => {
+                    let peer = socket.peer_addr().unwrap_or_else(|_| SocketAddr::from(([0, 0, 0, 0], 0)));
+                    info!("New connection through relay {relay}");
+                    self.serve(socket, peer);
+                }
+                Err(e) => {
+                    warn!("Relay {relay} failed: {e}");
+                    tokio::time::sleep(Duration::from_secs(RELAY_RETRY_SECS)).await;
+                }
+            }
+        }
+    }
+
+    // TLS handshake and client session on their own task
+    fn serve(&self, socket: TcpStream, peer: SocketAddr) {
+        let Some(acceptor) = self.tls.read().unwrap().clone() else {
+            return;
+        };
+        let server = self.clone();
+        self.stats.counters.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        // everything logged for this client carries its address and, once known, its role
+        let span = info_span!("conn", %peer, user = field::Empty);
+
+        tokio::spawn(async move {
+            let handshake = acceptor.accept(socket);
+            let handshake = match server.timeouts.handshake {
+                Some(t) => match tokio::time::timeout(t, handshake).await {
+                    Ok(res) => res,
+                    Err(_) => {
+                        info!("Closing connection: TLS handshake timed out");
+                        server.stats.counters.connections_rejected.fetch_add(1, Ordering::Relaxed);
                         return;
                     }
-                };
+                },
+                None => handshake.await,
+            };
+            let tls_stream: TlsStream<_> = match handshake {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("TLS handshake failed: {err}");
+                    server.stats.counters.connections_rejected.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+            };
 
-                if let Err(e) = Self::handle_client(tls_stream, password, files).await {
-                    eprintln!("Error handling client {peer}: {e}");
+            match server.handle_client(tls_stream, peer).await {
+                Ok(()) => info!("Client disconnected"),
+                Err(e) => match e.downcast_ref::<CodecError>() {
+                    Some(CodecError::Disconnected) => info!("Client disconnected"),
+                    Some(CodecError::Timeout) => info!("Closing connection: {e:#}"),
+                    _ => warn!("Error handling client: {e:#}"),
+                },
+            }
+        }.instrument(span));
+    }
+
+    /// Shares `path` as `name`, it is hashed in the background so it can also be fetched by content.
+    /// With an owner the file is only ever read with their access
+    pub async fn add_file(&self, name: String, path: PathBuf, owner: Option<Owner>) {
+        self.insert_share(name.clone(), path, Vec::new(), owner).await;
+        self.save_registry().await;
+        self.hash_in_background(vec![name]);
+    }
+
+    // registered without a hash, `hash_in_background` or the first download fills it in
+    async fn insert_share(&self, name: String, path: PathBuf, tags: Vec<String>, owner: Option<Owner>) {
+        let mut share = Share { path, tags, hash: None, available: false, owner };
+        share.available = share.open().await.is_ok();
+
+        let mut files = self.files.write().await;
+        self.watch(&share.path);
+        let old = files.insert(name.clone(), share);
+        if let Some(old) = &old {
+            self.unwatch(&old.path);
+        }
+        drop(files);
+        if let Some(hash) = old.and_then(|share| share.hash) {
+            self.reindex(&hash.1).await;
+        }
+        self.emit(ShareEvent::Added { name });
+    }
+
+    // hashes the shares one after the other, large files would hold up the caller for long
+    fn hash_in_background(&self, mut names: Vec<String>) {
+        // a share already being hashed is not hashed twice at once
+        names.retain(|name| self.hashing.lock().unwrap().insert(name.clone()));
+        if names.is_empty() {
+            return;
+        }
+        let server = self.clone();
+        tokio::spawn(async move {
+            for name in names {
+                if let Err(e) = server.share_hash(&name).await {
+                    warn!("Failed to hash '{name}': {e}");
                 }
-            });
+                server.hashing.lock().unwrap().remove(&name);
+            }
+        });
+    }
+
+    pub async fn remove_file(&self, name: &str) {
+        self.drop_share(name).await;
+        self.save_registry().await;
+    }
+
+    async fn drop_share(&self, name: &str) {
+        let mut files = self.files.write().await;
+        let Some(old) = files.remove(name) else {
+            return;
+        };
+        self.unwatch(&old.path);
+        drop(files);
+        if let Some((_, hash)) = old.hash {
+            self.reindex(&hash).await;
         }
+        self.emit(ShareEvent::Removed { name: name.to_string() });
     }
 
-    pub async fn add_file(&self, name: String, path: PathBuf) {
+    /// Returns false if there is no share called `name` or `new_name` is taken.
+    /// The content index is keyed by path, so it is not affected
+    pub async fn rename_file(&self, name: &str, new_name: String) -> bool {
         let mut files = self.files.write().await;
-        files.insert(name, path);
+        if files.contains_key(&new_name) {
+            return false;
+        }
+        let Some(share) = files.remove(name) else {
+            return false;
+        };
+        files.insert(new_name.clone(), share);
+        drop(files);
+        self.emit(ShareEvent::Renamed { name: name.to_string(), new_name });
+        self.save_registry().await;
+        true
     }
 
-    pub async fn remove_file(&self, name: &str) {
+    /// Replaces the tags of a share, returns false if it doesn't exist
+    pub async fn set_tags(&self, name: &str, tags: Vec<String>) -> bool {
         let mut files = self.files.write().await;
-        files.remove(name);
+        let Some(share) = files.get_mut(name) else {
+            return false;
+        };
+        share.tags = tags;
+        drop(files);
+        self.save_registry().await;
+        true
+    }
+
+    // writes all shares to the registry through a temporary file, so it is never half written.
+    // The file is created fresh, whatever is lying around under its name is not written through
+    async fn save_registry(&self) {
+        let Some(registry) = &self.registry else {
+            return;
+        };
+        let _write = registry.write.lock().await;
+        let mut shares = self.files.read().await
+            .iter()
+            .map(|(name, share)| RegistryEntry {
+                name: name.clone(),
+                path: share.path.clone(),
+                tags: share.tags.clone(),
+                owner: share.owner.clone(),
+            })
+            .collect::<Vec<_>>();
+        shares.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut links = self.tokens.read().await.values().filter(|link| !link.expired()).cloned().collect::<Vec<_>>();
+        links.sort_by(|a, b| a.digest.cmp(&b.digest));
+        let data = RegistryData { shares, links };
+
+        let path = registry.path.clone();
+        let res = tokio::task::spawn_blocking(move || {
+            let tmp = path.with_extension("tmp");
+            // left over from a save that didn't finish
+            match std::fs::remove_file(&tmp) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            let mut file = create_private(&tmp)?;
+            file.write_all(&serde_json::to_vec_pretty(&data)?)?;
+            file.sync_all()?;
+            std::fs::rename(&tmp, &path)?;
+            anyhow::Ok(())
+        }).await;
+        if let Err(e) = res.map_err(anyhow::Error::from).and_then(|res| res) {
+            warn!("Failed to save shares to {}: {e:#}", registry.path.display());
+        }
+    }
+
+    // brings the shares in line with the registry: listed ones are added or updated, others removed.
+    // Nothing changes while there is no registry file yet, one someone else could have written is refused
+    async fn load_registry(&self) -> anyhow::Result<()> {
+        let Some(registry) = &self.registry else {
+            return Ok(());
+        };
+        let path = registry.path.clone();
+        let data = tokio::task::spawn_blocking(move || {
+            let mut data = Vec::new();
+            open_private(&path, std::fs::OpenOptions::new().read(true))?.read_to_end(&mut data)?;
+            std::io::Result::Ok(data)
+        }).await?;
+        let data = match data {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(anyhow::Error::from(e).context(format!("can't load {}", registry.path.display()))),
+        };
+        // registries of older builds are a bare list of shares
+        let RegistryData { shares: entries, links } = serde_json::from_slice(&data)
+            .or_else(|_| serde_json::from_slice(&data).map(|shares| RegistryData { shares, links: Vec::new() }))
+            .with_context(|| format!("invalid registry {}", registry.path.display()))?;
+        *self.tokens.write().await = links.into_iter()
+            .filter(|link| !link.expired())
+            .map(|link| (link.digest.clone(), link))
+            .collect();
+
+        let current = self.files.read().await
+            .iter()
+            .map(|(name, share)| (name.clone(), (share.path.clone(), share.tags.clone(), share.owner.clone())))
+            .collect::<HashMap<_, _>>();
+        for name in current.keys() {
+            if !entries.iter().any(|entry| &entry.name == name) {
+                self.drop_share(name).await;
+            }
+        }
+        let mut added = Vec::new();
+        for RegistryEntry { name, path, tags, owner } in entries {
+            match current.get(&name) {
+                Some((old_path, old_tags, old_owner)) if *old_path == path && *old_owner == owner => {
+                    if *old_tags != tags {
+                        if let Some(share) = self.files.write().await.get_mut(&name) {
+                            share.tags = tags;
+                        }
+                    }
+                }
+                _ => {
+                    self.insert_share(name.clone(), path, tags, owner).await;
+                    added.push(name);
+                }
+            }
+        }
+        self.hash_in_background(added);
+        info!("Loaded {} shares from {}", self.files.read().await.len(), registry.path.display());
+        Ok(())
+    }
+
+    pub async fn search(&self, query: &SearchQuery) -> anyhow::Result<(u64, Vec<SearchHit>)> {
+        let shares = self.files.read().await
+            .iter()
+            .map(|(name, share)| (name.clone(), share.clone()))
+            .collect();
+        search_shares(shares, query, MAX_SEARCH_RESULTS).await
+    }
+
+    /// Hash of a share, recomputed only when the file changed since the last call
+    pub async fn share_hash(&self, name: &str) -> anyhow::Result<String> {
+        let (share, file, mtime) = self.open_share(name).await?;
+        if let Some(hash) = share.hash_at(mtime) {
+            self.stats.hash_lookup(true);
+            return Ok(hash.to_string());
+        }
+        self.stats.hash_lookup(false);
+
+        let hash = hash_reader(file).await?;
+        // the share may have been replaced by one of another file meanwhile
+        let (old, was_available) = match self.files.write().await.get_mut(name) {
+            Some(current) if current.path == share.path && current.owner == share.owner => {
+                (current.hash.replace((mtime, hash.clone())), std::mem::replace(&mut current.available, true))
+            }
+            _ => return Ok(hash),
+        };
+        if let Some((_, old)) = old {
+            self.reindex(&old).await;
+        }
+        self.reindex(&hash).await;
+        if !was_available {
+            self.emit(ShareEvent::Available { name: name.to_string() });
+        }
+        Ok(hash)
+    }
+
+    // the share opened as its owner and the mtime of that handle,
+    // a stat with the daemon's access would see files the owner can't
+    async fn open_share(&self, name: &str) -> anyhow::Result<(Share, File, SystemTime)> {
+        let Some(share) = self.files.read().await.get(name).cloned() else {
+            anyhow::bail!("File not found");
+        };
+        let file = share.open().await?;
+        let mtime = file.metadata().await?.modified()?;
+        Ok((share, file, mtime))
+    }
+
+    /// A share whose content hashes to `hash`, looked up in the content index
+    pub async fn find_by_hash(&self, hash: &str) -> Option<(String, Share)> {
+        let hash = hash.to_ascii_lowercase();
+        loop {
+            let path = self.by_hash.read().await.get(&hash)?.clone();
+            let (name, share) = self.files.read().await.iter()
+                .find(|(_, share)| share.path == path && share.hash.as_ref().is_some_and(|(_, h)| *h == hash))
+                .map(|(name, share)| (name.clone(), share.clone()))?;
+
+            // rehashing a changed file drops it from this entry,
+            // so the next round tries another share or gives up
+            match self.share_hash(&name).await {
+                Ok(h) if h == hash => return Some((name, share)),
+                Ok(_) => continue,
+                Err(_) => {
+                    self.mark_unavailable(&name).await;
+                    continue;
+                }
+            }
+        }
     }
 
-    pub async fn list_files(&self) -> HashMap<String, String> {
+    // points the index entry for `hash` at a share that still has that content,
+    // or drops it when none is left
+    async fn reindex(&self, hash: &str) {
         let files = self.files.read().await;
-        files.iter()
-            .map(|(k, v)| (k.clone(), v.to_string_lossy().to_string()))
-            .collect()
+        let mut paths = files.values()
+            .filter(|share| share.hash.as_ref().is_some_and(|(_, h)| h == hash))
+            .map(|share| &share.path);
+
+        let mut index = self.by_hash.write().await;
+        if index.get(hash).is_some_and(|current| paths.clone().any(|p| p == current)) {
+            return;
+        }
+        match paths.next() {
+            Some(path) => index.insert(hash.to_string(), path.clone()),
+            None => index.remove(hash),
+        };
     }
 
-    async fn handle_client<S>(
-        mut socket: S,
-        password: Option<String>,
-        files: Arc<RwLock<HashMap<String, PathBuf>>>
-    ) -> anyhow::Result<()>
+    /// Link to share `name` as reached at `host`, with a new token that lets its holder
+    /// download just that content for `expires` seconds, or until it is revoked
+    pub async fn share_link(&self, name: &str, host: &str, expires: Option<u64>) -> anyhow::Result<ShareUri> {
+        if !self.files.read().await.contains_key(name) {
+            anyhow::bail!("File '{name}' not found");
+        }
+        // hashing a large file here would hold up whoever asked for the link
+        let (share, _, mtime) = self.open_share(name).await.with_context(|| format!("File '{name}' unavailable"))?;
+        let Some(hash) = share.hash_at(mtime).map(str::to_string) else {
+            self.hash_in_background(vec![name.to_string()]);
+            anyhow::bail!("Hash of '{name}' is pending, try again in a moment");
+        };
+
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        let token = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        let link = LinkToken {
+            digest: blake3::hash(token.as_bytes()).to_hex().to_string(),
+            hash,
+            name: name.to_string(),
+            expires: expires.map(|secs| unix_now().saturating_add(secs)),
+        };
+        self.tokens.write().await.insert(link.digest.clone(), link);
+        self.save_registry().await;
+
+        Ok(ShareUri {
+            host: host.to_string(),
+            port: self.port,
+            share: name.to_string(),
+            fingerprint: cert_fingerprint(CERT_PATH)?,
+            token: Some(token),
+        })
+    }
+
+    /// Records of the audit log that pass `query`, oldest first
+    pub async fn audit_records(&self, query: AuditQuery) -> anyhow::Result<Vec<AuditRecord>> {
+        let Some(audit) = self.audit.clone() else {
+            anyhow::bail!("This daemon keeps no audit log");
+        };
+        let records = tokio::task::spawn_blocking(move || read_audit(audit.path(), &query)).await?;
+        match records {
+            Ok(records) => Ok(records),
+            // nothing was recorded yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e).context("Can't read the audit log"),
+        }
+    }
+
+    /// Share links that haven't expired, ordered by id
+    pub async fn links(&self) -> Vec<LinkInfo> {
+        let mut links = self.tokens.read().await.values()
+            .filter(|link| !link.expired())
+            .map(LinkToken::info)
+            .collect::<Vec<_>>();
+        links.sort_by(|a, b| a.id.cmp(&b.id));
+        links
+    }
+
+    /// Makes the link with `id` stop working, false if there is none
+    pub async fn revoke_link(&self, id: &str) -> bool {
+        let removed = {
+            let mut tokens = self.tokens.write().await;
+            let before = tokens.len();
+            tokens.retain(|digest, _| digest[..16] != *id);
+            tokens.len() < before
+        };
+        if removed {
+            self.save_registry().await;
+        }
+        removed
+    }
+
+    // content hash a link token gives access to, none for unknown and expired tokens
+    async fn link_hash(&self, token: &str) -> Option<String> {
+        let digest = blake3::hash(token.as_bytes()).to_hex().to_string();
+        self.tokens.read().await.get(&digest).filter(|link| !link.expired()).map(|link| link.hash.clone())
+    }
+
+    /// Names and content hashes of all shares, ordered by name
+    pub async fn list_entries(&self) -> Vec<ShareEntry> {
+        let mut names = self.files.read().await.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        let mut entries = Vec::with_capacity(names.len());
+        for name in names {
+            let hash = self.share_hash(&name).await.ok();
+            let available = self.files.read().await.get(&name).is_some_and(|share| share.available);
+            entries.push(ShareEntry { name, hash, available });
+        }
+        entries
+    }
+
+    /// Names, paths and availability of all shares, ordered by name
+    pub async fn list_files(&self) -> Vec<ShareInfo> {
+        let files = self.files.read().await;
+        let mut list = files.iter()
+            .map(|(name, share)| ShareInfo {
+                name: name.clone(),
+                path: share.path.to_string_lossy().into_owned(),
+                available: share.available,
+            })
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Connections, transfers and share changes from now on, as `daemon watch` shows them
+    pub fn activity(&self) -> broadcast::Receiver<TimedEvent> {
+        self.stats.subscribe()
+    }
+
+    /// What `daemon status` reports about this server
+    pub async fn status(&self) -> DaemonStatus {
+        let local = self.listener.as_ref().and_then(|listener| listener.local_addr().ok());
+        let mut addresses = vec![local.map_or_else(|| format!("0.0.0.0:{}", self.port), |addr| addr.to_string())];
+        if let Some((relay, session)) = &self.relay {
+            addresses.push(format!("relay {relay} session '{session}'"));
+        }
+        DaemonStatus {
+            pid: std::process::id(),
+            uptime_secs: self.stats.uptime_secs(),
+            addresses,
+            fingerprint: cert_fingerprint(CERT_PATH).ok(),
+            shares: Some(self.files.read().await.len()),
+            sync_jobs: None,
+            connections: self.stats.connections(),
+            transfers: self.stats.transfers(),
+            bytes_transferred: self.stats.bytes_served(),
+        }
+    }
+
+    /// Runs one client session on an established stream, from auth until it quits
+    pub async fn handle_client<S>(&self, mut socket: S, peer: SocketAddr) -> anyhow::Result<()>
     where S: AsyncRead + AsyncWrite + Unpin
     {
-        let req: Request = recv_message(&mut socket).await?;
-        match req {
-            Request::Auth(pass) if pass == password => {
-                send_message(&mut socket, &Response::AuthOk).await?;
-            }
+        let Server { files, codec, timeouts, inbox, .. } = self;
+        let codec = *codec;
+        let timeouts = *timeouts;
+        let mut conn = self.stats.connect(peer, self.audit.clone());
+
+        let req: Request = codec.with_read_timeout(timeouts.auth)
+            .recv(&mut socket).await
+            .context("waiting for auth")?;
+        let (role, token) = match req {
+            Request::Auth(pass) if self.admin_password.is_some() && pass == self.admin_password => (Role::Admin, None),
+            Request::Auth(pass) if self.upload_password.is_some() && pass == self.upload_password => (Role::Uploader, None),
+            Request::Auth(pass) if pass == self.password => (Role::User, None),
+            Request::AuthToken(token) => match self.link_hash(&token).await {
+                Some(hash) => (Role::Link(hash), Some(token_id(&token))),
+                None => {
+                    conn.authenticated(None, Some(token_id(&token)));
+                    codec.send(&mut socket, &Response::AuthErr).await?;
+                    return Ok(());
+                }
+            },
             _ => {
-                send_message(&mut socket, &Response::AuthErr).await?;
+                conn.authenticated(None, None);
+                codec.send(&mut socket, &Response::AuthErr).await?;
                 return Ok(());
             }
-        }
+        };
+        conn.authenticated(Some(role.name()), token);
+        codec.send(&mut socket, &Response::AuthOk).await?;
+        Span::current().record("user", field::display(role.name()));
+        debug!("Authenticated");
 
         loop {
-            let req: Request = match recv_message(&mut socket).await {
-                Ok(msg) => msg,
-                Err(_) => {
-                    println!("Client disconnected");
-                    return Ok(());
+            let req: Request = codec.with_read_timeout(timeouts.idle)
+                .recv(&mut socket).await
+                .context("idle")?;
+
+            // a link names the share it was made for, but the content it was made for is what it gets
+            let req = match (&role, req) {
+                (Role::Link(hash), Request::Download { offset, .. }) => Request::DownloadByHash { hash: hash.clone(), offset },
+                (Role::Link(hash), Request::DownloadDelta { block_size, signatures, .. }) => {
+                    let Some((name, _)) = self.find_by_hash(hash).await else {
+                        codec.send(&mut socket, &Response::Error("File not found".into())).await?;
+                        continue;
+                    };
+                    Request::DownloadDelta { name, block_size, signatures }
+                }
+                (Role::Link(_), req @ (Request::Ping | Request::Quit)) => req,
+                (Role::Link(_), _) => {
+                    codec.send(&mut socket, &Response::Error("Permission denied".into())).await?;
+                    continue;
                 }
+                (_, req) => req,
             };
 
             match req {
                 Request::List => {
-                    let files = files.read().await.keys().cloned().collect::<Vec<String>>();
-                    send_message(&mut socket, &Response::List(files)).await?;
+                    codec.send(&mut socket, &Response::List(self.list_entries().await)).await?;
+                }
+                Request::Search(query) => {
+                    let resp = match self.search(&query).await {
+                        Ok((total, hits)) => Response::SearchResults { total, hits },
+                        Err(e) => Response::Error(format!("Invalid search: {e}")),
+                    };
+                    codec.send(&mut socket, &resp).await?;
                 }
                 Request::Download { name, offset } => {
                     // find file
-                    let read_files = files.read().await;
-                    let Some(path) = read_files.get(&name) else {
-                        send_message(&mut socket, &Response::Error("File not found".into())).await?;
+                    let share = files.read().await.get(&name).cloned();
+                    let Some(share) = share else {
+                        codec.send(&mut socket, &Response::Error("File not found".into())).await?;
                         continue;
                     };
 
-                    // try to open file
-                    let Ok(mut file) = File::open(path).await else {
-                        let mut files = files.write().await;
-                        files.remove(&name);
-                        send_message(&mut socket, &Response::Error("Error opening file".into())).await?;
+                    let Ok(hash) = self.share_hash(&name).await else {
+                        self.mark_unavailable(&name).await;
+                        codec.send(&mut socket, &Response::Error("File unavailable".into())).await?;
+                        continue;
+                    };
+                    self.send_file(&mut socket, &conn, &name, &share, hash, offset).await?;
+                }
+                Request::DownloadByHash { hash, offset } => {
+                    let Some((name, share)) = self.find_by_hash(&hash).await else {
+                        codec.send(&mut socket, &Response::Error("File not found".into())).await?;
+                        continue;
+                    };
+                    self.send_file(&mut socket, &conn, &name, &share, hash.to_ascii_lowercase(), offset).await?;
+                }
+                Request::DownloadDelta { name, block_size, signatures } => {
+                    if !(DELTA_BLOCK_SIZE..=MAX_DELTA_BLOCK_SIZE).contains(&block_size) {
+                        codec.send(&mut socket, &Response::Error("Invalid block size".into())).await?;
+                        continue;
+                    }
+                    let share = files.read().await.get(&name).cloned();
+                    let Some(share) = share else {
+                        codec.send(&mut socket, &Response::Error("File not found".into())).await?;
+                        continue;
+                    };
+                    let Ok(hash) = self.share_hash(&name).await else {
+                        self.mark_unavailable(&name).await;
+                        codec.send(&mut socket, &Response::Error("File unavailable".into())).await?;
+                        continue;
+                    };
+                    self.send_delta(&mut socket, &conn, &name, &share, hash, block_size, signatures).await?;
+                }
+                Request::ChunkHashes { hash, start, count } => {
+                    let Some((_, share)) = self.find_by_hash(&hash).await else {
+                        codec.send(&mut socket, &Response::Error("File not found".into())).await?;
+                        continue;
+                    };
+                    let Ok(file) = share.open().await else {
+                        codec.send(&mut socket, &Response::Error("Error opening file".into())).await?;
                         continue;
                     };
 
-                    send_message(
+                    let count = count.min(MAX_CHUNK_HASHES);
+                    let resp = Response::ChunkHashes {
+                        size: get_file_length(&file).await?,
+                        chunk_size: CHUNK_SIZE as u64,
+                        start,
+                        hashes: hash_chunks(file, start, count).await?,
+                    };
+                    codec.send(&mut socket, &resp).await?;
+                }
+                Request::DownloadRange { hash, start, count } => {
+                    let Some((name, share)) = self.find_by_hash(&hash).await else {
+                        codec.send(&mut socket, &Response::Error("File not found".into())).await?;
+                        continue;
+                    };
+                    let Ok(mut file) = share.open().await else {
+                        codec.send(&mut socket, &Response::Error("Error opening file".into())).await?;
+                        continue;
+                    };
+
+                    let size = get_file_length(&file).await?;
+                    let offset = start.saturating_mul(CHUNK_SIZE as u64);
+                    let range = count.saturating_mul(CHUNK_SIZE as u64).min(size.saturating_sub(offset));
+                    let transfer = conn.transfer(&name, &hash, offset, 0, range);
+                    let span = info_span!("range", share = %name, start, count);
+                    codec.send(
                         &mut socket,
-                        &Response::FileInfo {
-                            name: name.clone(),
-                            size: get_file_length(&file).await?,
-                            hash: hash_file(&path).await?,
-                            chunk_size: CHUNK_SIZE as u64,
-                        }
+                        &Response::FileInfo { name, size, hash, chunk_size: CHUNK_SIZE as u64 }
                     ).await?;
 
                     file.seek(std::io::SeekFrom::Start(offset)).await?;
-                    let mut encoder = ZstdEncoder::new(&mut socket);
-                    
-                    let mut buf = vec![0u8; CHUNK_SIZE];
-                    let mut index: u64 = offset / CHUNK_SIZE as u64;
-
-                    loop {
-                        let n = file.read(&mut buf).await?;
-                        if n == 0 { break; }
-
-                        let chunk = Response::Chunck { index, data: buf[..n].to_vec() };
-                        send_message(&mut encoder, &chunk).await?;
-
-                        index += 1;
-
-                        match recv_message::<Request, _>(&mut encoder).await {
-                            Ok(Request::Ack { index: ack_idx }) if ack_idx == index - 1 => {}
-                            Ok(Request::Ack { .. }) => {
-                                eprintln!("Client ack mismatch, stopping transfer");
-                                break;
-                            }
-                            _ => {
-                                eprintln!("Client did not ack properly");
-                                break;
-                            }
-                        }
+                    self.send_chunks(&mut socket, &mut file, start, start.saturating_add(count), &transfer)
+                        .instrument(span)
+                        .await?;
+                }
+                Request::Upload { name, size, hash } => {
+                    let Some(inbox) = &inbox else {
+                        codec.send(&mut socket, &Response::Error("Uploads are disabled".into())).await?;
+                        continue;
+                    };
+                    if !matches!(role, Role::Uploader | Role::Admin) {
+                        codec.send(&mut socket, &Response::Error("Permission denied".into())).await?;
+                        continue;
                     }
 
-                    encoder.shutdown().await?;
-                    send_message(&mut socket, &Response::Done).await?;
-                    println!("File '{name}' sent successfully to client");
+                    let received = inbox.receive(&mut socket, &conn, codec, timeouts, name, size, hash).await?;
+                    if let Some((name, path)) = received {
+                        if inbox.auto_share {
+                            self.add_file(name, path, None).await;
+                        }
+                    }
+                }
+                Request::Admin(cmd) => {
+                    let resp = self.handle_admin(cmd, &role, &conn).await;
+                    codec.send(&mut socket, &Response::Admin(resp)).await?;
+                }
+                Request::Subscribe => {
+                    return self.stream_events(&mut socket).await;
+                }
+                Request::Ping => {
+                    codec.send(&mut socket, &Response::Pong).await?;
                 }
                 Request::Quit => {
-                    send_message(&mut socket, &Response::Bye).await?;
+                    codec.send(&mut socket, &Response::Bye).await?;
                     break;
                 }
-                _ => { break; }
+                _ => {
+                    return Err(CodecError::Protocol(format!("unexpected request: {req:?}")).into());
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+impl Server {
+    // FileInfo followed by the file from `offset`, rounded down to a chunk boundary
+    #[instrument(skip_all, fields(share = %name))]
+    async fn send_file<S>(&self, socket: &mut S, conn: &Connection, name: &str, share: &Share, hash: String, offset: u64) -> anyhow::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let Ok(mut file) = share.open().await else {
+            self.codec.send(socket, &Response::Error("Error opening file".into())).await?;
+            return Ok(());
+        };
+
+        let size = get_file_length(&file).await?;
+        let index = offset / CHUNK_SIZE as u64;
+        let resumed = (index * CHUNK_SIZE as u64).min(size);
+        let transfer = conn.transfer(name, &hash, resumed, resumed, size);
+        self.codec.send(
+            socket,
+            &Response::FileInfo {
+                name: name.to_string(),
+                size,
+                hash,
+                chunk_size: CHUNK_SIZE as u64,
+            }
+        ).await?;
+
+        file.seek(std::io::SeekFrom::Start(index * CHUNK_SIZE as u64)).await?;
+        self.send_chunks(socket, &mut file, index, u64::MAX, &transfer).await?;
+        info!("File sent");
+        Ok(())
+    }
+
+    // sends chunks [index, end) or until EOF, each one has to be acked before the next
+    async fn send_chunks<S>(&self, socket: &mut S, file: &mut File, mut index: u64, end: u64, transfer: &Transfer) -> anyhow::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+
+        while index < end {
+            let n = read_full(file, &mut buf).await?;
+            if n == 0 { break; }
+
+            let data = compress_chunk(&buf[..n]).await?;
+            self.stats.compressed(n, data.len());
+            let chunk = Response::Chunck { index, data };
+            self.codec.send(socket, &chunk).await?;
+            if !self.wait_ack(socket, index).await? {
+                break;
+            }
+            transfer.advance(n as u64, n as u64);
+            index += 1;
+        }
+
+        self.codec.send(socket, &Response::Done).await?;
+        Ok(())
+    }
+
+    // false if the client acked something else, the transfer stops then
+    async fn wait_ack<S>(&self, socket: &mut S, index: u64) -> anyhow::Result<bool>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let ack = self.codec.with_read_timeout(self.timeouts.ack)
+            .recv::<Request, _>(socket).await;
+        match ack {
+            Ok(Request::Ack { index: ack_idx }) if ack_idx == index => Ok(true),
+            Ok(Request::Ack { .. }) => {
+                warn!("Client ack mismatch, stopping transfer");
+                Ok(false)
             }
-                
+            Ok(_) => {
+                warn!("Client did not ack properly");
+                Ok(false)
+            }
+            Err(e) => Err(anyhow::Error::from(e).context("waiting for ack")),
+        }
+    }
+
+    // FileInfo, then the ops that turn the client's old copy into the file.
+    // The matching runs on a blocking thread and hands batches over as it goes
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip_all, fields(share = %name))]
+    async fn send_delta<S>(
+        &self,
+        socket: &mut S,
+        conn: &Connection,
+        name: &str,
+        share: &Share,
+        hash: String,
+        block_size: u64,
+        signatures: Vec<BlockSignature>,
+    ) -> anyhow::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let Ok(file) = share.open().await else {
+            self.codec.send(socket, &Response::Error("Error opening file".into())).await?;
+            return Ok(());
         };
+
+        let file = file.into_std().await;
+        let size = file.metadata()?.len();
+        let transfer = conn.transfer(name, &hash, 0, 0, size);
+        self.codec.send(
+            socket,
+            &Response::FileInfo {
+                name: name.to_string(),
+                size,
+                hash,
+                chunk_size: CHUNK_SIZE as u64,
+            }
+        ).await?;
+
+        let (tx, mut rx) = mpsc::channel(4);
+        let differ = tokio::task::spawn_blocking(move || {
+            diff(file, block_size as usize, &signatures, |ops| {
+                tx.blocking_send(ops).map_err(|_| anyhow::anyhow!("transfer stopped"))
+            })
+        });
+
+        let mut index = 0;
+        let mut complete = true;
+        while let Some(mut ops) = rx.recv().await {
+            let (mut done, mut sent) = (0, 0);
+            for op in &mut ops {
+                match op {
+                    DeltaOp::Copy { count, .. } => done += *count * block_size,
+                    DeltaOp::Literal(data) => {
+                        done += data.len() as u64;
+                        sent += data.len() as u64;
+                        let compressed = compress_chunk(data).await?;
+                        self.stats.compressed(data.len(), compressed.len());
+                        *data = compressed;
+                    }
+                }
+            }
+            self.codec.send(socket, &Response::Delta { index, ops }).await?;
+            if !self.wait_ack(socket, index).await? {
+                complete = false;
+                break;
+            }
+            transfer.advance(done, sent);
+            index += 1;
+        }
+
+        // a stopped transfer makes the differ fail on its next batch
+        drop(rx);
+        let res = differ.await?;
+        if complete {
+            res?;
+        }
+
+        self.codec.send(socket, &Response::Done).await?;
         Ok(())
     }
+
+    // watches the directories of all shares and applies what happens to them
+    async fn start_watching(&self) {
+        let (mut watcher, mut rx) = match ShareWatcher::new() {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                warn!("File watching disabled: {e}");
+                return;
+            }
+        };
+
+        // shares added from here on register themselves
+        let files = self.files.read().await;
+        for share in files.values() {
+            watcher.watch(&share.path);
+        }
+        *self.watcher.lock().unwrap() = Some(watcher);
+        drop(files);
+
+        let server = self.clone();
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                server.apply_fs_event(event).await;
+            }
+        });
+    }
+
+    fn watch(&self, path: &Path) {
+        if let Some(watcher) = self.watcher.lock().unwrap().as_mut() {
+            watcher.watch(path);
+        }
+    }
+
+    fn unwatch(&self, path: &Path) {
+        if let Some(watcher) = self.watcher.lock().unwrap().as_mut() {
+            watcher.unwatch(path);
+        }
+    }
+
+    // to the daemon log and every subscribed client
+    fn emit(&self, event: ShareEvent) {
+        info!("Share {event}");
+        self.stats.publish(DaemonEvent::Share(event.clone()));
+        // nobody subscribed is not an error
+        let _ = self.events.send(event);
+    }
+
+    async fn apply_fs_event(&self, event: notify::Event) {
+        match event.kind {
+            // inotify reports From and To first, by then the share is already unavailable
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                if let [from, to] = &event.paths[..] {
+                    self.file_moved(from, to).await;
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) | EventKind::Remove(_) => {
+                for path in &event.paths {
+                    for name in self.shares_at(path).await {
+                        self.mark_unavailable(&name).await;
+                    }
+                }
+            }
+            EventKind::Create(_)
+            | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+            | EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                for path in &event.paths {
+                    self.file_changed(path).await;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn shares_at(&self, path: &Path) -> Vec<String> {
+        self.files.read().await.iter()
+            .filter(|(_, share)| share.path == path)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    // keeps the share listed but refuses downloads until its file shows up again
+    async fn mark_unavailable(&self, name: &str) {
+        let old = match self.files.write().await.get_mut(name) {
+            Some(share) if share.available => {
+                share.available = false;
+                share.hash.take()
+            }
+            _ => return,
+        };
+        if let Some((_, hash)) = old {
+            self.reindex(&hash).await;
+        }
+        self.emit(ShareEvent::Unavailable { name: name.to_string() });
+    }
+
+    // content at `path` changed or appeared, cached hashes of shares there are stale.
+    // A share only comes back if its owner can open what is there now
+    async fn file_changed(&self, path: &Path) {
+        for name in self.shares_at(path).await {
+            let Some(share) = self.files.read().await.get(&name).cloned() else {
+                continue;
+            };
+            let readable = share.owner.is_none() || share.open().await.is_ok();
+            let (old, was_available) = match self.files.write().await.get_mut(&name) {
+                Some(share) => (share.hash.take(), std::mem::replace(&mut share.available, readable)),
+                None => continue,
+            };
+            if let Some((_, hash)) = &old {
+                self.reindex(hash).await;
+            }
+            if !readable {
+                if was_available {
+                    self.emit(ShareEvent::Unavailable { name });
+                }
+            } else if !was_available {
+                self.emit(ShareEvent::Available { name });
+            } else if old.is_some() {
+                // later writes to the same file are only reported once the hash was taken again
+                self.emit(ShareEvent::Modified { name });
+            }
+        }
+    }
+
+    // shares follow their file when it is renamed within watched directories
+    async fn file_moved(&self, from: &Path, to: &Path) {
+        // whatever was at `to` got replaced
+        self.file_changed(to).await;
+
+        let mut moved = Vec::new();
+        let mut files = self.files.write().await;
+        for (name, share) in files.iter_mut().filter(|(_, share)| share.path == from) {
+            self.unwatch(&share.path);
+            self.watch(to);
+            share.path = to.to_path_buf();
+            share.available = true;
+            moved.push(name.clone());
+        }
+        drop(files);
+
+        for name in moved {
+            self.emit(ShareEvent::Moved { name, path: to.to_path_buf() });
+        }
+    }
+
+    // sends share events until the client goes away, a client that falls
+    // too far behind misses the oldest ones
+    async fn stream_events<S>(&self, socket: &mut S) -> anyhow::Result<()>
+    where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let mut rx = self.events.subscribe();
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Event subscriber fell behind, skipped {n} events");
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            };
+            self.codec.send(socket, &Response::Event(event)).await?;
+        }
+    }
+
+    // remote share management, answered by the same handler as the control socket
+    async fn handle_admin(&self, cmd: DaemonCommand, role: &Role, conn: &Connection) -> DaemonResponse {
+        let command = format!("{cmd:?}");
+        let resp = match (&self.admin_tx, role) {
+            (Some(tx), Role::Admin) if cmd.is_remote() => match self.admit_remote(cmd).await {
+                Ok((cmd, owner)) => {
+                    let (resp_tx, resp_rx) = Responder::channel();
+                    if tx.send(DaemonMessage { cmd, resp_tx, owner }).await.is_err() {
+                        DaemonResponse::Err(ErrorKind::NotRunning, "Daemon not running".into())
+                    } else {
+                        self.single_response(resp_rx).await
+                    }
+                }
+                Err(resp) => resp,
+            },
+            (Some(_), Role::Admin) => DaemonResponse::Err(ErrorKind::PermissionDenied, "Command not allowed remotely".into()),
+            _ => DaemonResponse::Err(ErrorKind::PermissionDenied, "Permission denied".into()),
+        };
+
+        match &resp {
+            DaemonResponse::Err(ErrorKind::PermissionDenied, e) => conn.admin(command, AuditOutcome::Denied, Some(e.clone())),
+            DaemonResponse::Err(_, e) => conn.admin(command, AuditOutcome::Failed, Some(e.clone())),
+            _ => conn.admin(command, AuditOutcome::Ok, None),
+        }
+        resp
+    }
+
+    // remote admins get a single response, paged lists are put back together
+    // as long as they still fit into one message
+    async fn single_response(&self, mut resp_rx: mpsc::UnboundedReceiver<HandlerReply>) -> DaemonResponse {
+        let max = self.codec.max_message_size as u64;
+        // the frame also holds the tag of Response::Admin
+        let (mut responses, mut size) = (Vec::new(), 4);
+        while let Some(reply) = resp_rx.recv().await {
+            if let HandlerReply::Reply(DaemonReply::Response(resp)) = reply {
+                size += bincode::serialized_size(&resp).unwrap_or(u64::MAX);
+                if size > max {
+                    return DaemonResponse::Err(ErrorKind::Failed, format!("The answer is larger than the {max} bytes of one message"));
+                }
+                responses.push(resp);
+            }
+        }
+        DaemonResponse::merge_pages(responses).into_iter().next()
+            .unwrap_or_else(|| DaemonResponse::Err(ErrorKind::Failed, "Daemon failed to respond".into()))
+    }
+}
+
+impl Server {
+    // remote admins have no local user to check paths against like the control socket does,
+    // so the files they add have to be under the admin root and are read with its owner's access
+    async fn admit_remote(&self, cmd: DaemonCommand) -> Result<(DaemonCommand, Option<Owner>), DaemonResponse> {
+        let DaemonCommand::Add { path, name } = cmd else {
+            return Ok((cmd, None));
+        };
+        let Some((root, owner)) = self.admin_root.clone() else {
+            let message = "Remote admins can only add files when the daemon has an --admin-root";
+            return Err(DaemonResponse::Err(ErrorKind::PermissionDenied, message.into()));
+        };
+
+        let resolved = {
+            let (path, owner) = (path.clone(), owner.clone());
+            tokio::task::spawn_blocking(move || {
+                let resolved = confine(&root, &path)?;
+                let file = match owner {
+                    Some(owner) => owner.open(&resolved)?,
+                    None => std::fs::File::open(&resolved)?,
+                };
+                if !file.metadata()?.is_file() {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file"));
+                }
+                std::io::Result::Ok(resolved)
+            }).await
+        };
+        // the same answer for missing, unreadable and outside, so nothing is learned about other files
+        let Ok(Ok(resolved)) = resolved else {
+            let message = format!("Can't read {path}: it isn't a file under the admin root or can't be read");
+            return Err(DaemonResponse::Err(ErrorKind::PermissionDenied, message));
+        };
+        Ok((DaemonCommand::Add { path: resolved.to_string_lossy().into_owned(), name }, owner))
+    }
+}
+
+// `path` resolved against the canonical `root`, as long as it stays inside of it
+fn confine(root: &Path, path: &str) -> std::io::Result<PathBuf> {
+    let resolved = std::fs::canonicalize(root.join(path))?;
+    if !resolved.starts_with(root) {
+        return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "outside of the admin root"));
+    }
+    Ok(resolved)
+}
+
+/// Short id of a link token as the audit log and `daemon links` show it, the token itself stays secret
+pub fn token_id(token: &str) -> String {
+    blake3::hash(token.as_bytes()).to_hex()[..16].to_string()
+}
+
+pub fn set_keepalive(socket: &TcpStream, idle: Duration) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new()
+        .with_time(idle)
+        .with_interval(idle / 4);
+    SockRef::from(socket).set_tcp_keepalive(&keepalive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admin_paths_stay_under_the_root() {
+        let dir = std::env::temp_dir().join(format!("file_share_admin_root_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("root/sub")).unwrap();
+        std::fs::write(dir.join("root/sub/inside.txt"), b"in").unwrap();
+        std::fs::write(dir.join("outside.txt"), b"out").unwrap();
+        std::os::unix::fs::symlink(dir.join("outside.txt"), dir.join("root/link.txt")).unwrap();
+        let root = std::fs::canonicalize(dir.join("root")).unwrap();
+
+        let inside = root.join("sub/inside.txt");
+        assert_eq!(confine(&root, "sub/inside.txt").unwrap(), inside);
+        assert_eq!(confine(&root, &inside.to_string_lossy()).unwrap(), inside);
+        assert_eq!(confine(&root, "sub/../sub/inside.txt").unwrap(), inside);
+
+        let outside = dir.join("outside.txt").to_string_lossy().into_owned();
+        for bad in ["../outside.txt", "link.txt", "sub/../../outside.txt", &outside, "/etc/passwd", "missing.txt"] {
+            assert!(confine(&root, bad).is_err(), "{bad}");
+        }
+    }
+
+    // waits for the background hash of `name` to be the one of `content`
+    async fn hashed(server: &Server, name: &str, content: &[u8]) {
+        let expected = blake3::hash(content).to_hex().to_string();
+        for _ in 0..100 {
+            if server.files.read().await.get(name).is_some_and(|share| share.hash.as_ref().is_some_and(|(_, h)| *h == expected)) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("'{name}' was never hashed");
+    }
+
+    #[tokio::test]
+    async fn link_waits_for_the_background_hash() {
+        let dir = std::env::temp_dir().join(format!("file_share_link_hash_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        std::fs::write(&path, b"first").unwrap();
+        let server = Server::new(None);
+        server.add_file("notes.txt".into(), path.clone(), None).await;
+        hashed(&server, "notes.txt", b"first").await;
+
+        // nothing watches the file here, so the cached hash only goes stale by its mtime
+        std::fs::write(&path, b"second").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(60)).unwrap();
+        let err = server.share_link("notes.txt", "localhost", None).await.unwrap_err();
+        assert!(err.to_string().contains("pending"), "{err:#}");
+        hashed(&server, "notes.txt", b"second").await;
+        assert!(server.tokens.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn merged_pages_have_to_fit_one_message() {
+        let server = Server::new(None).with_codec(Codec::default().with_max_message_size(1024));
+        let share = ShareInfo { name: "x".repeat(100), path: "/".into(), available: true };
+        let page = |n| DaemonResponse::List(vec![share.clone(); n]);
+
+        let (resp_tx, resp_rx) = Responder::channel();
+        let _ = resp_tx.send(page(2));
+        let _ = resp_tx.send(page(2));
+        drop(resp_tx);
+        assert!(matches!(server.single_response(resp_rx).await, DaemonResponse::List(all) if all.len() == 4));
+
+        let (resp_tx, resp_rx) = Responder::channel();
+        for _ in 0..10 {
+            let _ = resp_tx.send(page(2));
+        }
+        drop(resp_tx);
+        assert!(matches!(server.single_response(resp_rx).await, DaemonResponse::Err(ErrorKind::Failed, _)));
+    }
 }
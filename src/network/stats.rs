@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::daemon::{DaemonEvent, TimedEvent};
+use crate::network::{AuditEvent, AuditIdentity, AuditLog, AuditOutcome};
+use crate::settings::{TRANSFER_DURATION_BUCKETS, WATCH_EVENTS_CAPACITY, WATCH_PROGRESS_MS};
+use crate::utils::unix_now;
+
+/// A client session as shown by `daemon status`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer: String,
+    /// unix seconds
+    pub since: u64,
+}
+
+/// A file going out to a client
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferInfo {
+    pub peer: String,
+    pub name: String,
+    /// bytes of the file the client has, counting data it reused
+    pub done: u64,
+    pub size: u64,
+}
+
+/// Runtime counters of a server, shared by all of its connection tasks,
+/// and the events `daemon watch` follows them by
+pub struct ServerStats {
+    started: Instant,
+    next_id: AtomicU64,
+    bytes_served: AtomicU64,
+    connections: Mutex<BTreeMap<u64, ConnectionInfo>>,
+    transfers: Mutex<BTreeMap<u64, TransferInfo>>,
+    pub counters: Counters,
+    activity: broadcast::Sender<TimedEvent>,
+}
+
+/// Totals since the start, as exported to Prometheus
+#[derive(Default)]
+pub struct Counters {
+    pub connections_accepted: AtomicU64,
+    /// closed before authenticating, e.g. a failed or timed out TLS handshake
+    pub connections_rejected: AtomicU64,
+    pub auth_failures: AtomicU64,
+    pub bytes_by_share: Mutex<BTreeMap<String, u64>>,
+    pub durations: Mutex<Histogram>,
+    /// chunk data before and after compression
+    pub uncompressed_bytes: AtomicU64,
+    pub compressed_bytes: AtomicU64,
+    pub hash_cache_hits: AtomicU64,
+    pub hash_cache_misses: AtomicU64,
+}
+
+/// Transfer durations in seconds, counted into TRANSFER_DURATION_BUCKETS
+#[derive(Default)]
+pub struct Histogram {
+    /// per bucket, not cumulative
+    pub counts: [u64; TRANSFER_DURATION_BUCKETS.len()],
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        if let Some(i) = TRANSFER_DURATION_BUCKETS.iter().position(|&bound| secs <= bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        ServerStats {
+            started: Instant::now(),
+            next_id: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            connections: Mutex::new(BTreeMap::new()),
+            transfers: Mutex::new(BTreeMap::new()),
+            counters: Counters::default(),
+            activity: broadcast::channel(WATCH_EVENTS_CAPACITY).0,
+        }
+    }
+}
+
+impl ServerStats {
+    /// Registers a client session until the returned guard is dropped,
+    /// its transfers are recorded in `audit` when they end
+    pub fn connect(self: &Arc<Self>, peer: SocketAddr, audit: Option<Arc<AuditLog>>) -> Connection {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(id, ConnectionInfo { peer: peer.to_string(), since: unix_now() });
+        self.publish(DaemonEvent::Connected { peer: peer.to_string() });
+        let who = AuditIdentity { peer: peer.to_string(), ..Default::default() };
+        Connection { stats: Arc::clone(self), id, who, audit }
+    }
+
+    /// Events from now on, until the receiver is dropped
+    pub fn subscribe(&self) -> broadcast::Receiver<TimedEvent> {
+        self.activity.subscribe()
+    }
+
+    pub fn publish(&self, event: DaemonEvent) {
+        // nobody watching is not an error
+        let _ = self.activity.send(TimedEvent { time: unix_now(), event });
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.elapsed().as_secs()
+    }
+
+    /// File data sent to clients since the start, data a client reused doesn't count
+    pub fn bytes_served(&self) -> u64 {
+        self.bytes_served.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn transfers(&self) -> Vec<TransferInfo> {
+        self.transfers.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn active_transfers(&self) -> usize {
+        self.transfers.lock().unwrap().len()
+    }
+
+    pub fn compressed(&self, uncompressed: usize, compressed: usize) {
+        self.counters.uncompressed_bytes.fetch_add(uncompressed as u64, Ordering::Relaxed);
+        self.counters.compressed_bytes.fetch_add(compressed as u64, Ordering::Relaxed);
+    }
+
+    pub fn hash_lookup(&self, cached: bool) {
+        let counter = if cached { &self.counters.hash_cache_hits } else { &self.counters.hash_cache_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A registered client session
+pub struct Connection {
+    stats: Arc<ServerStats>,
+    id: u64,
+    who: AuditIdentity,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Connection {
+    /// Records an auth attempt, a successful one names the role the session goes on with.
+    /// `token` is the id of the link token it was made with
+    pub fn authenticated(&mut self, user: Option<&'static str>, token: Option<String>) {
+        self.who.user = user;
+        self.who.token = token;
+        self.stats.publish(DaemonEvent::Auth { peer: self.who.peer.clone(), user: user.map(str::to_string) });
+        if user.is_none() {
+            self.stats.counters.auth_failures.fetch_add(1, Ordering::Relaxed);
+            self.stats.counters.connections_rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some(audit) = &self.audit {
+            let outcome = if user.is_some() { AuditOutcome::Ok } else { AuditOutcome::Denied };
+            audit.record(&self.who, AuditEvent::Auth, outcome, None);
+        }
+    }
+
+    /// Records a remote admin command and how it went
+    pub fn admin(&self, command: String, outcome: AuditOutcome, error: Option<String>) {
+        if let Some(audit) = &self.audit {
+            audit.record(&self.who, AuditEvent::Admin { command }, outcome, error);
+        }
+    }
+
+    /// Registers a transfer of `size` bytes of the share with `hash`, `done` of which the client
+    /// already has, until the returned guard is dropped. It starts at byte `offset` of the file
+    pub fn transfer(&self, name: &str, hash: &str, offset: u64, done: u64, size: u64) -> Transfer {
+        self.register(name, hash, offset, done, size, false)
+    }
+
+    /// Registers an upload of `size` bytes into the inbox as `name`, resumed at `offset`,
+    /// until the returned guard is dropped
+    pub fn upload(&self, name: &str, hash: &str, offset: u64, size: u64) -> Transfer {
+        self.register(name, hash, offset, offset, size, true)
+    }
+
+    fn register(&self, name: &str, hash: &str, offset: u64, done: u64, size: u64, upload: bool) -> Transfer {
+        let id = self.stats.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = TransferInfo { peer: self.who.peer.clone(), name: name.to_string(), done, size };
+        self.stats.transfers.lock().unwrap().insert(id, info);
+        self.stats.publish(DaemonEvent::TransferStarted {
+            id,
+            peer: self.who.peer.clone(),
+            share: name.to_string(),
+            done,
+            size,
+            upload,
+        });
+        Transfer {
+            stats: Arc::clone(&self.stats),
+            id,
+            started: Instant::now(),
+            last_progress: Mutex::new(Instant::now()),
+            sent: AtomicU64::new(0),
+            upload,
+            error: Mutex::new(None),
+            audit: self.audit.clone().map(|audit| (audit, self.who.clone(), hash.to_string(), offset)),
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.stats.connections.lock().unwrap().remove(&self.id);
+        self.stats.publish(DaemonEvent::Disconnected { peer: self.who.peer.clone() });
+    }
+}
+
+/// A registered transfer
+pub struct Transfer {
+    stats: Arc<ServerStats>,
+    id: u64,
+    started: Instant,
+    last_progress: Mutex<Instant>,
+    sent: AtomicU64,
+    // data coming in, it doesn't count as served
+    upload: bool,
+    // set when the data arrived but was refused
+    error: Mutex<Option<String>>,
+    // where it ends up once done, with the identity, hash and offset of the record
+    audit: Option<(Arc<AuditLog>, AuditIdentity, String, u64)>,
+}
+
+impl Transfer {
+    /// The client got `done` more bytes of the file, `sent` of them went over the wire
+    pub fn advance(&self, done: u64, sent: u64) {
+        if let Some(info) = self.stats.transfers.lock().unwrap().get_mut(&self.id) {
+            info.done += done;
+            if !self.upload {
+                *self.stats.counters.bytes_by_share.lock().unwrap().entry(info.name.clone()).or_default() += sent;
+            }
+
+            // watchers get a few updates a second, however small the chunks
+            let mut last = self.last_progress.lock().unwrap();
+            if last.elapsed() >= Duration::from_millis(WATCH_PROGRESS_MS) {
+                *last = Instant::now();
+                let event = DaemonEvent::TransferProgress { id: self.id, share: info.name.clone(), done: info.done, size: info.size };
+                self.stats.publish(event);
+            }
+        }
+        self.sent.fetch_add(sent, Ordering::Relaxed);
+        if !self.upload {
+            self.stats.bytes_served.fetch_add(sent, Ordering::Relaxed);
+        }
+    }
+
+    /// All data arrived but was refused, e.g. it didn't match its hash
+    pub fn fail(&self, error: &str) {
+        *self.error.lock().unwrap() = Some(error.to_string());
+    }
+}
+
+impl Drop for Transfer {
+    // a transfer dropped before the client got everything broke off
+    fn drop(&mut self) {
+        let Some(info) = self.stats.transfers.lock().unwrap().remove(&self.id) else {
+            return;
+        };
+        let elapsed = self.started.elapsed();
+        let error = self.error.get_mut().unwrap().take();
+        let complete = info.done >= info.size && error.is_none();
+        let bytes = *self.sent.get_mut();
+        let duration_ms = elapsed.as_millis() as u64;
+        self.stats.counters.durations.lock().unwrap().observe(elapsed.as_secs_f64());
+
+        if let Some((audit, who, hash, offset)) = self.audit.take() {
+            let outcome = if complete { AuditOutcome::Ok } else { AuditOutcome::Failed };
+            let share = info.name.clone();
+            let event = match self.upload {
+                false => AuditEvent::Transfer { share, hash, offset, bytes, duration_ms },
+                true => AuditEvent::Upload { share, hash, offset, bytes, duration_ms },
+            };
+            audit.record(&who, event, outcome, error);
+        }
+        self.stats.publish(DaemonEvent::TransferFinished {
+            id: self.id,
+            peer: info.peer,
+            share: info.name,
+            bytes,
+            duration_ms,
+            complete,
+            upload: self.upload,
+        });
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::bail;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+use crate::network::Client;
+use crate::settings::{CHUNK_SIZE, MAX_CHUNK_HASHES, SWARM_BLOCK_CHUNKS, SWARM_MAX_CHUNKS};
+use crate::utils::hash_file;
+
+/// A server that may have the content
+#[derive(Debug, Clone)]
+pub struct SwarmPeer {
+    pub addr: String,
+    pub password: Option<String>,
+}
+
+// what is left to fetch, shared by all peer workers
+struct SwarmState {
+    pending: VecDeque<u64>,
+    // block -> number of workers fetching it
+    in_flight: HashMap<u64, usize>,
+    done: Vec<bool>,
+}
+
+impl SwarmState {
+    fn block_done(&self, block: u64, chunk_count: u64) -> bool {
+        block_chunks(block, chunk_count).all(|i| self.done[i as usize])
+    }
+
+    // next block for a worker: a pending one, or once none are left, one that a
+    // slower peer is still working on so the last blocks don't wait on it
+    fn take(&mut self, mine: &HashSet<u64>, chunk_count: u64) -> Option<u64> {
+        let block = match self.pending.pop_front() {
+            Some(block) => block,
+            None => *self.in_flight.keys()
+                .filter(|b| !mine.contains(b) && !self.block_done(**b, chunk_count))
+                .min()?,
+        };
+        *self.in_flight.entry(block).or_default() += 1;
+        Some(block)
+    }
+
+    fn release(&mut self, block: u64, chunk_count: u64) {
+        if let Some(n) = self.in_flight.get_mut(&block) {
+            *n -= 1;
+            if *n == 0 {
+                self.in_flight.remove(&block);
+                if !self.block_done(block, chunk_count) {
+                    self.pending.push_front(block);
+                }
+            }
+        }
+    }
+}
+
+fn block_chunks(block: u64, chunk_count: u64) -> std::ops::Range<u64> {
+    let start = block * SWARM_BLOCK_CHUNKS;
+    start..(start + SWARM_BLOCK_CHUNKS).min(chunk_count)
+}
+
+/// Downloads the content with blake3 `hash` from all `peers` at once.
+/// Peers pull blocks of chunks from a shared queue, so faster peers serve more of the file.
+/// A peer that sends a chunk not matching its hash, fails or takes longer than
+/// `block_timeout` for a block is dropped and its block goes back to the queue.
+pub async fn swarm_download(
+    hash: &str,
+    peers: Vec<SwarmPeer>,
+    output: &Path,
+    block_timeout: Duration,
+) -> anyhow::Result<u64> {
+    let mut connecting = JoinSet::new();
+    for peer in peers {
+        connecting.spawn(async move {
+            let client = Client::connect(&peer.addr, peer.password, None).await;
+            (peer.addr, client)
+        });
+    }
+
+    let mut clients = Vec::new();
+    while let Some(res) = connecting.join_next().await {
+        match res? {
+            (addr, Ok(client)) => clients.push((addr, client)),
+            (addr, Err(e)) => warn!("Skipping peer {addr}: {e}"),
+        }
+    }
+    if clients.is_empty() {
+        bail!("No peer could be reached");
+    }
+
+    // every peer has to report the same size and chunk hashes as the first one that has the file,
+    // the others are left out. The final file hash check catches peers that agree on a lie
+    let mut manifest = None;
+    let mut agreeing = Vec::with_capacity(clients.len());
+    for (addr, mut client) in clients {
+        match (fetch_manifest(&mut client, hash).await, &manifest) {
+            (Ok(m), None) => {
+                manifest = Some(m);
+                agreeing.push((addr, client));
+            }
+            (Ok(m), Some(first)) if m == *first => agreeing.push((addr, client)),
+            (Ok(_), Some(_)) => warn!("Skipping peer {addr}: its chunk list differs from the other peers'"),
+            (Err(e), _) => warn!("Peer {addr} can't serve {hash}: {e}"),
+        }
+    }
+    let clients = agreeing;
+    let Some((size, chunk_hashes)) = manifest else {
+        bail!("No peer has {hash}");
+    };
+
+    let chunk_count = chunk_hashes.len() as u64;
+    let block_count = chunk_count.div_ceil(SWARM_BLOCK_CHUNKS);
+    let state = Arc::new(Mutex::new(SwarmState {
+        pending: (0..block_count).collect(),
+        in_flight: HashMap::new(),
+        done: vec![false; chunk_count as usize],
+    }));
+    let chunk_hashes = Arc::new(chunk_hashes);
+
+    let file = File::create(output)?;
+    file.set_len(size)?;
+    let file = Arc::new(file);
+
+    let mut workers = JoinSet::new();
+    for (addr, client) in clients {
+        let worker = PeerWorker {
+            hash: hash.to_string(),
+            addr,
+            client,
+            state: Arc::clone(&state),
+            chunk_hashes: Arc::clone(&chunk_hashes),
+            file: Arc::clone(&file),
+            block_timeout,
+        };
+        workers.spawn(worker.run());
+    }
+
+    while let Some(res) = workers.join_next().await {
+        let (addr, served) = res?;
+        info!("Peer {addr} served {served} bytes");
+    }
+
+    let missing = state.lock().unwrap().done.iter().filter(|d| !**d).count();
+    if missing > 0 {
+        bail!("{missing} chunks missing, no peers left");
+    }
+
+    file.sync_all()?;
+    if !hash_file(&PathBuf::from(output)).await?.eq_ignore_ascii_case(hash) {
+        bail!("Hash mismatch after download");
+    }
+    Ok(size)
+}
+
+// size and chunk hashes of the content as one peer reports them. Only CHUNK_SIZE chunks
+// are taken, so a peer can't make chunks empty or the list huge
+async fn fetch_manifest(client: &mut Client, hash: &str) -> anyhow::Result<(u64, Vec<[u8; 32]>)> {
+    let (size, chunk_size, mut hashes) = client.chunk_hashes(hash, 0, MAX_CHUNK_HASHES).await?;
+    if chunk_size != CHUNK_SIZE as u64 {
+        bail!("Unsupported chunk size {chunk_size}");
+    }
+    let chunk_count = size.div_ceil(CHUNK_SIZE as u64);
+    if chunk_count > SWARM_MAX_CHUNKS {
+        bail!("Content of {size} bytes is too large for a swarm download");
+    }
+
+    while (hashes.len() as u64) < chunk_count {
+        let (more_size, more_chunk_size, more) = client.chunk_hashes(hash, hashes.len() as u64, MAX_CHUNK_HASHES).await?;
+        if more_size != size || more_chunk_size != chunk_size {
+            bail!("Size changed while listing chunks");
+        }
+        if more.is_empty() {
+            bail!("Incomplete chunk list");
+        }
+        hashes.extend(more);
+    }
+    if hashes.len() as u64 != chunk_count {
+        bail!("Chunk list doesn't match the size");
+    }
+    Ok((size, hashes))
+}
+
+struct PeerWorker {
+    hash: String,
+    addr: String,
+    client: Client,
+    state: Arc<Mutex<SwarmState>>,
+    chunk_hashes: Arc<Vec<[u8; 32]>>,
+    file: Arc<File>,
+    block_timeout: Duration,
+}
+
+impl PeerWorker {
+    // returns the peer address and how many bytes it contributed
+    async fn run(mut self) -> (String, u64) {
+        let chunk_count = self.chunk_hashes.len() as u64;
+        let mut served = 0;
+        let mut mine = HashSet::new();
+
+        loop {
+            let Some(block) = self.state.lock().unwrap().take(&mine, chunk_count) else {
+                break;
+            };
+            mine.insert(block);
+
+            let range = block_chunks(block, chunk_count);
+            let res = tokio::time::timeout(self.block_timeout, self.fetch(range.start, range.end - range.start)).await;
+            let complete = {
+                let mut state = self.state.lock().unwrap();
+                state.release(block, chunk_count);
+                state.block_done(block, chunk_count)
+            };
+
+            match res {
+                Ok(Ok(bytes)) if complete => served += bytes,
+                Ok(Ok(_)) => {
+                    warn!("Dropping peer {}: block {block} came back incomplete", self.addr);
+                    break;
+                }
+                Ok(Err(e)) => {
+                    warn!("Dropping peer {}: {e}", self.addr);
+                    break;
+                }
+                Err(_) => {
+                    warn!("Dropping peer {}: block {block} timed out", self.addr);
+                    break;
+                }
+            }
+        }
+        (self.addr, served)
+    }
+
+    // chunks of a block are verified as they come in and written together on a blocking thread.
+    // Each worker writes at its own offsets, verified chunks two peers fetched in the endgame are
+    // identical, so no lock is held while writing
+    async fn fetch(&mut self, start: u64, count: u64) -> anyhow::Result<u64> {
+        let PeerWorker { hash, client, state, chunk_hashes, file, .. } = self;
+        let mut chunks = Vec::new();
+
+        client.download_range(hash, start, count, |index, data| {
+            let Some(expected) = chunk_hashes.get(index as usize) else {
+                bail!("chunk {index} out of range");
+            };
+            if blake3::hash(data).as_bytes() != expected {
+                bail!("chunk {index} is corrupt");
+            }
+            // another peer may have delivered it first in the endgame
+            if !state.lock().unwrap().done[index as usize] {
+                chunks.push((index, data.to_vec()));
+            }
+            Ok(())
+        }).await?;
+
+        let file = Arc::clone(file);
+        let chunks = tokio::task::spawn_blocking(move || {
+            for (index, data) in &chunks {
+                file.write_all_at(data, index * CHUNK_SIZE as u64)?;
+            }
+            std::io::Result::Ok(chunks)
+        }).await??;
+
+        let mut state = state.lock().unwrap();
+        let mut written = 0;
+        for (index, data) in chunks {
+            if !std::mem::replace(&mut state.done[index as usize], true) {
+                written += data.len() as u64;
+            }
+        }
+        Ok(written)
+    }
+}
@@ -0,0 +1,191 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::network::Client;
+use crate::utils::{create_private, open_private};
+
+// kept in the mirrored directory, maps share names to the hash last synced.
+// Every server and prefix gets its own, so jobs sharing a directory don't mix them up
+const STATE_FILE_PREFIX: &str = ".fileshare-sync-";
+
+/// What a sync job mirrors and how
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncConfig {
+    pub server: String,
+    /// SHA-256 fingerprint the server certificate has to match
+    pub fingerprint: String,
+    pub password: Option<String>,
+    /// shares whose name starts with this, a full name selects one share
+    pub prefix: String,
+    pub dir: PathBuf,
+    /// remove local files whose share is gone
+    pub delete: bool,
+    /// seconds between polls
+    pub interval: u64,
+}
+
+/// Progress of a sync job as reported to the user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncStatus {
+    pub id: u64,
+    pub server: String,
+    pub prefix: String,
+    pub dir: PathBuf,
+    pub state: String,
+    /// files downloaded or deleted since the job started
+    pub changes: u64,
+    /// unix seconds of the last complete pass
+    pub last_sync: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// Mirrors the matching shares into `config.dir` every `config.interval` seconds, forever.
+/// Progress goes to `status`, errors end the pass and are retried on the next one
+pub async fn run_sync(config: SyncConfig, status: Arc<Mutex<SyncStatus>>) {
+    let mut client = None;
+
+    loop {
+        status.lock().unwrap().state = "syncing".into();
+        let res = sync_pass(&config, &mut client, &status).await;
+
+        match res {
+            Ok(()) => {
+                let mut status = status.lock().unwrap();
+                status.state = "idle".into();
+                status.last_sync = Some(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+                status.last_error = None;
+            }
+            Err(e) => {
+                // reconnect next time, the connection may be what failed
+                client = None;
+                let mut status = status.lock().unwrap();
+                status.state = "error".into();
+                status.last_error = Some(format!("{e:#}"));
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(config.interval)).await;
+    }
+}
+
+async fn sync_pass(config: &SyncConfig, client: &mut Option<Client>, status: &Mutex<SyncStatus>) -> anyhow::Result<()> {
+    let client = match client {
+        Some(client) => client,
+        None => client.insert(Client::connect(&config.server, config.password.clone(), Some(&config.fingerprint)).await?),
+    };
+
+    tokio::fs::create_dir_all(&config.dir).await?;
+    let state_path = config.dir.join(state_file(config));
+    let mut synced = load_state(&state_path).await;
+
+    let remote = client.list().await?
+        .into_iter()
+        .filter(|entry| entry.name.starts_with(&config.prefix))
+        .collect::<Vec<_>>();
+
+    for entry in &remote {
+        // a share that can't be hashed right now is picked up on a later pass
+        let Some(hash) = &entry.hash else {
+            continue;
+        };
+        // share names that aren't plain file names can't be mirrored safely
+        if Path::new(&entry.name).file_name().is_none_or(|n| n != entry.name.as_str()) || entry.name.starts_with('.') {
+            continue;
+        }
+
+        let path = config.dir.join(&entry.name);
+        if synced.get(&entry.name) == Some(hash) && tokio::fs::try_exists(&path).await? {
+            continue;
+        }
+
+        // a delta against the current local file, if any, also replaces it atomically
+        client.download_delta(&entry.name, &path).await?;
+        synced.insert(entry.name.clone(), hash.clone());
+        save_state(&state_path, &synced).await?;
+        status.lock().unwrap().changes += 1;
+        info!("Synced '{}' into {}", entry.name, config.dir.display());
+    }
+
+    if config.delete {
+        // only files this job downloaded are ever removed
+        let gone = synced.keys()
+            .filter(|name| !remote.iter().any(|entry| &entry.name == *name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for name in gone {
+            match tokio::fs::remove_file(config.dir.join(&name)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            synced.remove(&name);
+            save_state(&state_path, &synced).await?;
+            status.lock().unwrap().changes += 1;
+            info!("Removed '{name}' from {}", config.dir.display());
+        }
+    }
+    Ok(())
+}
+
+// `.fileshare-sync-<hash>` with the hash taken over the server and prefix of the job
+fn state_file(config: &SyncConfig) -> String {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(config.server.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(config.prefix.as_bytes());
+    format!("{STATE_FILE_PREFIX}{}", &hasher.finalize().to_hex()[..16])
+}
+
+async fn load_state(path: &Path) -> BTreeMap<String, String> {
+    match tokio::fs::read(path).await {
+        Ok(data) => bincode::deserialize(&data).unwrap_or_default(),
+        Err(_) => BTreeMap::new(),
+    }
+}
+
+// written next to the real file first so a crash never leaves half a state
+async fn save_state(path: &Path, synced: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, bincode::serialize(synced)?).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Sync jobs saved at `path` by `save_jobs`, none if there is no such file yet
+pub async fn load_jobs(path: &Path) -> anyhow::Result<Vec<SyncConfig>> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let mut data = Vec::new();
+        match open_private(&path, std::fs::OpenOptions::new().read(true)) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(serde_json::from_slice(&data)?)
+    }).await?
+}
+
+/// Keeps `jobs` at `path` so the client daemon can restart them. Their passwords are
+/// saved along, so the file is created readable by its owner only
+pub async fn save_jobs(path: &Path, jobs: Vec<SyncConfig>) -> anyhow::Result<()> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let tmp = path.with_extension("tmp");
+        // left over from a save that didn't finish
+        match std::fs::remove_file(&tmp) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut file = create_private(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(&jobs)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, &path)?;
+        anyhow::Ok(())
+    }).await?
+}
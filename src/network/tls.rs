@@ -1,31 +1,59 @@
 use std::{fs, fs::File, io::BufReader, path::Path, sync::Arc};
 use rustls::{
-    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
-    ServerConfig,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
 };
 use rustls_pemfile::{certs, pkcs8_private_keys};
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair};
+use sha2::{Digest, Sha256};
+use tracing::info;
 
 pub fn create_or_load_tls(cert_path: &str, key_path: &str) -> anyhow::Result<TlsAcceptor> {
     let config = if Path::new(cert_path).exists() && Path::new(key_path).exists() {
-        println!("Using existing TLS certificate and key");
+        info!("Using existing TLS certificate and key");
         load_tls_config(cert_path, key_path)?
     } else {
-        println!("Generating self-signed TLS certificate...");
+        info!("Generating self-signed TLS certificate...");
         generate_self_signed_tls(cert_path, key_path)?
     };
 
     Ok(TlsAcceptor::from(Arc::new(config)))
 }
 
+/// SHA-256 of the DER certificate as lowercase hex, what clients pin
+pub fn cert_fingerprint(cert_path: &str) -> anyhow::Result<String> {
+    let mut cert_reader = BufReader::new(File::open(cert_path)?);
+    let Some(cert) = certs(&mut cert_reader).next() else {
+        anyhow::bail!("No certificate found in {cert_path}");
+    };
+    Ok(fingerprint(&cert?))
+}
+
+/// sha256 of a DER certificate, as hex
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Checks a certificate fingerprint as given on the command line, returns it in lowercase
+pub fn parse_fingerprint(fp: &str) -> Result<String, String> {
+    if fp.len() != 64 || !fp.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("'{fp}' is not a SHA-256 fingerprint of 64 hex digits"));
+    }
+    Ok(fp.to_ascii_lowercase())
+}
+
 fn load_tls_config(cert_path: &str, key_path: &str) -> anyhow::Result<ServerConfig> {
     // read certificate
     let mut cert_reader = BufReader::new(File::open(cert_path)?);
     let cert_chain = certs(&mut cert_reader)
         .collect::<std::io::Result<Vec<_>>>()?
         .into_iter()
-        .map(CertificateDer::from)
         .collect::<Vec<_>>();
 
     // read key
@@ -64,13 +92,98 @@ fn generate_self_signed_tls(cert_path: &str, key_path: &str) -> anyhow::Result<S
     fs::create_dir_all(Path::new(cert_path).parent().unwrap())?;
     fs::write(cert_path, cert.pem())?;
     fs::write(key_path, key_pair.serialize_pem())?;
-    println!("Self-signed certificate generated at {cert_path}");
+    info!("Self-signed certificate generated at {cert_path}");
 
     // convert for rustls
-    let cert_der = CertificateDer::from(cert.der().clone());
+    let cert_der = cert.der().clone();
     let key_der = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_pair.serialize_der().clone()));
 
     Ok(ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(vec![cert_der], key_der)?)
 }
+
+pub fn create_tls_connector() -> TlsConnector {
+    tls_connector(None)
+}
+
+/// Connector that only accepts a server certificate with this SHA-256 fingerprint
+pub fn create_pinned_tls_connector(fingerprint: &str) -> TlsConnector {
+    tls_connector(Some(fingerprint.to_ascii_lowercase()))
+}
+
+fn tls_connector(pin: Option<String>) -> TlsConnector {
+    let algorithms = CryptoProvider::get_default()
+        .map(|provider| provider.signature_verification_algorithms)
+        .unwrap_or_else(|| rustls::crypto::aws_lc_rs::default_provider().signature_verification_algorithms);
+
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyServerCert { algorithms, pin }))
+        .with_no_client_auth();
+
+    TlsConnector::from(Arc::new(config))
+}
+
+// servers use self-signed certificates, so there is no CA to check against.
+// The handshake signature is still verified against the presented certificate,
+// and with a pin the certificate has to be the pinned one.
+#[derive(Debug)]
+struct AnyServerCert {
+    algorithms: WebPkiSupportedAlgorithms,
+    pin: Option<String>,
+}
+
+impl ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match &self.pin {
+            Some(pin) if *pin != fingerprint(end_entity) => {
+                Err(rustls::Error::General("server certificate does not match the pinned fingerprint".into()))
+            }
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Acceptor with a fresh self-signed certificate kept in memory, and that certificate's fingerprint
+#[cfg(test)]
+pub fn test_acceptor() -> (TlsAcceptor, String) {
+    let key_pair = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["localhost".to_string()]).unwrap().self_signed(&key_pair).unwrap();
+    let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+    let fp = fingerprint(cert.der());
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], key)
+        .unwrap();
+    (TlsAcceptor::from(Arc::new(config)), fp)
+}
@@ -0,0 +1,194 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::bail;
+use rand_core::{OsRng, RngCore};
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::network::{
+    announce, cert_fingerprint, create_or_load_tls, create_tls_connector, find_announcement, relay_connect,
+    relay_listen, Announcement, Client, Codec, DiscoveryConfig, PakeRole, Request, Server, Spake2
+};
+use crate::settings::{CERT_PATH, KEY_PATH};
+
+// TLS exporter label for the channel binding of the code exchange
+const EXPORTER_LABEL: &[u8] = b"EXPORTER-file_share-send";
+
+/// How the two sides of a send find each other
+#[derive(Debug, Clone)]
+pub enum Rendezvous {
+    /// the sender announces itself by multicast
+    Lan(DiscoveryConfig),
+    /// both connect out to the relay at this address
+    Relay(String),
+}
+
+/// Random code like "42-lamp-nest", the number is only used to find the
+/// sender, the words are the secret
+pub fn generate_code() -> String {
+    let nameplate = OsRng.next_u32() % 999 + 1;
+    let first = WORDS[(OsRng.next_u32() % WORDS.len() as u32) as usize];
+    let second = WORDS[(OsRng.next_u32() % WORDS.len() as u32) as usize];
+    format!("{nameplate}-{first}-{second}")
+}
+
+// announcement name and relay session of a code
+fn session_name(code: &str) -> anyhow::Result<String> {
+    match code.split_once('-') {
+        Some((nameplate, words)) if nameplate.parse::<u32>().is_ok() && !words.is_empty() => {
+            Ok(format!("send-{nameplate}"))
+        }
+        _ => bail!("Invalid code '{code}'"),
+    }
+}
+
+/// Offers `path` to the one receiver that knows `code`.
+/// The sender gives up after a single attempt with a wrong code
+pub async fn send_file(path: &Path, code: &str, rendezvous: Rendezvous) -> anyhow::Result<()> {
+    let Some(name) = path.file_name() else {
+        bail!("'{}' is not a file", path.display());
+    };
+    if !path.is_file() {
+        bail!("'{}' is not a file", path.display());
+    }
+    let session = session_name(code)?;
+    let acceptor = create_or_load_tls(CERT_PATH, KEY_PATH)?;
+
+    let (socket, peer) = match rendezvous {
+        Rendezvous::Relay(relay) => {
+            let socket = relay_listen(&relay, &session).await?;
+            let peer = socket.peer_addr()?;
+            (socket, peer)
+        }
+        Rendezvous::Lan(config) => {
+            let listener = TcpListener::bind("0.0.0.0:0").await?;
+            let announcement = Announcement {
+                name: session,
+                port: listener.local_addr()?.port(),
+                fingerprint: cert_fingerprint(CERT_PATH)?,
+                auth_required: true,
+            };
+            let announcing = tokio::spawn(announce(config, announcement));
+            let accepted = listener.accept().await;
+            announcing.abort();
+            accepted?
+        }
+    };
+
+    let mut tls = acceptor.accept(socket).await?;
+    let binding = tls.get_ref().1.export_keying_material([0u8; 32], EXPORTER_LABEL, None)?;
+    let key = exchange_keys(&mut tls, PakeRole::Sender, code, &binding).await?;
+
+    // the rest is an ordinary download from a server with just this share
+    let server = Server::new(Some(session_password(&key)));
+    server.add_file(name.to_string_lossy().into_owned(), path.to_path_buf(), None).await;
+    server.handle_client(tls, peer).await
+}
+
+/// Fetches the file offered under `code` into `output`, a file or directory.
+/// Returns the saved path and size
+pub async fn receive_file(code: &str, rendezvous: Rendezvous, output: &Path, wait: Duration) -> anyhow::Result<(PathBuf, u64)> {
+    let session = session_name(code)?;
+
+    let socket = match rendezvous {
+        Rendezvous::Relay(relay) => relay_connect(&relay, &session).await?,
+        Rendezvous::Lan(config) => {
+            let Some((addr, _)) = find_announcement(config, &session, wait).await? else {
+                bail!("No sender found for this code");
+            };
+            TcpStream::connect(addr).await?
+        }
+    };
+
+    // the certificate isn't checked, the code exchange below authenticates the sender
+    let mut tls = create_tls_connector().connect(ServerName::try_from("sender")?, socket).await?;
+    let binding = tls.get_ref().1.export_keying_material([0u8; 32], EXPORTER_LABEL, None)?;
+    let key = exchange_keys(&mut tls, PakeRole::Receiver, code, &binding).await?;
+
+    let mut client = Client::from_tls(tls, Some(session_password(&key))).await?;
+    let [entry] = client.list().await?.try_into().map_err(|_| anyhow::anyhow!("Sender offers more than one file"))?;
+    let received = client.download(&Request::Download { name: entry.name, offset: 0 }, output).await?;
+    let _ = client.request(&Request::Quit).await;
+    Ok(received)
+}
+
+// SPAKE2 over the TLS stream, then both sides prove they got the same key.
+// The receiver proves it first so a wrong code never gets anything back
+async fn exchange_keys<S>(stream: &mut S, role: PakeRole, code: &str, binding: &[u8]) -> anyhow::Result<[u8; 32]>
+where S: AsyncRead + AsyncWrite + Unpin
+{
+    let codec = Codec::default().with_max_message_size(1024);
+    let pake = Spake2::start(role, code.trim().to_lowercase().as_bytes());
+
+    let key = match role {
+        PakeRole::Sender => {
+            codec.send(stream, pake.message()).await?;
+            let peer: Vec<u8> = codec.recv(stream).await?;
+            let key = pake.finish(&peer, binding)?;
+
+            let theirs: [u8; 32] = codec.recv(stream).await?;
+            if blake3::Hash::from(theirs) != confirmation(&key, PakeRole::Receiver) {
+                bail!("Receiver used the wrong code");
+            }
+            codec.send(stream, confirmation(&key, PakeRole::Sender).as_bytes()).await?;
+            key
+        }
+        PakeRole::Receiver => {
+            let peer: Vec<u8> = codec.recv(stream).await?;
+            codec.send(stream, pake.message()).await?;
+            let key = pake.finish(&peer, binding)?;
+
+            codec.send(stream, confirmation(&key, PakeRole::Receiver).as_bytes()).await?;
+            let theirs: [u8; 32] = match codec.recv(stream).await {
+                Ok(theirs) => theirs,
+                Err(_) => bail!("Wrong code"),
+            };
+            if blake3::Hash::from(theirs) != confirmation(&key, PakeRole::Sender) {
+                bail!("Wrong code");
+            }
+            key
+        }
+    };
+    Ok(key)
+}
+
+// blake3::Hash compares in constant time
+fn confirmation(key: &[u8; 32], role: PakeRole) -> blake3::Hash {
+    let label: &[u8] = match role {
+        PakeRole::Sender => b"sender confirm",
+        PakeRole::Receiver => b"receiver confirm",
+    };
+    blake3::keyed_hash(key, label)
+}
+
+// password for the regular auth step of the session
+fn session_password(key: &[u8; 32]) -> String {
+    blake3::keyed_hash(key, b"session password").to_hex().to_string()
+}
+
+const WORDS: [&str; 256] = [
+    "able", "acid", "aged", "also", "area", "army", "away", "baby", "back", "ball", "band", "bank",
+    "base", "bath", "bear", "beat", "bell", "belt", "best", "bird", "blow", "blue", "boat", "body",
+    "bone", "book", "boot", "born", "boss", "bowl", "bulk", "burn", "bush", "busy", "cafe", "cake",
+    "calm", "camp", "card", "care", "cart", "case", "cash", "cast", "cell", "chat", "chef", "chip",
+    "city", "clay", "club", "coal", "coat", "code", "cold", "cook", "cool", "copy", "corn", "cost",
+    "crew", "crop", "cube", "cure", "dark", "data", "dawn", "deal", "deck", "deep", "deer", "desk",
+    "dial", "diet", "disk", "dock", "door", "dose", "down", "draw", "drum", "duck", "dune", "dust",
+    "duty", "earl", "east", "easy", "edge", "epic", "even", "exit", "face", "fact", "fair", "fall",
+    "farm", "fast", "fern", "file", "film", "fire", "firm", "fish", "flag", "flat", "flow", "foam",
+    "fold", "folk", "food", "foot", "fork", "form", "fort", "frog", "fuel", "fund", "gain", "game",
+    "gate", "gear", "gift", "girl", "glad", "glow", "goal", "gold", "golf", "good", "gray", "grid",
+    "grin", "grip", "hair", "half", "hall", "hand", "harp", "hawk", "head", "heat", "helm", "herb",
+    "hero", "hill", "hint", "home", "hook", "hope", "horn", "host", "hour", "huge", "idea", "inch",
+    "iron", "item", "jazz", "jeep", "join", "joke", "jump", "jury", "keen", "kept", "kick", "kind",
+    "king", "kite", "knee", "knot", "lace", "lake", "lamp", "land", "lane", "last", "lava", "lawn",
+    "lead", "leaf", "lean", "left", "lens", "lift", "lime", "line", "link", "lion", "list", "load",
+    "loaf", "lock", "loft", "long", "loop", "lord", "loud", "luck", "lung", "made", "mail", "main",
+    "malt", "mango", "many", "mask", "meal", "melt", "menu", "mild", "milk", "mind", "mint", "moon",
+    "moss", "most", "moth", "move", "much", "mule", "myth", "nail", "navy", "neat", "neck", "nest",
+    "news", "nice", "nine", "node", "noon", "nose", "note", "oak", "oath", "oboe", "odor", "oven",
+    "over", "pace", "pack", "page", "palm", "park", "pass", "path", "peak", "pear", "pier", "pine",
+    "pink", "pipe", "plan", "plum",
+];
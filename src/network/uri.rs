@@ -0,0 +1,176 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+
+use crate::network::parse_fingerprint;
+
+const SCHEME: &str = "fileshare://";
+
+/// Link to one share: `fileshare://host:port/share-name?fp=<sha256>&token=<token>`.
+/// `fp` pins the server certificate, `token` replaces the password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareUri {
+    /// host name or IP, IPv6 without brackets
+    pub host: String,
+    pub port: u16,
+    pub share: String,
+    pub fingerprint: String,
+    pub token: Option<String>,
+}
+
+impl ShareUri {
+    /// `host:port` to connect to
+    pub fn addr(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    pub fn is_uri(s: &str) -> bool {
+        s.starts_with(SCHEME)
+    }
+}
+
+impl fmt::Display for ShareUri {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{SCHEME}{}/{}?fp={}", self.addr(), encode(&self.share), self.fingerprint)?;
+        if let Some(token) = &self.token {
+            write!(f, "&token={}", encode(token))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ShareUri {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let rest = s.strip_prefix(SCHEME).ok_or_else(|| anyhow!("URI must start with {SCHEME}"))?;
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (authority, path) = rest.split_once('/').ok_or_else(|| anyhow!("URI has no share name"))?;
+
+        let (host, port) = authority.rsplit_once(':').ok_or_else(|| anyhow!("URI has no port"))?;
+        let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+        if host.is_empty() {
+            bail!("URI has no host");
+        }
+        let port = port.parse().map_err(|_| anyhow!("Invalid port '{port}'"))?;
+
+        let share = decode(path)?;
+        if share.is_empty() {
+            bail!("URI has no share name");
+        }
+
+        let mut fingerprint = None;
+        let mut token = None;
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match key {
+                "fp" => fingerprint = Some(decode(value)?.to_ascii_lowercase()),
+                "token" => token = Some(decode(value)?),
+                // unknown parameters are left for newer versions
+                _ => {}
+            }
+        }
+
+        let fingerprint = fingerprint.ok_or_else(|| anyhow!("URI has no fp parameter"))?;
+        let fingerprint = parse_fingerprint(&fingerprint).map_err(|e| anyhow!(e))?;
+
+        Ok(ShareUri { host: host.to_string(), port, share, fingerprint, token })
+    }
+}
+
+// percent-encodes everything but unreserved characters
+fn encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn decode(s: &str) -> anyhow::Result<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next(), bytes.next()];
+            let [Some(hi), Some(lo)] = hex else {
+                bail!("Truncated escape in '{s}'");
+            };
+            let hex = std::str::from_utf8(&[hi, lo])?.to_string();
+            out.push(u8::from_str_radix(&hex, 16).map_err(|_| anyhow!("Invalid escape in '{s}'"))?);
+        } else {
+            out.push(b);
+        }
+    }
+    Ok(String::from_utf8(out)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FP: &str = "abababababababababababababababababababababababababababababababab";
+
+    #[test]
+    fn parses_a_full_link() {
+        let uri: ShareUri = format!("fileshare://files.local:4000/my%20notes.txt?fp={}&token=t%2Bk", FP.to_uppercase())
+            .parse().unwrap();
+        assert_eq!(uri, ShareUri {
+            host: "files.local".into(),
+            port: 4000,
+            share: "my notes.txt".into(),
+            fingerprint: FP.into(),
+            token: Some("t+k".into()),
+        });
+        assert_eq!(uri.addr(), "files.local:4000");
+    }
+
+    #[test]
+    fn ipv6_hosts_are_bracketed() {
+        let uri: ShareUri = format!("fileshare://[::1]:4000/a?fp={FP}").parse().unwrap();
+        assert_eq!(uri.host, "::1");
+        assert_eq!(uri.addr(), "[::1]:4000");
+        assert_eq!(uri.token, None);
+    }
+
+    #[test]
+    fn display_round_trips() {
+        let uri = ShareUri {
+            host: "10.0.0.2".into(),
+            port: 4000,
+            share: "dir/file & more?".into(),
+            fingerprint: FP.into(),
+            token: Some("a=b&c".into()),
+        };
+        assert_eq!(uri.to_string().parse::<ShareUri>().unwrap(), uri);
+    }
+
+    #[test]
+    fn unknown_parameters_are_ignored() {
+        assert!(format!("fileshare://h:1/a?v=2&fp={FP}").parse::<ShareUri>().is_ok());
+    }
+
+    #[test]
+    fn broken_links_are_refused() {
+        for bad in [
+            format!("http://h:1/a?fp={FP}"),
+            format!("fileshare://h:1?fp={FP}"),
+            format!("fileshare://h:1/?fp={FP}"),
+            format!("fileshare://h/a?fp={FP}"),
+            format!("fileshare://:1/a?fp={FP}"),
+            format!("fileshare://h:port/a?fp={FP}"),
+            format!("fileshare://h:1/a%2?fp={FP}"),
+            format!("fileshare://h:1/a%zz?fp={FP}"),
+            "fileshare://h:1/a".to_string(),
+            "fileshare://h:1/a?fp=abcd".to_string(),
+        ] {
+            assert!(bad.parse::<ShareUri>().is_err(), "{bad}");
+        }
+    }
+}
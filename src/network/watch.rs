@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// A change to the shares, logged by the daemon and streamed to subscribed clients
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ShareEvent {
+    Added { name: String },
+    Removed { name: String },
+    Renamed { name: String, new_name: String },
+    /// the file changed in place, its old hash is no longer valid
+    Modified { name: String },
+    /// the file was renamed on disk and the share followed it
+    Moved { name: String, path: PathBuf },
+    /// the file is gone, the share stays listed but can't be downloaded
+    Unavailable { name: String },
+    /// the file is back at its path
+    Available { name: String },
+}
+
+impl fmt::Display for ShareEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareEvent::Added { name } => write!(f, "'{name}' added"),
+            ShareEvent::Removed { name } => write!(f, "'{name}' removed"),
+            ShareEvent::Renamed { name, new_name } => write!(f, "'{name}' renamed to '{new_name}'"),
+            ShareEvent::Modified { name } => write!(f, "'{name}' modified"),
+            ShareEvent::Moved { name, path } => write!(f, "'{name}' moved to {}", path.display()),
+            ShareEvent::Unavailable { name } => write!(f, "'{name}' unavailable"),
+            ShareEvent::Available { name } => write!(f, "'{name}' available"),
+        }
+    }
+}
+
+/// inotify watches on the directories holding shared files.
+/// Directories rather than files, so renames and recreated files are seen too
+pub struct ShareWatcher {
+    watcher: RecommendedWatcher,
+    // directory -> number of shares in it
+    dirs: HashMap<PathBuf, usize>,
+}
+
+impl ShareWatcher {
+    /// File system events of watched directories go to the returned receiver
+    pub fn new() -> notify::Result<(Self, mpsc::UnboundedReceiver<Event>)> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+            Ok(event) => {
+                let _ = tx.send(event);
+            }
+            Err(e) => warn!("File watcher error: {e}"),
+        })?;
+        Ok((ShareWatcher { watcher, dirs: HashMap::new() }, rx))
+    }
+
+    pub fn watch(&mut self, path: &Path) {
+        let Some(dir) = watched_dir(path) else {
+            return;
+        };
+        if let Some(count) = self.dirs.get_mut(dir) {
+            *count += 1;
+            return;
+        }
+        match self.watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                self.dirs.insert(dir.to_path_buf(), 1);
+            }
+            Err(e) => warn!("Failed to watch {}: {e}", dir.display()),
+        }
+    }
+
+    pub fn unwatch(&mut self, path: &Path) {
+        let Some(dir) = watched_dir(path) else {
+            return;
+        };
+        let Some(count) = self.dirs.get_mut(dir) else {
+            return;
+        };
+        *count -= 1;
+        if *count == 0 {
+            self.dirs.remove(dir);
+            let _ = self.watcher.unwatch(dir);
+        }
+    }
+}
+
+fn watched_dir(path: &Path) -> Option<&Path> {
+    path.parent().filter(|dir| !dir.as_os_str().is_empty())
+}
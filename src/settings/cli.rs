@@ -1,6 +1,16 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+
 use clap::{Parser, Subcommand};
+use tracing::Level;
 
-use super::config::{AUTHOR, VERSION, ABOUT, LONG_ABOUT, NAME};
+use crate::daemon::{parse_gid, parse_uid};
+use crate::network::{parse_fingerprint, AuditOutcome, CollisionPolicy};
+use crate::utils::{parse_duration, parse_time, read_secret};
+use super::config::{
+    AUTHOR, VERSION, ABOUT, LONG_ABOUT, NAME, MAX_MESSAGE_SIZE, HANDSHAKE_TIMEOUT_SECS,
+    AUTH_TIMEOUT_SECS, IDLE_TIMEOUT_SECS, ACK_TIMEOUT_SECS, KEEPALIVE_SECS, DISCOVERY_GROUP,
+    DISCOVER_WAIT_SECS, RECEIVE_WAIT_SECS, SWARM_BLOCK_TIMEOUT_SECS, SYNC_INTERVAL_SECS, LINK_EXPIRY
+};
 
 /// P2P File Share CLI
 #[derive(Parser)]
@@ -14,6 +24,10 @@ use super::config::{AUTHOR, VERSION, ABOUT, LONG_ABOUT, NAME};
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    /// Print results and errors as JSON
+    #[arg(long, global = true)]
+    pub json: bool,
 }
 
 #[derive(Subcommand)]
@@ -29,23 +43,151 @@ pub enum Command {
         #[command(subcommand)]
         command: ClientCliCommand,
     },
+
+    #[command(flatten)]
+    Transfer(TransferCliCommand),
+}
+
+/// One-shot transfers protected by a code instead of a password
+#[derive(Subcommand)]
+pub enum TransferCliCommand {
+    /// Send a file to whoever has the printed code
+    Send {
+        /// File to send
+        path: String,
+        /// Use this code instead of a random one
+        #[arg(long)]
+        code: Option<String>,
+        /// Meet the receiver at this relay instead of on the LAN
+        #[arg(long)]
+        relay: Option<String>,
+        /// Multicast group and port for announcements
+        #[arg(long, default_value_t = DISCOVERY_GROUP)]
+        discovery_group: SocketAddrV4,
+        /// Interface address to announce on, 127.0.0.1 for loopback only
+        #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+        discovery_interface: Ipv4Addr,
+    },
+
+    /// Receive a file from `send`
+    Receive {
+        /// Code printed by the sender
+        code: String,
+        /// Save path or directory, defaults to the current directory
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Meet the sender at this relay instead of on the LAN
+        #[arg(long)]
+        relay: Option<String>,
+        /// Seconds to look for the sender on the LAN
+        #[arg(long, default_value_t = RECEIVE_WAIT_SECS)]
+        wait: u64,
+        /// Multicast group and port to listen on
+        #[arg(long, default_value_t = DISCOVERY_GROUP)]
+        discovery_group: SocketAddrV4,
+        /// Interface address to listen on, 127.0.0.1 for loopback only
+        #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+        discovery_interface: Ipv4Addr,
+    },
 }
 
 /// Commands for managing your local daemon
 #[derive(Subcommand)]
+// parsed once per run, boxing Start would only complicate the match
+#[allow(clippy::large_enum_variant)]
 pub enum ServerCliCommand {
     /// Start the file sharing daemon
     Start {
         /// Port to listen on
         port: u16,
-        /// Optional password for the daemon
-        #[arg(short, long)]
+        /// Stay in the foreground and log to stderr, for systemd units and containers
+        #[arg(long)]
+        foreground: bool,
+        /// File holding the password clients need, its first line
+        #[arg(long = "password-file", value_name = "FILE", value_parser = read_secret)]
         password: Option<String>,
+        /// Largest protocol message accepted from clients, in bytes
+        #[arg(long, default_value_t = MAX_MESSAGE_SIZE)]
+        max_message_size: u32,
+        /// Seconds a client has to finish the TLS handshake (0 disables)
+        #[arg(long, default_value_t = HANDSHAKE_TIMEOUT_SECS)]
+        handshake_timeout: u64,
+        /// Seconds a client has to authenticate (0 disables)
+        #[arg(long, default_value_t = AUTH_TIMEOUT_SECS)]
+        auth_timeout: u64,
+        /// Seconds a client may stay silent between requests (0 disables)
+        #[arg(long, default_value_t = IDLE_TIMEOUT_SECS)]
+        idle_timeout: u64,
+        /// Seconds to wait for each chunk ack during a download (0 disables)
+        #[arg(long, default_value_t = ACK_TIMEOUT_SECS)]
+        ack_timeout: u64,
+        /// TCP keepalive idle time in seconds (0 disables)
+        #[arg(long, default_value_t = KEEPALIVE_SECS)]
+        keepalive: u64,
+        /// Directory that accepts uploads from clients, uploads are disabled without it
+        #[arg(long)]
+        inbox: Option<String>,
+        /// Max total bytes stored in the inbox
+        #[arg(long, requires = "inbox")]
+        inbox_quota: Option<u64>,
+        /// What to do when an upload has the name of an existing inbox file
+        #[arg(long, value_enum, default_value_t = CollisionPolicy::Rename, requires = "inbox")]
+        on_collision: CollisionPolicy,
+        /// Share finished uploads automatically
+        #[arg(long, requires = "inbox")]
+        auto_share: bool,
+        /// File holding the password that lets clients upload into the inbox, admins always can
+        #[arg(long = "upload-password-file", value_name = "FILE", value_parser = read_secret, requires = "inbox")]
+        upload_password: Option<String>,
+        /// File holding the password that gives clients the admin role to manage shares remotely
+        #[arg(long = "admin-password-file", value_name = "FILE", value_parser = read_secret)]
+        admin_password: Option<String>,
+        /// Directory remote admins may add files from, read with the access of its owner.
+        /// Without it they can't add any
+        #[arg(long, requires = "admin_password")]
+        admin_root: Option<String>,
+        /// Announce this daemon on the LAN so `client discover` finds it
+        #[arg(long)]
+        announce: bool,
+        /// Advertise this daemon as a `_fileshare._tcp` DNS-SD service
+        #[arg(long)]
+        mdns: bool,
+        /// Name used for --announce and --mdns, defaults to the hostname
+        #[arg(long)]
+        name: Option<String>,
+        /// Multicast group and port for announcements
+        #[arg(long, default_value_t = DISCOVERY_GROUP)]
+        discovery_group: SocketAddrV4,
+        /// Interface address to announce on, 127.0.0.1 for loopback only
+        #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+        discovery_interface: Ipv4Addr,
+        /// Relay to also accept clients through, for peers behind NAT
+        #[arg(long, requires = "session")]
+        relay: Option<String>,
+        /// Session id clients use to reach this daemon through the relay
+        #[arg(long, requires = "relay")]
+        session: Option<String>,
+        /// User besides the daemon's own who may change shares through the control socket, repeatable
+        #[arg(long = "control-user", value_name = "USER", value_parser = parse_uid)]
+        control_users: Vec<u32>,
+        /// Group whose members may change shares through the control socket, repeatable
+        #[arg(long = "control-group", value_name = "GROUP", value_parser = parse_gid)]
+        control_groups: Vec<u32>,
+        /// Serve Prometheus metrics on http://127.0.0.1:PORT/metrics
+        #[arg(long, value_name = "PORT")]
+        metrics_port: Option<u16>,
     },
 
     /// Stop the file sharing daemon
     Stop,
 
+    /// Stop the daemon and start it again with the same options
+    Restart,
+
+    /// Re-read the shares file and TLS certificate without dropping connections.
+    /// Start options only change with a restart
+    Reload,
+
     /// Add a file to share
     Add {
         /// Path to the file
@@ -62,6 +204,114 @@ pub enum ServerCliCommand {
 
     /// List all shared files
     List,
+
+    /// Rename a shared file
+    Rename {
+        /// Current name
+        name: String,
+        /// New name
+        new_name: String,
+    },
+
+    /// Replace the tags of a shared file
+    Tag {
+        /// Name of the shared file
+        name: String,
+        /// New tags, none clears them
+        tags: Vec<String>,
+    },
+
+    /// Show what the running daemon is doing
+    Status,
+
+    /// Print connections, logins, transfers and share changes as they happen, until interrupted
+    Watch,
+
+    /// Print the daemon log
+    Logs {
+        /// Keep printing new lines as they are written
+        #[arg(short, long)]
+        follow: bool,
+        /// How many of the last lines to print
+        #[arg(short = 'n', long, default_value_t = 20)]
+        lines: usize,
+        /// Least severe level to show: error, warn, info, debug or trace
+        #[arg(long)]
+        level: Option<Level>,
+        /// Only lines containing this text, e.g. peer=10.0.0.2 or share=notes
+        #[arg(long)]
+        grep: Option<String>,
+    },
+
+    /// Change what the running daemon logs, e.g. debug or info,file_share::network::server=trace
+    LogLevel {
+        filter: String,
+    },
+
+    /// Print who authenticated, downloaded or managed shares, and when
+    Audit {
+        /// Records from this time on: unix seconds, an age like 12h or 7d, or a UTC date like 2026-10-19T04:00
+        #[arg(long, value_parser = parse_time)]
+        since: Option<u64>,
+        /// Records up to this time, in the same formats as --since
+        #[arg(long, value_parser = parse_time)]
+        until: Option<u64>,
+        /// Only records of this role: user, uploader, admin or link
+        #[arg(long)]
+        user: Option<String>,
+        /// Only records of the link token with this id
+        #[arg(long)]
+        token: Option<String>,
+        /// Only transfers of this share
+        #[arg(long)]
+        share: Option<String>,
+        /// Only records with this outcome
+        #[arg(long, value_enum)]
+        outcome: Option<AuditOutcome>,
+    },
+
+    /// Print a fileshare:// link that downloads a share in one step
+    Link {
+        /// Name of the shared file
+        name: String,
+        /// Host clients should connect to, defaults to the hostname
+        #[arg(long)]
+        host: Option<String>,
+        /// How long the link works, like 12h or 30d, 0 until it is revoked
+        #[arg(long, value_parser = parse_duration, default_value = LINK_EXPIRY)]
+        expires: u64,
+    },
+
+    /// List the share links that still work
+    Links,
+
+    /// Make a share link stop working
+    RevokeLink {
+        /// Link id from `links`
+        id: String,
+    },
+
+    /// Run a relay that pairs peers which can't reach each other
+    Relay {
+        #[command(subcommand)]
+        command: RelayCliCommand,
+    },
+}
+
+/// Commands for the relay daemon
+#[derive(Subcommand)]
+pub enum RelayCliCommand {
+    /// Start the relay daemon
+    Start {
+        /// Port to listen on
+        port: u16,
+        /// Stay in the foreground and log to stderr, for systemd units and containers
+        #[arg(long)]
+        foreground: bool,
+    },
+
+    /// Stop the relay daemon
+    Stop,
 }
 
 /// Commands for connecting to a remote server
@@ -69,25 +319,237 @@ pub enum ServerCliCommand {
 pub enum ClientCliCommand {
     /// Connect to a remote server
     Connect {
-        /// Server address
+        /// Server address, or the relay address with --session
         addr: String,
-        /// Optional password
-        #[arg(short, long)]
+        /// Stay in the foreground and log to stderr, for systemd units and containers
+        #[arg(long)]
+        foreground: bool,
+        /// File holding the server password, its first line
+        #[arg(long = "password-file", value_name = "FILE", value_parser = read_secret)]
         password: Option<String>,
+        /// Reach the server through the relay at ADDR under this session id
+        #[arg(long, requires = "fp")]
+        session: Option<String>,
+        /// SHA-256 fingerprint the server certificate has to match, as `daemon status` shows it.
+        /// Without it the certificate seen on the first connection is remembered in ~/.file_share/known_hosts
+        #[arg(long, value_parser = parse_fingerprint)]
+        fp: Option<String>,
     },
 
     /// Disconnect from the current server
     Disconnect,
 
+    /// Browse for `_fileshare._tcp` DNS-SD services
+    Browse {
+        /// Seconds to browse
+        #[arg(short, long, default_value_t = DISCOVER_WAIT_SECS)]
+        wait: u64,
+    },
+
+    /// Resolve a DNS-SD service instance to its address
+    Resolve {
+        /// Instance name, as shown by browse
+        instance: String,
+        /// Seconds to wait for an answer
+        #[arg(short, long, default_value_t = DISCOVER_WAIT_SECS)]
+        wait: u64,
+    },
+
     /// Request a list of available files
     List,
 
+    /// Find file share daemons announcing themselves on the LAN
+    Discover {
+        /// Seconds to listen for announcements
+        #[arg(short, long, default_value_t = DISCOVER_WAIT_SECS)]
+        wait: u64,
+        /// Multicast group and port to listen on
+        #[arg(long, default_value_t = DISCOVERY_GROUP)]
+        group: SocketAddrV4,
+        /// Interface address to listen on, 127.0.0.1 for loopback only
+        #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+        interface: Ipv4Addr,
+    },
+
+    /// Search the shares of the connected server
+    Search {
+        /// Name pattern, a case-insensitive substring by default
+        pattern: Option<String>,
+        /// Treat the pattern as a glob
+        #[arg(long, conflicts_with = "regex")]
+        glob: bool,
+        /// Treat the pattern as a regular expression
+        #[arg(long)]
+        regex: bool,
+        /// Minimum size in bytes
+        #[arg(long)]
+        min_size: Option<u64>,
+        /// Maximum size in bytes
+        #[arg(long)]
+        max_size: Option<u64>,
+        /// Only files modified after this time: unix seconds, an age like 12h or 7d, or a UTC date like 2026-10-19T04:00
+        #[arg(long, value_parser = parse_time)]
+        modified_after: Option<u64>,
+        /// Only files modified before this time, in the same formats as --modified-after
+        #[arg(long, value_parser = parse_time)]
+        modified_before: Option<u64>,
+        /// Required tag, can be repeated
+        #[arg(short, long = "tag")]
+        tags: Vec<String>,
+        /// File extension, e.g. pdf
+        #[arg(long = "type")]
+        file_type: Option<String>,
+        /// Number of results to skip
+        #[arg(long, default_value_t = 0)]
+        offset: u64,
+        /// Results per page, capped by the server
+        #[arg(long, default_value_t = 0)]
+        limit: u64,
+    },
+
     /// Download a file
     Download {
-        /// File name to download
+        /// File name to download, its blake3 hash with --hash, or a fileshare:// link
         name: String,
-        /// Save path
+        /// Look the file up by content hash instead of name
+        #[arg(long)]
+        hash: bool,
+        /// Only fetch what changed compared to the file already at the save path
+        #[arg(long, conflicts_with = "hash")]
+        delta: bool,
+        /// Save path or directory, defaults to the current directory
         #[arg(short, long)]
         output: Option<String>,
     },
+
+    /// Download content by blake3 hash from several servers at once
+    Swarm {
+        /// blake3 hash of the content
+        hash: String,
+        /// Save path
+        output: String,
+        /// Server address, can be repeated
+        #[arg(long = "peer")]
+        peers: Vec<String>,
+        /// File holding the password for the servers
+        #[arg(long = "password-file", value_name = "FILE", value_parser = read_secret)]
+        password: Option<String>,
+        /// Also use servers announcing themselves on the LAN
+        #[arg(long)]
+        discover: bool,
+        /// Seconds a peer may take per block before it is dropped
+        #[arg(long, default_value_t = SWARM_BLOCK_TIMEOUT_SECS)]
+        block_timeout: u64,
+    },
+
+    /// Keep a local directory mirrored from a server, runs as a job in the client daemon
+    Sync {
+        /// Server address
+        server: String,
+        /// Mirror shares whose name starts with this, a full name mirrors one share
+        prefix: String,
+        /// Local directory to mirror into
+        dir: String,
+        /// File holding the password for the server
+        #[arg(long = "password-file", value_name = "FILE", value_parser = read_secret)]
+        password: Option<String>,
+        /// Delete local files whose share was removed
+        #[arg(long)]
+        delete: bool,
+        /// Seconds between checks for changes
+        #[arg(long, default_value_t = SYNC_INTERVAL_SECS)]
+        interval: u64,
+        /// SHA-256 fingerprint the server certificate has to match, defaults to the one in the known hosts
+        #[arg(long, value_parser = parse_fingerprint)]
+        fp: Option<String>,
+    },
+
+    /// Upload a file into a server's inbox, an interrupted upload resumes when run again
+    Upload {
+        /// Server address
+        server: String,
+        /// File to upload
+        path: String,
+        /// Name to store it under, defaults to the file name
+        #[arg(long)]
+        name: Option<String>,
+        /// File holding the upload or admin password of the server
+        #[arg(long = "password-file", value_name = "FILE", value_parser = read_secret)]
+        password: Option<String>,
+        /// SHA-256 fingerprint the server certificate has to match, instead of the known hosts
+        #[arg(long, value_parser = parse_fingerprint)]
+        fp: Option<String>,
+    },
+
+    /// Print share changes of a server as they happen, until interrupted
+    Events {
+        /// Server address
+        server: String,
+        /// File holding the password for the server
+        #[arg(long = "password-file", value_name = "FILE", value_parser = read_secret)]
+        password: Option<String>,
+        /// SHA-256 fingerprint the server certificate has to match, instead of the known hosts
+        #[arg(long, value_parser = parse_fingerprint)]
+        fp: Option<String>,
+    },
+
+    /// Show what the client daemon is doing
+    Status,
+
+    /// Change what the client daemon logs, e.g. debug or warn
+    LogLevel {
+        filter: String,
+    },
+
+    /// Show the sync jobs of the client daemon
+    SyncStatus,
+
+    /// Stop a sync job
+    SyncStop {
+        /// Job id from sync-status
+        id: u64,
+    },
+
+    /// Manage the shares of the connected server, needs the admin password
+    Admin {
+        #[command(subcommand)]
+        command: AdminCliCommand,
+    },
+}
+
+/// Share management on a remote server
+#[derive(Subcommand)]
+pub enum AdminCliCommand {
+    /// Share a file that is on the server
+    Add {
+        /// Path to the file on the server
+        path: String,
+        /// Optional custom name for sharing
+        name: Option<String>,
+    },
+
+    /// Delete a shared file
+    Delete {
+        /// Name of the file to delete
+        name: String,
+    },
+
+    /// Rename a shared file
+    Rename {
+        /// Current name
+        name: String,
+        /// New name
+        new_name: String,
+    },
+
+    /// Replace the tags of a shared file
+    Tag {
+        /// Name of the shared file
+        name: String,
+        /// New tags, none clears them
+        tags: Vec<String>,
+    },
+
+    /// List all shared files with their paths
+    List,
 }
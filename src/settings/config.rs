@@ -1,20 +1,125 @@
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
 pub const NAME: &str = "File Share";
 pub const AUTHOR: &str = "Pawelgit1234";
 pub const VERSION: &str = "0.1.0";
+// bumped on incompatible wire protocol changes
+pub const PROTOCOL_VERSION: u32 = 1;
 pub const ABOUT: &str = "";
 pub const LONG_ABOUT: &str = "";
 
-pub const SERVER_DAEMON_OUT_PATH: &str = "/tmp/server_file_share.out";
+pub const SERVER_DAEMON_LOG_PATH: &str = "/tmp/server_file_share.log";
 pub const SERVER_DAEMON_ERR_PATH: &str = "/tmp/server_file_share.err";
 pub const SERVER_DAEMON_PID_PATH: &str = "/tmp/server_file_share.pid";
 pub const SERVER_DAEMON_SOCKET_PATH: &str = "/tmp/server_file_share.sock";
+// JSON lines of auth attempts, transfers and remote admin commands in the state directory, only ever appended to
+pub const SERVER_AUDIT_FILE: &str = "server.audit";
+
+// daemons keep their state in $XDG_STATE_HOME/STATE_DIR_NAME, ~/.local/state/STATE_DIR_NAME
+// or ROOT_STATE_DIR when running as root
+pub const STATE_DIR_NAME: &str = "file_share";
+pub const ROOT_STATE_DIR: &str = "/var/lib/file_share";
+// shares of the server daemon in the state directory, kept across restarts and re-read on reload
+pub const SERVER_SHARES_FILE: &str = "server.shares";
+// sync jobs of the client daemon in the state directory, restarted with it
+pub const CLIENT_SYNC_FILE: &str = "client.sync";
 
-pub const CLIENT_DAEMON_OUT_PATH: &str = "/tmp/client_file_share.out";
+// bumped whenever the encoding of the control messages or their framing changes, a test in daemon::protocol checks it
+pub const CONTROL_PROTOCOL_VERSION: u32 = 7;
+// entries per response when a daemon streams a long list
+pub const CONTROL_PAGE_SIZE: usize = 1000;
+
+pub const CLIENT_DAEMON_LOG_PATH: &str = "/tmp/client_file_share.log";
 pub const CLIENT_DAEMON_ERR_PATH: &str = "/tmp/client_file_share.err";
 pub const CLIENT_DAEMON_PID_PATH: &str = "/tmp/client_file_share.pid";
 pub const CLIENT_DAEMON_SOCKET_PATH: &str = "/tmp/client_file_share.sock";
 
+pub const RELAY_DAEMON_LOG_PATH: &str = "/tmp/relay_file_share.log";
+pub const RELAY_DAEMON_ERR_PATH: &str = "/tmp/relay_file_share.err";
+pub const RELAY_DAEMON_PID_PATH: &str = "/tmp/relay_file_share.pid";
+
+// how long stop and restart wait for a daemon to exit
+pub const DAEMON_STOP_TIMEOUT_SECS: u64 = 10;
+
+// daemon logs are rotated to PATH.1 .. PATH.LOG_KEEP_FILES once they grow past this
+pub const LOG_MAX_SIZE: u64 = 10 * 1024 * 1024; // 10 MB
+pub const LOG_KEEP_FILES: usize = 5;
+// initial log filter, e.g. `debug` or `info,file_share::network::server=trace`
+pub const LOG_ENV: &str = "FILE_SHARE_LOG";
+pub const DEFAULT_LOG_FILTER: &str = "info";
+// how often `daemon logs -f` looks for new lines
+pub const LOGS_POLL_MS: u64 = 500;
+
+// upper bounds in seconds of the transfer duration histogram on the metrics endpoint
+pub const TRANSFER_DURATION_BUCKETS: [f64; 9] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 1800.0];
+// a scrape request larger than this is refused
+pub const METRICS_MAX_REQUEST: usize = 8 * 1024;
+pub const METRICS_TIMEOUT_SECS: u64 = 10;
+
+// per user, in the home directory
+pub const CONFIG_DIR_NAME: &str = ".file_share";
+// how long a share link works unless `daemon link --expires` says otherwise
+pub const LINK_EXPIRY: &str = "7d";
+
+// fingerprints of servers the client connected to, see `KnownHosts`
+pub const KNOWN_HOSTS_FILE: &str = "known_hosts";
+
 pub const CERT_PATH: &str = "~/.file_share/certs/cert.pem";
 pub const KEY_PATH: &str = "~/.file_share/certs/key.pem";
 
-pub const CHUNK_SIZE: usize = 64 * 1024; // 64 KB
\ No newline at end of file
+pub const CHUNK_SIZE: usize = 64 * 1024; // 64 KB
+// unfinished uploads nobody resumed for this long are removed, so they stop counting against the inbox quota
+pub const INBOX_PART_EXPIRY_SECS: u64 = 24 * 3600;
+pub const MAX_SEARCH_RESULTS: u64 = 100;
+// swarm downloads hand out work in blocks of this many chunks
+pub const SWARM_BLOCK_CHUNKS: u64 = 16;
+pub const SWARM_BLOCK_TIMEOUT_SECS: u64 = 60;
+// largest content a swarm download takes on, in chunks of CHUNK_SIZE (256 GB)
+pub const SWARM_MAX_CHUNKS: u64 = 4 * 1024 * 1024;
+// 32 bytes each, keeps a ChunkHashes response well under MAX_MESSAGE_SIZE
+pub const MAX_CHUNK_HASHES: u64 = 65536;
+// delta downloads use bigger blocks for big files so the signatures
+// of the old copy stay under MAX_DELTA_BLOCKS
+pub const DELTA_BLOCK_SIZE: u64 = 8 * 1024;
+pub const MAX_DELTA_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+pub const MAX_DELTA_BLOCKS: u64 = 65536;
+
+pub const MAX_MESSAGE_SIZE: u32 = 4 * 1024 * 1024; // 4 MB
+pub const READ_TIMEOUT: Duration = Duration::from_secs(60);
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// session timeouts in seconds, 0 disables
+pub const HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+pub const AUTH_TIMEOUT_SECS: u64 = 30;
+pub const IDLE_TIMEOUT_SECS: u64 = 300;
+pub const ACK_TIMEOUT_SECS: u64 = 30;
+pub const KEEPALIVE_SECS: u64 = 60;
+
+// how often sync jobs poll their server
+pub const SYNC_INTERVAL_SECS: u64 = 30;
+
+// share events buffered per subscriber before the oldest are dropped
+pub const SHARE_EVENTS_CAPACITY: usize = 256;
+// daemon events buffered per `daemon watch` before the oldest are dropped
+pub const WATCH_EVENTS_CAPACITY: usize = 1024;
+// least time between two progress events of one transfer
+pub const WATCH_PROGRESS_MS: u64 = 500;
+
+// client daemon pings the server so the idle timeout doesn't close the session
+pub const PING_INTERVAL_SECS: u64 = 60;
+
+// LAN discovery
+pub const DISCOVERY_GROUP: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 70, 83), 47150);
+pub const ANNOUNCE_INTERVAL_SECS: u64 = 5;
+pub const DISCOVER_WAIT_SECS: u64 = 3;
+// how long `receive` looks for the sender of a code
+pub const RECEIVE_WAIT_SECS: u64 = 30;
+
+// relay
+pub const RELAY_HELLO_TIMEOUT_SECS: u64 = 10;
+// wait before a server registers with the relay again after a failure
+pub const RELAY_RETRY_SECS: u64 = 5;
+pub const MAX_SESSION_ID_LEN: usize = 128;
+// peers one relay keeps waiting at once, each holds a connection
+pub const MAX_RELAY_WAITING: usize = 256;
@@ -0,0 +1,24 @@
+use std::io;
+
+use async_compression::tokio::bufread::ZstdDecoder;
+use async_compression::tokio::write::ZstdEncoder;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+// every chunk is its own zstd frame, so chunks can be decoded independently
+pub async fn compress_chunk(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = ZstdEncoder::new(Vec::with_capacity(data.len()));
+    encoder.write_all(data).await?;
+    encoder.shutdown().await?;
+    Ok(encoder.into_inner())
+}
+
+// refuses to inflate past max_len, so a tiny frame can't blow up into gigabytes
+pub async fn decompress_chunk(data: &[u8], max_len: usize) -> io::Result<Vec<u8>> {
+    let mut decoder = ZstdDecoder::new(data);
+    let mut out = Vec::with_capacity(max_len);
+    (&mut decoder).take(max_len as u64 + 1).read_to_end(&mut out).await?;
+    if out.len() > max_len {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed chunk too large"));
+    }
+    Ok(out)
+}
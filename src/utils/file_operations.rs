@@ -1,13 +1,26 @@
 use std::path::PathBuf;
 
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
 use crate::settings::CHUNK_SIZE;
 
+/// First line of the file at `path`, for passwords that shouldn't show up in the process list
+pub fn read_secret(path: &str) -> Result<String, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("can't read {path}: {e}"))?;
+    match content.lines().next() {
+        Some(secret) if !secret.is_empty() => Ok(secret.to_string()),
+        _ => Err(format!("{path} is empty")),
+    }
+}
+
 pub async fn hash_file(path: &PathBuf) -> anyhow::Result<String> {
+    hash_reader(File::open(path).await?).await
+}
+
+/// blake3 of what is left to read in `file`
+pub async fn hash_reader(mut tmp: File) -> anyhow::Result<String> {
     let mut hasher = blake3::Hasher::new();
-    let mut tmp = File::open(path).await?;
     let mut buf = vec![0u8; CHUNK_SIZE];
     loop {
         let n = tmp.read(&mut buf).await?;
@@ -18,6 +31,31 @@ pub async fn hash_file(path: &PathBuf) -> anyhow::Result<String> {
     Ok(hash)
 }
 
+/// blake3 of every chunk in `[start, start + count)` of `file`, used to verify swarm downloads
+pub async fn hash_chunks(mut file: File, start: u64, count: u64) -> anyhow::Result<Vec<[u8; 32]>> {
+    file.seek(std::io::SeekFrom::Start(start * CHUNK_SIZE as u64)).await?;
+
+    let mut hashes = Vec::new();
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    while (hashes.len() as u64) < count {
+        let n = read_full(&mut file, &mut buf).await?;
+        if n == 0 { break; }
+        hashes.push(*blake3::hash(&buf[..n]).as_bytes());
+    }
+    Ok(hashes)
+}
+
+// fills buf unless EOF comes first, so chunk boundaries match CHUNK_SIZE
+pub async fn read_full(file: &mut File, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..]).await?;
+        if n == 0 { break; }
+        filled += n;
+    }
+    Ok(filled)
+}
+
 pub async fn get_file_length(file: &File) -> anyhow::Result<u64> {
     let meta = file.metadata().await?;
     Ok(meta.len())
@@ -1,3 +1,11 @@
 pub mod file_operations;
+pub mod compression;
+pub mod system;
+pub mod time;
+pub mod state;
 
-pub use file_operations::*;
\ No newline at end of file
+pub use file_operations::*;
+pub use compression::*;
+pub use system::*;
+pub use time::*;
+pub use state::*;
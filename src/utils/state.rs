@@ -0,0 +1,73 @@
+use std::fs::{self, DirBuilder, File, Metadata, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::settings::{CONFIG_DIR_NAME, ROOT_STATE_DIR, STATE_DIR_NAME};
+
+/// Directory the daemons keep their state in: `/var/lib/file_share` for root, otherwise
+/// `$XDG_STATE_HOME/file_share` or `~/.local/state/file_share`. It is created with mode 0700
+/// and refused if it belongs to someone else
+pub fn state_dir() -> io::Result<PathBuf> {
+    let dir = if unsafe { libc::geteuid() } == 0 {
+        PathBuf::from(ROOT_STATE_DIR)
+    } else {
+        let xdg = std::env::var_os("XDG_STATE_HOME").map(PathBuf::from).filter(|dir| dir.is_absolute());
+        let home = std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/state"));
+        xdg.or(home)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "neither XDG_STATE_HOME nor HOME is set"))?
+            .join(STATE_DIR_NAME)
+    };
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+    let meta = fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != unsafe { libc::geteuid() } {
+        let message = format!("{} is not a directory of this user", dir.display());
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+    }
+    // ours, but made accessible to others at some point
+    if meta.mode() & 0o077 != 0 {
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(dir)
+}
+
+/// `file` in the state directory
+pub fn state_path(file: &str) -> io::Result<PathBuf> {
+    Ok(state_dir()?.join(file))
+}
+
+/// `file` in `~/.file_share`, the directory is created with mode 0700 if needed
+pub fn config_path(file: &str) -> io::Result<PathBuf> {
+    let home = std::env::var_os("HOME")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))?;
+    let dir = Path::new(&home).join(CONFIG_DIR_NAME);
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    Ok(dir.join(file))
+}
+
+/// Opens `path` without following a symlink there and checks that this user owns it
+/// and nobody else can write to it
+pub fn open_private(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    let file = options.custom_flags(libc::O_NOFOLLOW).open(path)?;
+    check_private(&file.metadata()?, path)?;
+    Ok(file)
+}
+
+/// Creates `path` with mode 0600, failing if anything is there already, a symlink included
+pub fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+fn check_private(meta: &Metadata, path: &Path) -> io::Result<()> {
+    if meta.uid() != unsafe { libc::geteuid() } || meta.mode() & 0o022 != 0 {
+        let message = format!("{} is not owned by this user or writable by others", path.display());
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// `2026-10-19T04:00:00Z` for unix seconds
+pub fn format_utc(secs: u64) -> String {
+    let (days, rest) = (secs / 86400, secs % 86400);
+    let (year, month, day) = civil_from_days(days as i64);
+    format!("{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z", rest / 3600, rest % 3600 / 60, rest % 60)
+}
+
+/// Parses a point in time as unix seconds, an age like `30m`, `12h` or `7d`,
+/// or a UTC date `2026-10-19` with an optional `T04:00` or `T04:00:00`
+pub fn parse_time(text: &str) -> Result<u64, String> {
+    if let Ok(secs) = text.parse() {
+        return Ok(secs);
+    }
+
+    if let Some(age) = with_unit(text) {
+        return Ok(unix_now().saturating_sub(age));
+    }
+
+    let invalid = || format!("'{text}' is not unix seconds, an age like 12h or a date like 2026-10-19T04:00");
+    let text = text.strip_suffix('Z').unwrap_or(text);
+    let (date, time) = text.split_once('T').unwrap_or((text, "00:00"));
+    let date = date.split('-').map(str::parse::<u64>).collect::<Result<Vec<_>, _>>().map_err(|_| invalid())?;
+    let time = time.split(':').map(str::parse::<u64>).collect::<Result<Vec<_>, _>>().map_err(|_| invalid())?;
+    let &[year, month, day] = date.as_slice() else {
+        return Err(invalid());
+    };
+    let (hour, minute, second) = match *time.as_slice() {
+        [hour, minute] => (hour, minute, 0),
+        [hour, minute, second] => (hour, minute, second),
+        _ => return Err(invalid()),
+    };
+    if year > 9999 || !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return Err(invalid());
+    }
+
+    let days = u64::try_from(days_from_civil(year as i64, month, day)).map_err(|_| invalid())?;
+    days.checked_mul(86400)
+        .and_then(|secs| secs.checked_add(hour * 3600 + minute * 60 + second))
+        .ok_or_else(invalid)
+}
+
+/// Parses a length of time as seconds, plain or like `30m`, `12h` or `7d`
+pub fn parse_duration(text: &str) -> Result<u64, String> {
+    text.parse().ok()
+        .or_else(|| with_unit(text))
+        .ok_or_else(|| format!("'{text}' is not seconds or a duration like 12h"))
+}
+
+// `30s`, `30m`, `12h` or `7d` in seconds
+fn with_unit(text: &str) -> Option<u64> {
+    let unit = match text.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 3600,
+        'd' => 86400,
+        _ => return None,
+    };
+    text[..text.len() - 1].parse::<u64>().ok()?.checked_mul(unit)
+}
+
+// proleptic Gregorian calendar, after Howard Hinnant's `days_from_civil`
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_utc() {
+        assert_eq!(format_utc(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_utc(951_868_800), "2000-03-01T00:00:00Z");
+        assert_eq!(format_utc(1_709_251_199), "2024-02-29T23:59:59Z");
+        assert_eq!(format_utc(1_792_382_400), "2026-10-19T04:00:00Z");
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_time("1792382400"), Ok(1_792_382_400));
+        assert_eq!(parse_time("2026-10-19"), Ok(1_792_368_000));
+        assert_eq!(parse_time("2026-10-19T04:00"), Ok(1_792_382_400));
+        assert_eq!(parse_time("2024-02-29T23:59:59Z"), Ok(1_709_251_199));
+    }
+
+    #[test]
+    fn formatted_times_parse_back() {
+        for secs in [0, 86_399, 951_868_800, 1_709_251_199, 1_792_382_400, 4_102_444_800] {
+            assert_eq!(parse_time(&format_utc(secs)), Ok(secs));
+        }
+    }
+
+    #[test]
+    fn parses_ages() {
+        let now = unix_now();
+        let age = now - parse_time("12h").unwrap();
+        // the clock may tick between the two reads
+        assert!((12 * 3600..=12 * 3600 + 1).contains(&age), "{age}");
+    }
+
+    #[test]
+    fn refuses_garbage() {
+        for bad in ["", "yesterday", "12x", "2026-13-01", "2026-10-32", "2026-10-19T24:00", "2026-10", "1969-12-31", "T04:00"] {
+            assert!(parse_time(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn refuses_years_past_9999() {
+        assert_eq!(parse_time("9999-12-31T23:59:59"), Ok(253_402_300_799));
+        for bad in ["10000-01-01", "9223372036854775807-01-01", "18446744073709551615-12-31"] {
+            assert!(parse_time(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("30m"), Ok(1800));
+        assert_eq!(parse_duration("7d"), Ok(604_800));
+        assert!(parse_duration("7w").is_err());
+        assert!(parse_duration(&format!("{}d", u64::MAX)).is_err());
+    }
+}